@@ -0,0 +1,338 @@
+use actix_web::http::header::USER_AGENT;
+use actix_web::HttpRequest;
+use pnet::ipnetwork::IpNetwork;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::fs;
+use std::net::IpAddr;
+use std::path::{Component, Path, PathBuf};
+
+use crate::archives::split_member;
+use crate::config::RootConfig;
+use crate::library::Object;
+
+/// Limits what some clients see to some of the shared folders. A rule applies to the
+/// clients matching both `subnets` and `user_agent`, where set, and to every client
+/// when neither is.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct AccessRule {
+    /// Source addresses, single (`192.168.1.20`) or subnets (`192.168.50.0/24`).
+    pub subnets: Vec<String>,
+    /// Case-insensitive regex matched against the User-Agent header.
+    pub user_agent: Option<String>,
+    /// Titles of the shared folders these clients see; none hides the whole library.
+    pub roots: Vec<String>,
+}
+
+/// A shared folder, as configured and with its symlinks resolved.
+#[derive(Debug, Clone, PartialEq)]
+struct SharedRoot {
+    path: PathBuf,
+    canonical: PathBuf,
+}
+
+impl SharedRoot {
+    fn new(path: &Path) -> Self {
+        SharedRoot {
+            path: path.to_path_buf(),
+            canonical: fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+        }
+    }
+}
+
+/// What a client may see: what lies below some of the shared folders, or below all of
+/// them for clients no rule restricts.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Access {
+    roots: Vec<SharedRoot>,
+    restricted: bool,
+}
+
+impl Access {
+    pub fn is_restricted(&self) -> bool {
+        self.restricted
+    }
+
+    /// Whether the client sees nothing of the library.
+    pub fn is_denied(&self) -> bool {
+        self.restricted && self.roots.is_empty()
+    }
+
+    /// Whether `path`, as the index records it, lies below a shared folder the client
+    /// sees.
+    pub fn allows(&self, path: &Path) -> bool {
+        !path.components().any(|x| x == Component::ParentDir)
+            && self.roots.iter().any(|x| path.starts_with(&x.path))
+    }
+
+    /// Like [`Access::allows`], for a path taken from a request: it must still lie below
+    /// a shared folder once symlinks are resolved. Archive members go by their archive.
+    pub fn allows_file(&self, path: &Path) -> bool {
+        let resolved = match fs::canonicalize(path) {
+            Ok(x) => x,
+            Err(_) => match split_member(path) {
+                Some((archive, member))
+                    if member
+                        .components()
+                        .all(|x| matches!(x, Component::Normal(_))) =>
+                {
+                    match fs::canonicalize(archive) {
+                        Ok(x) => x,
+                        Err(_) => return false,
+                    }
+                }
+                _ => return false,
+            },
+        };
+        self.roots
+            .iter()
+            .any(|x| resolved.starts_with(&x.canonical))
+    }
+
+    /// Objects without a path, such as the streams in a playlist, go with the container
+    /// listing them.
+    pub fn allows_object(&self, object: &Object) -> bool {
+        object.path.as_os_str().is_empty() || self.allows(&object.path)
+    }
+
+    /// The shared folders among `roots` the client sees.
+    pub fn roots(&self, roots: &[(u64, PathBuf)]) -> Vec<(u64, PathBuf)> {
+        roots
+            .iter()
+            .filter(|(_, x)| self.allows(x))
+            .cloned()
+            .collect()
+    }
+}
+
+struct Rule {
+    user_agent: Option<Regex>,
+    subnets: Vec<IpNetwork>,
+    access: Access,
+}
+
+impl Rule {
+    fn new(rule: &AccessRule, roots: &[RootConfig]) -> Result<Self, String> {
+        let user_agent = match &rule.user_agent {
+            Some(pattern) => Some(
+                RegexBuilder::new(pattern)
+                    .case_insensitive(true)
+                    .build()
+                    .map_err(|e| format!("invalid user_agent pattern {:?}: {}", pattern, e))?,
+            ),
+            None => None,
+        };
+        let subnets = rule
+            .subnets
+            .iter()
+            .map(|x| {
+                x.parse::<IpNetwork>()
+                    .map_err(|e| format!("invalid subnet {:?}: {}", x, e))
+            })
+            .collect::<Result<_, _>>()?;
+        let roots = rule
+            .roots
+            .iter()
+            .map(|title| match roots.iter().find(|x| &x.title == title) {
+                Some(x) => Ok(SharedRoot::new(&x.path)),
+                None => Err(format!("unknown shared folder {:?}", title)),
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Rule {
+            user_agent,
+            subnets,
+            access: Access {
+                roots,
+                restricted: true,
+            },
+        })
+    }
+
+    fn matches(&self, user_agent: &str, ip: Option<IpAddr>) -> bool {
+        self.user_agent
+            .as_ref()
+            .is_none_or(|x| x.is_match(user_agent))
+            && (self.subnets.is_empty()
+                || ip.is_some_and(|ip| self.subnets.iter().any(|x| x.contains(ip))))
+    }
+}
+
+/// The `access` rules, tried in order; the first matching rule wins, and clients no
+/// rule matches see every shared folder.
+pub struct AccessRules {
+    rules: Vec<Rule>,
+    unrestricted: Access,
+}
+
+impl AccessRules {
+    /// Fails on a rule with an invalid `user_agent` pattern or subnet, or naming a
+    /// shared folder there is none of, rather than leave out what is meant to restrict
+    /// someone.
+    pub fn new(rules: &[AccessRule], roots: &[RootConfig]) -> Result<Self, String> {
+        Ok(AccessRules {
+            rules: rules
+                .iter()
+                .map(|x| Rule::new(x, roots))
+                .collect::<Result<_, _>>()?,
+            unrestricted: Access {
+                roots: roots.iter().map(|x| SharedRoot::new(&x.path)).collect(),
+                restricted: false,
+            },
+        })
+    }
+
+    pub fn find(&self, user_agent: &str, ip: Option<IpAddr>) -> Access {
+        // Dual-stack sockets report IPv4 clients as IPv4-mapped IPv6 addresses.
+        let ip = ip.map(|x| x.to_canonical());
+        self.rules
+            .iter()
+            .find(|x| x.matches(user_agent, ip))
+            .map_or_else(|| self.unrestricted.clone(), |x| x.access.clone())
+    }
+
+    pub fn for_request(&self, req: &HttpRequest) -> Access {
+        let user_agent = req
+            .headers()
+            .get(USER_AGENT)
+            .and_then(|x| x.to_str().ok())
+            .unwrap_or_default();
+        self.find(user_agent, req.peer_addr().map(|x| x.ip()))
+    }
+
+    /// Whether to answer discovery from `ip`. Discovery carries no User-Agent, so only
+    /// the addresses rules name count: the first rule whose `subnets` include `ip`
+    /// decides, answering unless it hides the whole library. Addresses no rule names
+    /// are answered.
+    pub fn allows_discovery(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let access = self
+            .rules
+            .iter()
+            .find(|x| x.subnets.iter().any(|x| x.contains(ip)))
+            .map_or(&self.unrestricted, |x| &x.access);
+        !access.is_denied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn root(title: &str, path: &Path) -> RootConfig {
+        RootConfig {
+            title: title.to_string(),
+            path: path.to_path_buf(),
+            collation: Default::default(),
+            flatten: false,
+        }
+    }
+
+    /// The rules in the README: one user agent limited to "Kids", and a subnet that
+    /// sees nothing.
+    fn readme_rules(roots: &[RootConfig]) -> AccessRules {
+        let rules = [
+            AccessRule {
+                user_agent: Some("KidsTV".to_string()),
+                roots: vec!["Kids".to_string()],
+                ..AccessRule::default()
+            },
+            AccessRule {
+                subnets: vec!["192.168.50.0/24".to_string()],
+                ..AccessRule::default()
+            },
+        ];
+        AccessRules::new(&rules, roots).unwrap()
+    }
+
+    #[test]
+    fn discovery_goes_by_address() {
+        let rules = readme_rules(&[root("Kids", Path::new("/media/kids"))]);
+        let ip = |x| IpAddr::V4(Ipv4Addr::new(192, 168, x, 7));
+        assert!(!rules.allows_discovery(ip(50)));
+        assert!(!rules.allows_discovery("::ffff:192.168.50.7".parse().unwrap()));
+        assert!(rules.allows_discovery(ip(1)));
+        // Browsing still goes by the first rule matching both.
+        assert!(rules
+            .find("KidsTV", Some(ip(50)))
+            .allows(Path::new("/media/kids/a.mkv")));
+        assert!(rules.find("VLC", Some(ip(50))).is_denied());
+    }
+
+    #[test]
+    fn invalid_pattern_is_an_error() {
+        let rules = [AccessRule {
+            user_agent: Some("Kids(".to_string()),
+            ..AccessRule::default()
+        }];
+        assert!(AccessRules::new(&rules, &[]).is_err());
+    }
+
+    #[test]
+    fn invalid_subnet_is_an_error() {
+        let rules = [AccessRule {
+            subnets: vec![
+                "192.168.50.0/24".to_string(),
+                "192.168.300.0/24".to_string(),
+            ],
+            ..AccessRule::default()
+        }];
+        let error = AccessRules::new(&rules, &[]).err().unwrap();
+        assert!(error.contains("192.168.300.0/24"), "{}", error);
+    }
+
+    #[test]
+    fn unknown_root_is_an_error() {
+        let roots = [root("Kids", Path::new("/media/kids"))];
+        let rules = [AccessRule {
+            roots: vec!["Kids".to_string(), "Cartoons".to_string()],
+            ..AccessRule::default()
+        }];
+        let error = AccessRules::new(&rules, &roots).err().unwrap();
+        assert!(error.contains("Cartoons"), "{}", error);
+    }
+
+    #[test]
+    fn unrestricted_clients_see_only_the_roots() {
+        let rules = readme_rules(&[
+            root("Kids", Path::new("/media/kids")),
+            root("Films", Path::new("/media/films")),
+        ]);
+        let all = rules.find("VLC", None);
+        assert!(!all.is_restricted());
+        assert!(all.allows(Path::new("/media/films/a.mkv")));
+        assert!(!all.allows(Path::new("/etc/passwd")));
+        assert!(!all.allows(Path::new("/media/films/../../etc/passwd")));
+        assert!(!all.allows(Path::new("/media/filmsx/a.mkv")));
+        let kids = rules.find("KidsTV/1.0", None);
+        assert!(kids.allows(Path::new("/media/kids/a.mkv")));
+        assert!(!kids.allows(Path::new("/media/films/a.mkv")));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn requested_files_are_resolved() {
+        let dir = std::env::temp_dir().join(format!("agni-access-{}", std::process::id()));
+        let shared = dir.join("shared");
+        fs::create_dir_all(&shared).unwrap();
+        fs::write(dir.join("secret.txt"), b"").unwrap();
+        fs::write(shared.join("a.mkv"), b"").unwrap();
+        fs::write(shared.join("Comic.cbz"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret.txt"), shared.join("escape.mkv")).unwrap();
+        std::os::unix::fs::symlink(&shared, dir.join("link")).unwrap();
+
+        // Shared through a symlink, as roots may be.
+        let rules = AccessRules::new(&[], &[root("Shared", &dir.join("link"))]).unwrap();
+        let access = rules.find("", None);
+        assert!(access.allows_file(&dir.join("link/a.mkv")));
+        assert!(access.allows_file(&shared.join("a.mkv")));
+        assert!(access.allows_file(&dir.join("link/Comic.cbz/01.jpg")));
+        assert!(!access.allows_file(&dir.join("link/escape.mkv")));
+        assert!(!access.allows_file(&dir.join("link/../secret.txt")));
+        assert!(!access.allows_file(&dir.join("secret.txt")));
+        assert!(!access.allows_file(&dir.join("link/missing.mkv")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
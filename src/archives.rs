@@ -0,0 +1,194 @@
+use actix_web::web::Bytes;
+use flate2::read::DeflateDecoder;
+use futures::stream::{self, Stream};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use zip::{CompressionMethod, ZipArchive};
+
+use crate::collation::{natural_order_strings, Collation};
+use crate::media_types::{media_type_for, PHOTO};
+
+const ARCHIVE_EXTENSIONS: &[&str] = &["zip", "cbz"];
+const CHUNK_SIZE: usize = 64 * 1024;
+
+pub fn is_archive(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| ARCHIVE_EXTENSIONS.contains(&x.to_lowercase().as_str()))
+}
+
+fn to_io_error<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+fn open(archive: &Path) -> io::Result<ZipArchive<File>> {
+    ZipArchive::new(File::open(archive)?).map_err(to_io_error)
+}
+
+/// Members agni can read without help: stored or deflated, and not encrypted.
+fn is_supported(compression: CompressionMethod) -> bool {
+    matches!(
+        compression,
+        CompressionMethod::Stored | CompressionMethod::Deflated
+    )
+}
+
+/// The images in `archive`, by their path inside it, with their size. Folders and the
+/// `__MACOSX` resource forks macOS adds are left out.
+pub fn image_members(archive: &Path) -> io::Result<Vec<(PathBuf, u64)>> {
+    let mut zip = open(archive)?;
+    let mut members = vec![];
+    for index in 0..zip.len() {
+        let file = match zip.by_index(index) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let name: PathBuf = match file.enclosed_name() {
+            Some(x) => x.components().collect(),
+            None => continue,
+        };
+        let hidden = name.components().any(|x| {
+            let x = x.as_os_str().to_string_lossy();
+            x.starts_with('.') || x == "__MACOSX"
+        });
+        if file.is_file()
+            && !hidden
+            && is_supported(file.compression())
+            && media_type_for(&name).is_some_and(|x| x.class == PHOTO)
+        {
+            members.push((name, file.size()));
+        }
+    }
+    Ok(members)
+}
+
+/// The first image of `archive` in natural order, such as the cover of a comic.
+pub fn cover(archive: &Path) -> Option<PathBuf> {
+    let members = image_members(archive).ok()?;
+    let (name, _) = members.iter().min_by(|(a, _), (b, _)| {
+        natural_order_strings(
+            &a.to_string_lossy(),
+            &b.to_string_lossy(),
+            Collation::default(),
+        )
+    })?;
+    Some(archive.join(name))
+}
+
+/// Splits the path of an archive member into the archive and the path inside it.
+/// Paths that exist on disk are not members. Only ancestors named like archives are
+/// looked at, so most paths are told apart without touching the disk.
+pub fn split_member(path: &Path) -> Option<(&Path, &Path)> {
+    let mut archives = path
+        .ancestors()
+        .skip(1)
+        .filter(|x| is_archive(x))
+        .peekable();
+    archives.peek()?;
+    if path.exists() {
+        return None;
+    }
+    let archive = archives.find(|x| x.is_file())?;
+    Some((archive, path.strip_prefix(archive).ok()?))
+}
+
+/// Opens member `name` of `archive`, reading it straight out of the archive, and gives
+/// its size.
+pub fn open_member(archive: &Path, name: &Path) -> io::Result<(Box<dyn Read + Send>, u64)> {
+    let mut zip = open(archive)?;
+    let stored_name = zip
+        .file_names()
+        .find(|x| Path::new(x).components().eq(name.components()))
+        .map(str::to_string)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no such member"))?;
+    let (compression, start, compressed_size, size) = {
+        let file = zip.by_name(&stored_name).map_err(to_io_error)?;
+        (
+            file.compression(),
+            file.data_start(),
+            file.compressed_size(),
+            file.size(),
+        )
+    };
+    let mut file = zip.into_inner();
+    file.seek(SeekFrom::Start(start))?;
+    let data = file.take(compressed_size);
+    let reader: Box<dyn Read + Send> = match compression {
+        CompressionMethod::Stored => Box::new(data),
+        CompressionMethod::Deflated => Box::new(DeflateDecoder::new(data).take(size)),
+        x => return Err(to_io_error(format!("unsupported compression {}", x))),
+    };
+    Ok((reader, size))
+}
+
+/// The contents of `path`, which may be a file or an archive member.
+pub fn read(path: &Path) -> io::Result<Vec<u8>> {
+    let (archive, name) = match split_member(path) {
+        Some(x) => x,
+        None => return fs::read(path),
+    };
+    let (mut reader, size) = open_member(archive, name)?;
+    let mut data = Vec::with_capacity(size.min(1 << 24) as usize);
+    reader.read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Streams what `reader` reads, a chunk at a time off the async executor.
+pub fn member_stream(
+    reader: Box<dyn Read + Send>,
+) -> impl Stream<Item = io::Result<Bytes>> + Unpin {
+    Box::pin(stream::unfold(Some(reader), |reader| async move {
+        let mut reader = reader?;
+        let read = tokio::task::spawn_blocking(move || {
+            let mut buf = vec![0; CHUNK_SIZE];
+            let read = reader.read(&mut buf);
+            (reader, buf, read)
+        })
+        .await;
+        match read {
+            Ok((_, _, Ok(0))) => None,
+            Ok((reader, mut buf, Ok(n))) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some(reader)))
+            }
+            Ok((_, _, Err(e))) => Some((Err(e), None)),
+            Err(e) => Some((Err(io::Error::other(e)), None)),
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn splits_member_paths() {
+        let dir = std::env::temp_dir().join(format!("agni-archives-{}", std::process::id()));
+        fs::create_dir_all(dir.join("Folder.zip")).unwrap();
+        fs::write(dir.join("Comic.CBZ"), b"").unwrap();
+        fs::write(dir.join("Folder.zip/inner.zip"), b"").unwrap();
+
+        let member = dir.join("Comic.CBZ/pages/01.jpg");
+        assert_eq!(
+            split_member(&member),
+            Some((dir.join("Comic.CBZ").as_path(), Path::new("pages/01.jpg")))
+        );
+        // A folder named like an archive is passed over for the archive inside it.
+        let nested = dir.join("Folder.zip/inner.zip/01.jpg");
+        assert_eq!(
+            split_member(&nested),
+            Some((
+                dir.join("Folder.zip/inner.zip").as_path(),
+                Path::new("01.jpg")
+            ))
+        );
+        assert_eq!(split_member(&dir.join("Folder.zip/missing.jpg")), None);
+        assert_eq!(split_member(&dir.join("Folder.zip/inner.zip")), None);
+        assert_eq!(split_member(&dir.join("Comic.cbr/01.jpg")), None);
+        assert_eq!(split_member(Path::new("/no/archive/here.jpg")), None);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
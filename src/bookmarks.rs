@@ -0,0 +1,346 @@
+use percent_encoding::{percent_decode_str, percent_encode, NON_ALPHANUMERIC};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::types::{AppState, ListItem};
+use crate::util::{path_bytes, path_from_bytes};
+
+pub const BOOKMARKS_FILE: &str = "bookmarks.json";
+/// Share of a file that must have been streamed for it to count as watched.
+pub const WATCHED_THRESHOLD: f64 = 0.9;
+/// How far, as a share of the file, a read may start past the point playback got to
+/// and still count as carrying on from it, for renderers that skip ahead a little.
+const RESUME_SLACK: f64 = 0.01;
+/// How often changed bookmarks are written out.
+pub const SAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Bookmark {
+    /// Where playback stopped, in seconds.
+    pub position: u64,
+    pub play_count: u32,
+    /// How far into the file, from 0 to 1, playback read without skipping ahead.
+    pub progress: f64,
+    pub watched: bool,
+    /// When the file was last streamed, in seconds since the Unix epoch.
+    pub last_played: u64,
+}
+
+impl Bookmark {
+    pub fn in_progress(&self) -> bool {
+        !self.watched && (self.position > 0 || self.progress > 0.0)
+    }
+}
+
+/// Resume positions, play counts and watched state, persisted as JSON in the data
+/// directory.
+///
+/// Entries are keyed by the media file behind an object rather than its object ID,
+/// which is only valid until agni restarts. Paths that are not UTF-8 are stored
+/// percent-encoded, which cannot be mistaken for a plain path as `/` is encoded too.
+#[derive(Debug, Default)]
+pub struct BookmarkStore {
+    path: PathBuf,
+    bookmarks: HashMap<String, Bookmark>,
+    /// Prefixed to the titles of watched items.
+    watched_marker: Option<String>,
+    /// Whether there are changes not yet written out.
+    changed: bool,
+}
+
+/// A snapshot of the store, to be written out away from the async executor.
+#[derive(Debug)]
+pub struct PendingSave {
+    path: PathBuf,
+    contents: Vec<u8>,
+}
+
+impl PendingSave {
+    pub fn write(&self) {
+        let write = || -> io::Result<()> {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            // Write next to the store and rename, so a crash never leaves it half written.
+            let temporary = self.path.with_extension("json.tmp");
+            fs::write(&temporary, &self.contents)?;
+            fs::rename(&temporary, &self.path)
+        };
+        if let Err(e) = write() {
+            log::warn!(
+                "Could not persist bookmarks to {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+/// Writes out the bookmarks if they changed, on a blocking thread.
+pub async fn write_pending(app_state: &Arc<Mutex<AppState>>) {
+    let pending = app_state.lock().unwrap().bookmarks.pending_save();
+    if let Some(pending) = pending {
+        if let Err(e) = tokio::task::spawn_blocking(move || pending.write()).await {
+            log::warn!("Could not persist bookmarks: {}", e);
+        }
+    }
+}
+
+/// Formats seconds as a UPnP duration, `H:MM:SS`.
+pub fn format_duration(seconds: u64) -> String {
+    format!(
+        "{}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// Playing time of `path`, where the container records it.
+pub fn media_duration(path: &Path) -> Option<Duration> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "mp4" | "m4v" | "m4a" => mp4ameta::Tag::read_from_path(path).ok()?.duration(),
+        _ => None,
+    }
+}
+
+fn key(file: &Path) -> String {
+    match file.to_str() {
+        Some(x) if x.starts_with('/') => x.to_string(),
+        _ => percent_encode(path_bytes(file), NON_ALPHANUMERIC).to_string(),
+    }
+}
+
+fn path_for_key(key: &str) -> PathBuf {
+    if key.starts_with('/') {
+        PathBuf::from(key)
+    } else {
+        path_from_bytes(percent_decode_str(key).collect())
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+impl BookmarkStore {
+    pub fn load(data_dir: &Path, watched_marker: Option<String>) -> Self {
+        let path = data_dir.join(BOOKMARKS_FILE);
+        let bookmarks = match fs::read_to_string(&path) {
+            Ok(contents) => serde_json::from_str(&contents).unwrap_or_else(|e| {
+                log::error!("Failed to parse bookmarks {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        BookmarkStore {
+            path,
+            bookmarks,
+            watched_marker,
+            changed: false,
+        }
+    }
+
+    /// The store as it is now, if it changed since the last call. Changes are written
+    /// out in batches every [`SAVE_INTERVAL`] rather than each time.
+    pub fn pending_save(&mut self) -> Option<PendingSave> {
+        if !self.changed {
+            return None;
+        }
+        let contents = match serde_json::to_vec(&self.bookmarks) {
+            Ok(x) => x,
+            Err(e) => {
+                log::warn!("Could not serialize bookmarks: {}", e);
+                return None;
+            }
+        };
+        self.changed = false;
+        Some(PendingSave {
+            path: self.path.clone(),
+            contents,
+        })
+    }
+
+    pub fn get(&self, file: &Path) -> Option<Bookmark> {
+        self.bookmarks.get(&key(file)).copied()
+    }
+
+    pub fn is_watched(&self, file: &Path) -> bool {
+        self.get(file).is_some_and(|x| x.watched)
+    }
+
+    /// Files marked watched.
+    pub fn watched(&self) -> HashSet<PathBuf> {
+        self.bookmarks
+            .iter()
+            .filter(|(_, x)| x.watched)
+            .map(|(x, _)| path_for_key(x))
+            .collect()
+    }
+
+    /// Files started but not finished, most recently played first.
+    pub fn in_progress(&self) -> Vec<PathBuf> {
+        let mut files: Vec<(&String, &Bookmark)> = self
+            .bookmarks
+            .iter()
+            .filter(|(_, x)| x.in_progress())
+            .collect();
+        files.sort_by(|a, b| b.1.last_played.cmp(&a.1.last_played).then(a.0.cmp(b.0)));
+        files.into_iter().map(|(x, _)| path_for_key(x)).collect()
+    }
+
+    pub fn set_position(&mut self, file: &Path, position: u64) {
+        self.bookmarks.entry(key(file)).or_default().position = position;
+        self.changed = true;
+    }
+
+    /// Counts a play of `file` from the top. After one that got to the end, progress
+    /// starts over, so that watching it again moves the resume position.
+    pub fn record_play(&mut self, file: &Path) {
+        let bookmark = self.bookmarks.entry(key(file)).or_default();
+        if bookmark.progress >= WATCHED_THRESHOLD {
+            bookmark.progress = 0.0;
+        }
+        bookmark.play_count += 1;
+        bookmark.last_played = now();
+        self.changed = true;
+    }
+
+    /// Records that a stream of `file` read bytes `start..end` of its `size`. Only a
+    /// read that carries on from where playback got counts, so a renderer probing the
+    /// end of a file for its index does not mark it watched. The resume position
+    /// follows from the playing time, when known.
+    pub fn record_progress(
+        &mut self,
+        file: &Path,
+        start: u64,
+        end: u64,
+        size: u64,
+        duration: Option<Duration>,
+    ) {
+        if size == 0 {
+            return;
+        }
+        let reached = self.get(file).map_or(0.0, |x| x.progress);
+        let start = start as f64 / size as f64;
+        let progress = (end as f64 / size as f64).min(1.0);
+        if start > reached + RESUME_SLACK || progress <= reached {
+            return;
+        }
+        let bookmark = self.bookmarks.entry(key(file)).or_default();
+        bookmark.progress = progress;
+        bookmark.last_played = now();
+        if progress >= WATCHED_THRESHOLD {
+            bookmark.watched = true;
+            bookmark.position = 0;
+        } else if let Some(duration) = duration {
+            bookmark.watched = false;
+            bookmark.position = (duration.as_secs_f64() * progress) as u64;
+        }
+        self.changed = true;
+    }
+
+    /// Adds the resume position, play count and watched marker of `file`, if known, to
+    /// its item.
+    pub fn apply(&self, list_item: &mut ListItem, file: Option<&Path>) {
+        let bookmark = match file.and_then(|x| self.get(x)) {
+            Some(x) => x,
+            None => return,
+        };
+        if let ListItem::Item(item) = list_item {
+            if bookmark.position > 0 {
+                item.last_playback_position = Some(format_duration(bookmark.position));
+                // Samsung renderers resume from the BM field instead.
+                item.dcm_info = Some(format!("BM={}", bookmark.position));
+            }
+            item.playback_count = Some(bookmark.play_count);
+            if let (true, Some(marker)) = (bookmark.watched, &self.watched_marker) {
+                item.title = format!("{}{}", marker, item.title);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = "/media/Movie.mkv";
+    const SIZE: u64 = 1_000_000;
+
+    fn progress(store: &BookmarkStore) -> Option<(f64, bool)> {
+        store.get(Path::new(FILE)).map(|x| (x.progress, x.watched))
+    }
+
+    #[test]
+    fn probing_the_end_is_not_playback() {
+        let mut store = BookmarkStore::default();
+        store.record_progress(Path::new(FILE), SIZE - 64_000, SIZE, SIZE, None);
+        assert_eq!(progress(&store), None);
+        assert!(store.pending_save().is_none());
+    }
+
+    #[test]
+    fn contiguous_reads_add_up() {
+        let mut store = BookmarkStore::default();
+        store.record_progress(Path::new(FILE), 0, SIZE / 2, SIZE, None);
+        assert_eq!(progress(&store), Some((0.5, false)));
+        store.record_progress(Path::new(FILE), SIZE - 100, SIZE, SIZE, None);
+        assert_eq!(progress(&store), Some((0.5, false)));
+        store.record_progress(Path::new(FILE), SIZE / 2, SIZE, SIZE, None);
+        assert_eq!(progress(&store), Some((1.0, true)));
+    }
+
+    #[test]
+    fn rereading_the_start_keeps_progress() {
+        let mut store = BookmarkStore::default();
+        store.record_progress(Path::new(FILE), 0, SIZE / 2, SIZE, None);
+        store.record_progress(Path::new(FILE), 0, 4096, SIZE, None);
+        assert_eq!(progress(&store), Some((0.5, false)));
+    }
+
+    #[test]
+    fn playing_again_starts_over() {
+        let mut store = BookmarkStore::default();
+        let duration = Some(Duration::from_secs(1000));
+        store.record_play(Path::new(FILE));
+        store.record_progress(Path::new(FILE), 0, SIZE, SIZE, duration);
+        assert_eq!(progress(&store), Some((1.0, true)));
+
+        store.record_play(Path::new(FILE));
+        store.record_progress(Path::new(FILE), 0, SIZE / 4, SIZE, duration);
+        let bookmark = store.get(Path::new(FILE)).unwrap();
+        assert_eq!((bookmark.progress, bookmark.position), (0.25, 250));
+        assert_eq!(bookmark.play_count, 2);
+
+        // Picking up an unfinished play from the top keeps its progress.
+        store.record_play(Path::new(FILE));
+        assert_eq!(store.get(Path::new(FILE)).unwrap().progress, 0.25);
+    }
+
+    #[test]
+    fn resume_position_from_duration() {
+        let mut store = BookmarkStore::default();
+        let duration = Some(Duration::from_secs(1000));
+        store.record_progress(Path::new(FILE), 0, SIZE / 4, SIZE, duration);
+        assert_eq!(store.get(Path::new(FILE)).unwrap().position, 250);
+    }
+
+    #[test]
+    fn saves_only_changes() {
+        let mut store = BookmarkStore::default();
+        assert!(store.pending_save().is_none());
+        store.record_play(Path::new(FILE));
+        assert!(store.pending_save().is_some());
+        assert!(store.pending_save().is_none());
+    }
+}
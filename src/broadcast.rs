@@ -1,198 +1,270 @@
-use crate::util::get_local_ip;
-use std::net::{IpAddr, Ipv4Addr};
+use crate::access::AccessRules;
+use crate::config::InterfaceConfig;
+use crate::ssdp::{Device, SearchRequest, SsdpMessage};
+use crate::util::{get_local_ip, get_local_ip_for, LocalAddress};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::HashSet;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
+use std::sync::Arc;
 use tokio::net::UdpSocket;
 use uuid::Uuid;
 
-pub async fn broadcast_message<'a>(desc: &'a str, data: &'a [u8], unicast_ip: &Option<String>) {
-    let mut socket = UdpSocket::bind("[::]:0").await.unwrap();
-    let addr = if let Some(x) = unicast_ip {
-        x.to_owned()
-    } else {
-        "239.255.255.250:1900".to_string()
-    };
-    if !addr.contains("239.255.255.250:1900") {
-        println!("To Address: {:?}\nMessage:\n {:?}\n", addr, String::from_utf8_lossy(data));
+pub const SSDP_PORT: u16 = 1900;
+pub const SSDP_MULTICAST_V4: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+pub const SSDP_MULTICAST_V6_LINK_LOCAL: Ipv6Addr = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 0xc);
+pub const SSDP_MULTICAST_V6_SITE_LOCAL: Ipv6Addr = Ipv6Addr::new(0xff05, 0, 0, 0, 0, 0, 0, 0xc);
+
+pub fn is_ipv6_link_local(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xffc0 == 0xfe80
+}
+
+/// The SSDP multicast group announcements from `ip` go to: link-local addresses use
+/// `FF02::C`, every other IPv6 address `FF05::C`.
+pub fn ssdp_multicast_addr(ip: IpAddr) -> SocketAddr {
+    match ip {
+        IpAddr::V4(_) => SocketAddr::from((SSDP_MULTICAST_V4, SSDP_PORT)),
+        IpAddr::V6(x) if is_ipv6_link_local(&x) => {
+            SocketAddr::from((SSDP_MULTICAST_V6_LINK_LOCAL, SSDP_PORT))
+        }
+        IpAddr::V6(_) => SocketAddr::from((SSDP_MULTICAST_V6_SITE_LOCAL, SSDP_PORT)),
+    }
+}
+
+/// The multicast destination for `address`, scoped to its interface for IPv6.
+fn ssdp_destination(address: &LocalAddress) -> SocketAddr {
+    match ssdp_multicast_addr(address.network.ip()) {
+        SocketAddr::V6(x) => SocketAddr::V6(SocketAddrV6::new(
+            *x.ip(),
+            x.port(),
+            0,
+            address.interface_index,
+        )),
+        x => x,
     }
-    socket
-        .send_to(data, addr)
-        .await
-        .map(|bytes_written| {
-            if bytes_written != data.len() {
-                eprintln!("W: sending of {} truncated.", desc);
+}
+
+fn udp_socket_v6() -> io::Result<Socket> {
+    let socket = Socket::new(Domain::ipv6(), Type::dgram(), Some(Protocol::udp()))?;
+    socket.set_only_v6(true)?;
+    Ok(socket)
+}
+
+async fn bind_sender(destination: &SocketAddr) -> io::Result<UdpSocket> {
+    match destination {
+        SocketAddr::V4(_) => UdpSocket::bind("0.0.0.0:0").await,
+        SocketAddr::V6(x) => {
+            let socket = udp_socket_v6()?;
+            if x.ip().is_multicast() && x.scope_id() != 0 {
+                socket.set_multicast_if_v6(x.scope_id())?;
             }
-        })
-        .unwrap();
+            socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+            socket.set_nonblocking(true)?;
+            UdpSocket::from_std(socket.into_udp_socket())
+        }
+    }
 }
 
-pub async fn broadcast_presence<'a>(uuid: Uuid, unicast_ip: Option<String>) {
-    let make_msg = |ip: IpAddr, nt: &str, usn: &str| {
-        format!(
-            "\
-NOTIFY * HTTP/1.1\r\n\
-HOST: 239.255.255.250:1900\r\n\
-NT: {}\r\n\
-NTS: ssdp:alive\r\n\
-LOCATION: http://{}:3030/root.xml\r\n\
-USN: {}\r\n\
-CACHE-CONTROL: max-age=1800\r\n\
-SERVER: Linux/5.8, UPnP/1.0, agni/1.0\r\n\
-\r\n",
-            nt, ip, usn
-        )
-        .into_bytes()
+pub async fn broadcast_message(desc: &str, data: &[u8], destination: SocketAddr) {
+    if !destination.ip().is_multicast() {
+        println!(
+            "To Address: {:?}\nMessage:\n {:?}\n",
+            destination,
+            String::from_utf8_lossy(data)
+        );
+    }
+    let mut socket = match bind_sender(&destination).await {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("W: could not open socket for {}: {}", destination, e);
+            return;
+        }
     };
+    match socket.send_to(data, destination).await {
+        Ok(bytes_written) if bytes_written != data.len() => {
+            eprintln!("W: sending of {} truncated.", desc)
+        }
+        Ok(_) => {}
+        Err(e) => eprintln!("W: sending of {} to {} failed: {}", desc, destination, e),
+    }
+}
 
-    let make_dup = move |ip, uuid_urn: &str, nt| make_msg(ip, nt, &format!("{}::{}", uuid_urn, nt));
+const ADVERTISED_TYPES: [&str; 4] = [
+    "upnp:rootdevice",
+    "urn:schemas-upnp-org:device:MediaServer:1",
+    "urn:schemas-upnp-org:service:ConnectionManager:1",
+    "urn:schemas-upnp-org:service:ContentDirectory:1",
+];
 
-    let msg_root = move |ip, uuid_urn: &str| make_dup(ip, uuid_urn, "upnp:rootdevice");
-    let msg_mediaserver = move |ip, uuid_urn: &str| {
-        make_dup(ip, uuid_urn, "urn:schemas-upnp-org:device:MediaServer:1")
-    };
-    let msg_contentdir = move |ip, uuid_urn: &str| {
-        make_dup(
-            ip,
-            uuid_urn,
-            "urn:schemas-upnp-org:service:ContentDirectory:1",
-        )
-    };
-    let msg_connectionmanager = move |ip, uuid_urn: &str| {
-        make_dup(
-            ip,
-            uuid_urn,
-            "urn:schemas-upnp-org:service:ConnectionManager:1",
-        )
-    };
-    let msg_uuid = |ip, uuid_urn: &str| make_msg(ip, uuid_urn, uuid_urn);
-
-    let broadcast = || async move {
-        // println!("Broadcasted");
-        let ips = get_local_ip();
-        let uuid_urn = format!("uuid:{}", uuid);
-
-        // log::info!("PUBLIC IP : {:#?}", ips);
-
-        for ip in ips {
-            for _ in 0..3i32 {
-                broadcast_message("uuid", &msg_uuid(ip, &uuid_urn), &unicast_ip).await;
-                broadcast_message("root", &msg_root(ip, &uuid_urn), &unicast_ip).await;
-                broadcast_message("mediaserver", &msg_mediaserver(ip, &uuid_urn), &unicast_ip)
-                    .await;
-                broadcast_message(
-                    "connectionmanager",
-                    &msg_connectionmanager(ip, &uuid_urn),
-                    &unicast_ip,
-                )
-                .await;
-                broadcast_message("contentdir", &msg_contentdir(ip, &uuid_urn), &unicast_ip).await;
+/// The `(NT, USN)` pairs agni announces: the bare device UUID plus one per type.
+fn advertisements(device: &Device) -> Vec<(String, String)> {
+    let uuid_urn = format!("uuid:{}", device.uuid);
+    let mut advertisements = vec![(uuid_urn.clone(), uuid_urn.clone())];
+    for nt in ADVERTISED_TYPES.iter() {
+        advertisements.push((nt.to_string(), format!("{}::{}", uuid_urn, nt)));
+    }
+    advertisements
+}
+
+fn location_url(ip: IpAddr) -> String {
+    format!("http://{}/root.xml", SocketAddr::new(ip, 3030))
+}
+
+pub async fn broadcast_presence(
+    device: &Device,
+    interface_config: &InterfaceConfig,
+    unicast_ip: Option<SocketAddr>,
+) {
+    for address in get_local_ip(interface_config) {
+        let ip = address.network.ip();
+        let to = unicast_ip.unwrap_or_else(|| ssdp_destination(&address));
+        for _ in 0..3i32 {
+            for (nt, usn) in advertisements(device) {
+                let host = ssdp_multicast_addr(ip);
+                let message = SsdpMessage::alive(device, host, &nt, &usn, &location_url(ip));
+                broadcast_message(&nt, &message.to_bytes(), to).await;
             }
         }
-    };
+    }
+}
 
-    broadcast().await;
+/// Tells control points agni is going away, so they drop it before `max-age` expires.
+pub async fn broadcast_byebye(device: &Device, interface_config: &InterfaceConfig) {
+    for address in get_local_ip(interface_config) {
+        let host = ssdp_multicast_addr(address.network.ip());
+        for (nt, usn) in advertisements(device) {
+            let message = SsdpMessage::byebye(device, host, &nt, &usn);
+            broadcast_message(&nt, &message.to_bytes(), ssdp_destination(&address)).await;
+        }
+    }
 }
 
-pub async fn reply_presence<'a>(uuid: Uuid, unicast_ip: Option<String>) {
-    let make_msg = |ip: IpAddr, nt: &str, usn: &str| {
-        format!(
-            "\
-HTTP/1.1 200 OK\r\n\
-CACHE-CONTROL: max-age=1800\r\n\
-DATE: {}\r\n\
-EXT:\r\n\
-LOCATION: http://{}:3030/root.xml\r\n\
-SERVER: Linux/5.8, UPnP/1.0, agni/1.0\r\n\
-ST: {}\r\n\
-USN: {}\r\n\
-\r\n",
-            chrono::offset::Utc::now().format("%a, %m %b %Y %H:%M:%S GMT"),
-            ip,
-            nt,
-            usn
-        )
-        .into_bytes()
+/// Answers an M-SEARCH from `remote` with the LOCATION of the interface it arrived on.
+pub async fn reply_presence(
+    device: &Device,
+    interface_config: &InterfaceConfig,
+    remote: SocketAddr,
+    request: &SearchRequest,
+) {
+    let local_ip = match get_local_ip_for(interface_config, remote) {
+        Some(x) => x.network.ip(),
+        None => {
+            log::debug!("Ignoring M-SEARCH from {}: no matching interface", remote);
+            return;
+        }
     };
 
-    let make_dup = move |ip, uuid_urn: &str, nt| make_msg(ip, nt, &format!("{}::{}", uuid_urn, nt));
+    let location = location_url(local_ip);
+    let responses: Vec<SsdpMessage> = advertisements(device)
+        .into_iter()
+        .filter(|(nt, _)| request.matches(nt))
+        .map(|(nt, usn)| SsdpMessage::search_response(device, &nt, &usn, &location))
+        .collect();
 
-    let msg_root = move |ip, uuid_urn: &str| make_dup(ip, uuid_urn, "upnp:rootdevice");
-    let msg_mediaserver = move |ip, uuid_urn: &str| {
-        make_dup(ip, uuid_urn, "urn:schemas-upnp-org:device:MediaServer:1")
-    };
-    let msg_contentdir = move |ip, uuid_urn: &str| {
-        make_dup(
-            ip,
-            uuid_urn,
-            "urn:schemas-upnp-org:service:ContentDirectory:1",
-        )
-    };
-    let msg_connectionmanager = move |ip, uuid_urn: &str| {
-        make_dup(
-            ip,
-            uuid_urn,
-            "urn:schemas-upnp-org:service:ConnectionManager:1",
-        )
-    };
-    let msg_uuid = |ip, uuid_urn: &str| make_msg(ip, uuid_urn, uuid_urn);
-
-    let broadcast = || async move {
-        // println!("Broadcasted");
-        let ips = get_local_ip();
-        let uuid_urn = format!("uuid:{}", uuid);
-
-        // log::info!("PUBLIC IP : {:#?}", ips);
-
-        for ip in ips {
-            for _ in 0..3i32 {
-                broadcast_message("uuid", &msg_uuid(ip, &uuid_urn), &unicast_ip).await;
-                broadcast_message("root", &msg_root(ip, &uuid_urn), &unicast_ip).await;
-                broadcast_message("mediaserver", &msg_mediaserver(ip, &uuid_urn), &unicast_ip)
-                    .await;
-                broadcast_message(
-                    "connectionmanager",
-                    &msg_connectionmanager(ip, &uuid_urn),
-                    &unicast_ip,
-                )
-                .await;
-                broadcast_message("contentdir", &msg_contentdir(ip, &uuid_urn), &unicast_ip).await;
-            }
+    for _ in 0..3i32 {
+        for response in &responses {
+            broadcast_message("search response", &response.to_bytes(), remote).await;
         }
-    };
+    }
+}
 
-    broadcast().await;
+fn bind_discover_v4(addresses: &[LocalAddress]) -> io::Result<std::net::UdpSocket> {
+    let socket = std::net::UdpSocket::bind((SSDP_MULTICAST_V4, SSDP_PORT))?;
+    for address in addresses {
+        if let IpAddr::V4(ip) = address.network.ip() {
+            match socket.join_multicast_v4(&SSDP_MULTICAST_V4, &ip) {
+                Ok(_) => println!("Connected to address: {}", ip),
+                Err(_) => println!("Address already in use: {}", ip),
+            };
+        }
+    }
+    Ok(socket)
 }
 
-pub async fn listen_to_discover_messages(uuid: Uuid) {
-    let mut socket = UdpSocket::bind("239.255.255.250:1900").await.unwrap();
-    let ips = get_local_ip();
-    for ip in ips {
-        let ip_string = ip.to_string();
-        let tokens = ip_string.split('.').collect::<Vec<&str>>();
-        let a = tokens[0].parse::<u8>().unwrap();
-        let b = tokens[1].parse::<u8>().unwrap();
-        let c = tokens[2].parse::<u8>().unwrap();
-        let d = tokens[3].parse::<u8>().unwrap();
-        match socket.join_multicast_v4(Ipv4Addr::new(239, 255, 255, 250), Ipv4Addr::new(a,b,c,d)) {
-            Ok(_) => println!("Connected to address: {}", &ip_string),
-            Err(_) => println!("Address already in use: {}", &ip_string),
-        };
+fn bind_discover_v6(addresses: &[LocalAddress]) -> io::Result<std::net::UdpSocket> {
+    let socket = udp_socket_v6()?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, SSDP_PORT)).into())?;
+    let socket = socket.into_udp_socket();
+    let interfaces: HashSet<u32> = addresses
+        .iter()
+        .filter(|x| x.network.is_ipv6())
+        .map(|x| x.interface_index)
+        .collect();
+    for interface in interfaces {
+        for group in &[SSDP_MULTICAST_V6_LINK_LOCAL, SSDP_MULTICAST_V6_SITE_LOCAL] {
+            match socket.join_multicast_v6(group, interface) {
+                Ok(_) => println!("Joined {} on interface {}", group, interface),
+                Err(e) => println!("Could not join {} on interface {}: {}", group, interface, e),
+            };
+        }
+    }
+    Ok(socket)
+}
+
+async fn serve_discover_messages(
+    socket: io::Result<std::net::UdpSocket>,
+    device: &Device,
+    interface_config: &InterfaceConfig,
+    access: &AccessRules,
+) {
+    let mut socket = match socket.and_then(|x| {
+        x.set_nonblocking(true)?;
+        UdpSocket::from_std(x)
+    }) {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!("Could not listen for discovery messages: {}", e);
+            return;
+        }
     };
-    
 
     loop {
         let mut buf = [0; 2048];
 
         match socket.recv_from(&mut buf).await {
             Ok((received, addr)) => {
-                let s = String::from_utf8_lossy(&buf[..received]);
-                if s.contains("M-SEARCH")
-                    && s.contains("ssdp:discover")
-                    && s.contains("ContentDirectory")
-                {
-                    // println!("From Address: {:?}\nMessage:\n {:?}\n", addr.to_string(), s);
-                    reply_presence(uuid.clone(), Some(addr.to_string())).await;
+                if !access.allows_discovery(addr.ip()) {
+                    log::debug!("Ignoring M-SEARCH from {}: access denied", addr);
+                    continue;
+                }
+                if let Some(request) = SearchRequest::parse(&buf[..received]) {
+                    // println!("From Address: {:?}\nMessage:\n {:?}\n", addr.to_string(), request);
+                    let device = *device;
+                    let interface_config = interface_config.clone();
+                    let delay = request.response_delay(Uuid::new_v4().as_u128() as u32);
+                    tokio::spawn(async move {
+                        tokio::time::delay_for(delay).await;
+                        reply_presence(&device, &interface_config, addr, &request).await;
+                    });
                 }
             }
             Err(e) => eprintln!("recv function failed: {:?}", e),
         };
     }
 }
+
+pub async fn listen_to_discover_messages(
+    device: Device,
+    interface_config: InterfaceConfig,
+    access: Arc<AccessRules>,
+) {
+    let addresses = get_local_ip(&interface_config);
+    let v4 = serve_discover_messages(
+        bind_discover_v4(&addresses),
+        &device,
+        &interface_config,
+        &access,
+    );
+    if interface_config.ipv6 {
+        let v6 = serve_discover_messages(
+            bind_discover_v6(&addresses),
+            &device,
+            &interface_config,
+            &access,
+        );
+        tokio::join!(v4, v6);
+    } else {
+        v4.await;
+    }
+}
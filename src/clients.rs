@@ -0,0 +1,341 @@
+use actix_web::http::header::USER_AGENT;
+use actix_web::HttpRequest;
+use pnet::ipnetwork::IpNetwork;
+use regex::{Regex, RegexBuilder};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::config::parse_subnets;
+use crate::transcoding::transcode_name;
+use crate::types::ListItem;
+
+const BUILTIN_PROFILES: &str = include_str!("client_profiles.json");
+/// Sony renderers identify themselves with this header rather than the User-Agent.
+pub const X_AV_CLIENT_INFO: &str = "x-av-client-info";
+
+/// How agni talks to a family of renderers. Every field is optional; the default
+/// profile describes a generic DLNA client.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ClientProfile {
+    pub name: String,
+    /// Case-insensitive regex matched against the User-Agent header.
+    pub user_agent: Option<String>,
+    /// Case-insensitive regex matched against the `X-AV-Client-Info` header.
+    pub client_info: Option<String>,
+    /// Source addresses this profile applies to, e.g. `192.168.1.20/32`.
+    pub subnets: Vec<String>,
+    /// MIME types to advertise and serve instead of agni's own, keyed by the latter.
+    pub mime_types: HashMap<String, String>,
+    /// Names of the transcoding profiles offered to this client; all when unset.
+    pub transcoding: Option<Vec<String>>,
+    /// Largest DIDL-Lite document, in bytes, sent in a single Browse response.
+    pub max_didl_size: Option<usize>,
+    /// Whether to send Samsung's `sec:CaptionInfoEx` and `CaptionInfo.sec` subtitle hints.
+    pub caption_info: bool,
+    /// Extra headers sent with every media response.
+    pub headers: HashMap<String, String>,
+}
+
+impl Default for ClientProfile {
+    fn default() -> Self {
+        ClientProfile {
+            name: "Generic".to_string(),
+            user_agent: None,
+            client_info: None,
+            subnets: vec![],
+            mime_types: HashMap::new(),
+            transcoding: None,
+            max_didl_size: None,
+            caption_info: true,
+            headers: HashMap::new(),
+        }
+    }
+}
+
+impl ClientProfile {
+    pub fn mime<'a>(&'a self, mime: &'a str) -> &'a str {
+        self.mime_types.get(mime).map_or(mime, |x| x.as_str())
+    }
+
+    pub fn allows_transcoder(&self, name: &str) -> bool {
+        self.transcoding
+            .as_ref()
+            .is_none_or(|x| x.iter().any(|x| x == name))
+    }
+
+    /// Rewrites the MIME type in a `protocol:network:mime:info` string.
+    pub fn protocol_info(&self, protocol_info: &str) -> String {
+        let parts: Vec<&str> = protocol_info.splitn(4, ':').collect();
+        match parts.as_slice() {
+            [protocol, network, mime, info] => {
+                format!("{}:{}:{}:{}", protocol, network, self.mime(mime), info)
+            }
+            _ => protocol_info.to_string(),
+        }
+    }
+
+    /// The DIDL-Lite object as this client should see it.
+    pub fn apply(&self, list_item: &ListItem) -> ListItem {
+        let mut list_item = list_item.clone();
+        if let ListItem::Item(item) = &mut list_item {
+            item.res
+                .retain(|x| transcode_name(&x.content).is_none_or(|x| self.allows_transcoder(&x)));
+            for res in &mut item.res {
+                res.protocol_info = self.protocol_info(&res.protocol_info);
+            }
+            if !self.caption_info {
+                item.caption_info.clear();
+            }
+        }
+        list_item
+    }
+}
+
+struct Matcher {
+    user_agent: Option<Regex>,
+    client_info: Option<Regex>,
+    subnets: Vec<IpNetwork>,
+}
+
+fn compile(profile: &ClientProfile, pattern: &Option<String>) -> Option<Regex> {
+    let pattern = pattern.as_ref()?;
+    match RegexBuilder::new(pattern).case_insensitive(true).build() {
+        Ok(x) => Some(x),
+        Err(e) => {
+            log::warn!(
+                "Ignoring invalid pattern in profile {}: {}",
+                profile.name,
+                e
+            );
+            None
+        }
+    }
+}
+
+impl Matcher {
+    fn new(profile: &ClientProfile) -> Self {
+        Matcher {
+            user_agent: compile(profile, &profile.user_agent),
+            client_info: compile(profile, &profile.client_info),
+            subnets: parse_subnets(&profile.subnets),
+        }
+    }
+
+    fn matches(&self, user_agent: &str, client_info: &str, ip: Option<IpAddr>) -> bool {
+        self.user_agent
+            .as_ref()
+            .is_some_and(|x| x.is_match(user_agent))
+            || self
+                .client_info
+                .as_ref()
+                .is_some_and(|x| x.is_match(client_info))
+            || ip.is_some_and(|ip| self.subnets.iter().any(|x| x.contains(ip)))
+    }
+}
+
+/// Known renderers, tried in order; the first matching profile wins.
+pub struct ClientProfiles {
+    profiles: Vec<(Matcher, Arc<ClientProfile>)>,
+    default: Arc<ClientProfile>,
+}
+
+fn read_profiles(dir: &Path) -> Vec<ClientProfile> {
+    let mut paths: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|x| x.ok())
+            .map(|x| x.path())
+            .filter(|x| x.extension().is_some_and(|x| x == "json"))
+            .collect(),
+        Err(e) => {
+            log::warn!("Cannot read client profiles from {}: {}", dir.display(), e);
+            return vec![];
+        }
+    };
+    paths.sort();
+    paths
+        .iter()
+        .filter_map(|path| {
+            let profile = fs::read_to_string(path)
+                .map_err(|e| e.to_string())
+                .and_then(|x| serde_json::from_str(&x).map_err(|e| e.to_string()));
+            match profile {
+                Ok(x) => Some(x),
+                Err(e) => {
+                    log::error!("Failed to load client profile {}: {}", path.display(), e);
+                    None
+                }
+            }
+        })
+        .collect()
+}
+
+impl ClientProfiles {
+    /// Loads the profiles in `dir`, if any, followed by the built-in ones.
+    pub fn load(dir: Option<&Path>) -> Self {
+        let mut profiles = dir.map(read_profiles).unwrap_or_default();
+        let builtin: Vec<ClientProfile> =
+            serde_json::from_str(BUILTIN_PROFILES).expect("invalid built-in client profiles");
+        profiles.extend(builtin);
+        ClientProfiles {
+            profiles: profiles
+                .into_iter()
+                .map(|x| (Matcher::new(&x), Arc::new(x)))
+                .collect(),
+            default: Arc::new(ClientProfile::default()),
+        }
+    }
+
+    pub fn find(
+        &self,
+        user_agent: &str,
+        client_info: &str,
+        ip: Option<IpAddr>,
+    ) -> Arc<ClientProfile> {
+        self.profiles
+            .iter()
+            .find(|(matcher, _)| matcher.matches(user_agent, client_info, ip))
+            .map_or_else(|| self.default.clone(), |(_, x)| x.clone())
+    }
+
+    pub fn for_request(&self, req: &HttpRequest) -> Arc<ClientProfile> {
+        let header = |name| {
+            req.headers()
+                .get(name)
+                .and_then(|x| x.to_str().ok())
+                .unwrap_or_default()
+        };
+        let profile = self.find(
+            header(USER_AGENT.as_str()),
+            header(X_AV_CLIENT_INFO),
+            req.peer_addr().map(|x| x.ip()),
+        );
+        log::debug!("Using client profile {}", profile.name);
+        profile
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{CaptionInfo, Item, Res};
+
+    fn film() -> ListItem {
+        let res = |protocol_info: &str, content: &str| Res {
+            protocol_info: protocol_info.to_string(),
+            duration: None,
+            content: content.to_string(),
+        };
+        ListItem::Item(Item {
+            id: 1000,
+            parent_id: 1,
+            title: "Film".to_string(),
+            class: "object.item.videoItem".to_string(),
+            res: vec![
+                res(
+                    "http-get:*:video/x-matroska:*",
+                    "http://host/agni/files/Film.mkv",
+                ),
+                res(
+                    "http-get:*:video/mp4:*",
+                    "http://host/agni/files/Film.mkv?transcode=mp4",
+                ),
+                res(
+                    "http-get:*:video/webm:*",
+                    "http://host/agni/files/Film.mkv?transcode=webm",
+                ),
+            ],
+            caption_info: vec![CaptionInfo {
+                caption_type: "srt".to_string(),
+                content: "http://host/agni/subtitles/Film.srt".to_string(),
+            }],
+            album_art: None,
+            last_playback_position: None,
+            playback_count: None,
+            dcm_info: None,
+            series_title: None,
+            episode_season: None,
+            episode_number: None,
+            description: None,
+            date: None,
+            genres: vec![],
+        })
+    }
+
+    #[test]
+    fn builtin_profiles() {
+        let profiles = ClientProfiles::load(None);
+        let find =
+            |user_agent, client_info| profiles.find(user_agent, client_info, None).name.clone();
+        assert_eq!(
+            find("SEC_HHP_[TV] Samsung Q7/1.0 DLNADOC/1.50", ""),
+            "Samsung"
+        );
+        assert_eq!(find("Linux/4.4 UPnP/1.0 webOS/1.0", ""), "LG");
+        assert_eq!(find("", "PLAYSTATION 4"), "Sony");
+        assert_eq!(find("vlc/3.0.16 libvlc", ""), "VLC");
+        assert_eq!(find("Mystery/1.0", ""), "Generic");
+    }
+
+    #[test]
+    fn profiles_from_the_directory_come_first() {
+        let dir = std::env::temp_dir().join(format!("agni-clients-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("1-living-room.json"),
+            r#"{"name": "Living room", "subnets": ["192.168.1.20/32"]}"#,
+        )
+        .unwrap();
+        fs::write(dir.join("2-broken.json"), "{").unwrap();
+        fs::write(dir.join("notes.txt"), "not a profile").unwrap();
+
+        let profiles = ClientProfiles::load(Some(&dir));
+        let ip = |x: &str| Some(x.parse().unwrap());
+        let find = |ip| profiles.find("Samsung", "", ip).name.clone();
+        assert_eq!(find(ip("192.168.1.20")), "Living room");
+        assert_eq!(find(ip("192.168.1.21")), "Samsung");
+        assert_eq!(find(None), "Samsung");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn protocol_info_uses_client_mime_types() {
+        let profiles = ClientProfiles::load(None);
+        let lg = profiles.find("webOS", "", None);
+        assert_eq!(
+            lg.protocol_info("http-get:*:video/x-matroska:DLNA.ORG_OP=01"),
+            "http-get:*:video/x-mkv:DLNA.ORG_OP=01"
+        );
+        assert_eq!(
+            lg.protocol_info("http-get:*:video/mp4:*"),
+            "http-get:*:video/mp4:*"
+        );
+        assert_eq!(lg.protocol_info("not protocol info"), "not protocol info");
+    }
+
+    #[test]
+    fn apply_limits_transcoders_and_captions() {
+        let profile = ClientProfile {
+            transcoding: Some(vec!["mp4".to_string()]),
+            caption_info: false,
+            mime_types: [("video/x-matroska".to_string(), "video/x-mkv".to_string())]
+                .iter()
+                .cloned()
+                .collect(),
+            ..ClientProfile::default()
+        };
+        let item = match profile.apply(&film()) {
+            ListItem::Item(x) => x,
+            _ => unreachable!(),
+        };
+        let res: Vec<_> = item.res.iter().map(|x| x.protocol_info.as_str()).collect();
+        assert_eq!(res, ["http-get:*:video/x-mkv:*", "http-get:*:video/mp4:*"]);
+        assert!(item.caption_info.is_empty());
+
+        assert_eq!(ClientProfile::default().apply(&film()), film());
+    }
+}
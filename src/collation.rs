@@ -0,0 +1,218 @@
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::iter::Peekable;
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+
+/// Leading words skipped with `ignore_articles`, each with its trailing space.
+const ARTICLES: &[&str] = &["the ", "a ", "an "];
+
+/// How titles under a shared folder are put in order.
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(default)]
+pub struct Collation {
+    /// Sort "The Matrix" under M.
+    pub ignore_articles: bool,
+    /// Sort "Émile" with "Emile".
+    pub ignore_accents: bool,
+}
+
+impl Collation {
+    fn strip_article<'a>(&self, title: &'a str) -> &'a str {
+        if !self.ignore_articles {
+            return title;
+        }
+        ARTICLES
+            .iter()
+            .find(|x| {
+                title.len() > x.len()
+                    && title
+                        .get(..x.len())
+                        .is_some_and(|prefix| prefix.eq_ignore_ascii_case(x))
+            })
+            .map_or(title, |x| &title[x.len()..])
+    }
+
+    /// `title` decomposed and lower-cased, a character at a time.
+    fn fold<'a>(&self, title: &'a str) -> Peekable<impl Iterator<Item = char> + 'a> {
+        let ignore_accents = self.ignore_accents;
+        self.strip_article(title)
+            .nfd()
+            .filter(move |&x| !(ignore_accents && is_combining_mark(x)))
+            .flat_map(char::to_lowercase)
+            .peekable()
+    }
+}
+
+/// Compares the runs of ASCII digits at the front of `a` and `b` by value, consuming
+/// them. Leading zeros are skipped here and only break ties in the end.
+fn compare_numbers(
+    a: &mut Peekable<impl Iterator<Item = char>>,
+    b: &mut Peekable<impl Iterator<Item = char>>,
+) -> Ordering {
+    while a.next_if_eq(&'0').is_some() {}
+    while b.next_if_eq(&'0').is_some() {}
+    // With as many digits on both sides, the first digit that differs decides.
+    let mut ordering = Ordering::Equal;
+    loop {
+        match (
+            a.next_if(|x| x.is_ascii_digit()),
+            b.next_if(|x| x.is_ascii_digit()),
+        ) {
+            (Some(x), Some(y)) => ordering = ordering.then(x.cmp(&y)),
+            (Some(_), None) => return Ordering::Greater,
+            (None, Some(_)) => return Ordering::Less,
+            (None, None) => return ordering,
+        }
+    }
+}
+
+/// Orders titles the way people expect: case-insensitively, and with numbers by value
+/// so "Episode 9" comes before "Episode 10". Titles that only differ in ways
+/// `collation` ignores fall back to plain string order, so only equal strings compare
+/// equal.
+pub fn natural_order_strings(a: &str, b: &str, collation: Collation) -> Ordering {
+    let (mut x, mut y) = (collation.fold(a), collation.fold(b));
+    loop {
+        let ordering = match (x.peek(), y.peek()) {
+            (None, None) => break,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(p), Some(q)) if p.is_ascii_digit() && q.is_ascii_digit() => {
+                compare_numbers(&mut x, &mut y)
+            }
+            (Some(p), Some(q)) => {
+                let ordering = p.cmp(q);
+                x.next();
+                y.next();
+                ordering
+            }
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.cmp(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    /// Titles mixing case, accents precomposed and not, digit runs with leading zeros
+    /// and punctuation that sorts on either side of the digits.
+    const TITLE: &str = "[aAbBzZéÉe\u{301}ßΩω 0-9._~-]{0,12}";
+
+    fn collation() -> impl Strategy<Value = Collation> {
+        (any::<bool>(), any::<bool>()).prop_map(|(ignore_articles, ignore_accents)| Collation {
+            ignore_articles,
+            ignore_accents,
+        })
+    }
+
+    fn cmp(a: &str, b: &str) -> Ordering {
+        natural_order_strings(a, b, Collation::default())
+    }
+
+    proptest! {
+        #[test]
+        fn antisymmetric(a in TITLE, b in TITLE, collation in collation()) {
+            prop_assert_eq!(
+                natural_order_strings(&a, &b, collation),
+                natural_order_strings(&b, &a, collation).reverse()
+            );
+        }
+
+        #[test]
+        fn transitive(a in TITLE, b in TITLE, c in TITLE, collation in collation()) {
+            let mut titles = [a, b, c];
+            titles.sort_by(|x, y| natural_order_strings(x, y, collation));
+            let [a, b, c] = &titles;
+            prop_assert_ne!(natural_order_strings(a, b, collation), Ordering::Greater);
+            prop_assert_ne!(natural_order_strings(b, c, collation), Ordering::Greater);
+            prop_assert_ne!(natural_order_strings(a, c, collation), Ordering::Greater);
+        }
+
+        #[test]
+        fn equal_only_when_identical(a in TITLE, b in TITLE, collation in collation()) {
+            prop_assert_eq!(natural_order_strings(&a, &a, collation), Ordering::Equal);
+            prop_assert_eq!(
+                natural_order_strings(&a, &b, collation) == Ordering::Equal,
+                a == b
+            );
+        }
+
+        #[test]
+        fn numbers_by_value(
+            prefix in "[a-z ]{0,4}",
+            suffix in "[a-z ]{0,4}",
+            x: u64,
+            y: u64,
+            x_zeros in 0..4usize,
+            y_zeros in 0..4usize,
+        ) {
+            prop_assume!(x != y);
+            let a = format!("{}{}{}{}", prefix, "0".repeat(x_zeros), x, suffix);
+            let b = format!("{}{}{}{}", prefix, "0".repeat(y_zeros), y, suffix);
+            prop_assert_eq!(cmp(&a, &b), x.cmp(&y));
+        }
+
+        #[test]
+        fn leading_zeros_only_break_ties(x: u32, zeros in 1..4usize, suffix in "[a-z]{0,3}") {
+            let padded = format!("Episode {}{}{}", "0".repeat(zeros), x, suffix);
+            let plain = format!("Episode {}{}", x, suffix);
+            prop_assert_eq!(cmp(&padded, &plain), padded.cmp(&plain));
+            // Either way round, both sort before the next number.
+            let next = format!("Episode {}", x as u64 + 1);
+            prop_assert_eq!(cmp(&padded, &next), Ordering::Less);
+            prop_assert_eq!(cmp(&plain, &next), Ordering::Less);
+        }
+
+        #[test]
+        fn digit_runs_longer_than_any_integer(
+            a in "[1-9][0-9]{20,60}",
+            b in "[1-9][0-9]{20,60}",
+            zeros in 0..3usize,
+        ) {
+            // Without leading zeros, a longer run is a larger number; as long, the
+            // digits decide.
+            let by_value = a.len().cmp(&b.len()).then(a.cmp(&b));
+            let padded = format!("{}{}", "0".repeat(zeros), a);
+            prop_assert_eq!(cmp(&format!("{}x", a), &format!("{}y", b)), by_value.then(Ordering::Less));
+            if a != b {
+                prop_assert_eq!(cmp(&padded, &b), by_value);
+            }
+        }
+
+        #[test]
+        fn case_insensitive(a in "[a-zà-öø-þα-ωа-я ]{0,10}", b in "[a-zà-öø-þα-ωа-я ]{0,10}") {
+            prop_assume!(a != b);
+            let ordering = cmp(&a, &b);
+            prop_assert_eq!(cmp(&a.to_uppercase(), &b), ordering);
+            prop_assert_eq!(cmp(&a, &b.to_uppercase()), ordering);
+        }
+    }
+
+    #[test]
+    fn unicode_case_folding() {
+        assert_eq!(cmp("ÉCOLE", "école"), "ÉCOLE".cmp("école"));
+        assert_eq!(cmp("école", "ECOLE"), Ordering::Greater);
+        assert_eq!(cmp("ΩMEGA", "ψ"), Ordering::Greater);
+        assert_eq!(cmp("ЯБЛОКО", "яблоко 2"), Ordering::Less);
+        // Decomposed and precomposed accents fold together.
+        assert_eq!(cmp("Cafe\u{301} 2", "Café 10"), Ordering::Less);
+        let accents = Collation {
+            ignore_accents: true,
+            ..Collation::default()
+        };
+        assert_eq!(
+            natural_order_strings("Émile 10", "emile 9", accents),
+            Ordering::Greater
+        );
+        assert_eq!(
+            natural_order_strings("Émile", "Emile", accents),
+            "Émile".cmp("Emile")
+        );
+    }
+}
@@ -0,0 +1,267 @@
+use pnet::ipnetwork::IpNetwork;
+use serde::{Deserialize, Deserializer};
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::access::AccessRule;
+use crate::collation::Collation;
+use crate::transcoding::TranscodeProfile;
+
+pub const CONFIG_ENV: &str = "AGNI_CONFIG";
+pub const DEFAULT_CONFIG_PATH: &str = "agni.json";
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Config {
+    /// Where agni keeps state that must survive restarts.
+    pub data_dir: PathBuf,
+    pub interfaces: InterfaceConfig,
+    /// Extra formats offered for matching files, streamed through an external command.
+    pub transcoding: Vec<TranscodeProfile>,
+    /// Directory of `*.json` renderer profiles, tried before the built-in ones.
+    pub client_profiles: Option<PathBuf>,
+    /// Prefixed to the titles of watched items, e.g. `"✓ "`.
+    pub watched_marker: Option<String>,
+    pub library: LibraryConfig,
+    /// Internet radio stations, listed under "Radio".
+    pub radio: Vec<Station>,
+    /// Which shared folders particular clients see; the first matching rule wins.
+    pub access: Vec<AccessRule>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: PathBuf::from(".agni"),
+            interfaces: InterfaceConfig::default(),
+            transcoding: vec![],
+            client_profiles: None,
+            watched_marker: None,
+            library: LibraryConfig::default(),
+            radio: vec![],
+            access: vec![],
+        }
+    }
+}
+
+/// An internet radio station from the config.
+#[derive(Deserialize, Debug, Clone)]
+pub struct Station {
+    pub title: String,
+    pub url: String,
+}
+
+/// A folder shared at the top level of the content directory.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RootConfig {
+    pub title: String,
+    pub path: PathBuf,
+    #[serde(default)]
+    pub collation: Collation,
+    /// List all the media below the root directly, without its folders.
+    #[serde(default)]
+    pub flatten: bool,
+}
+
+/// System folders that never hold media: version control, Synology thumbnails, fsck
+/// leftovers and trash.
+const DEFAULT_EXCLUDE: &[&str] = &[".git/", "@eaDir/", "lost+found/", ".Trash/", ".Trash-*/"];
+
+/// What the library indexes and how often it rescans.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LibraryConfig {
+    pub roots: Vec<RootConfig>,
+    /// Seconds between rescans; `0` scans only at startup.
+    pub scan_interval: u64,
+    /// How many directories are read at once.
+    pub scan_threads: usize,
+    /// List `.zip` and `.cbz` archives as folders of the images they hold.
+    pub archives: bool,
+    /// Gitignore-style patterns left out of every shared folder.
+    pub exclude: Vec<String>,
+    /// Leave out files and folders whose name starts with a dot.
+    pub hide_dotfiles: bool,
+    /// Leave out folders with nothing in them.
+    pub hide_empty_folders: bool,
+    /// Leave out folders and playlists with no media anywhere below them.
+    pub prune_empty_containers: bool,
+    /// Add a container that merges the roots, folders of the same name and all.
+    pub merge_roots: bool,
+}
+
+impl Default for LibraryConfig {
+    fn default() -> Self {
+        LibraryConfig {
+            roots: vec![RootConfig {
+                title: "Documents".to_string(),
+                path: PathBuf::from("/home/nithin/Server"),
+                collation: Collation::default(),
+                flatten: false,
+            }],
+            scan_interval: 3600,
+            scan_threads: 4,
+            archives: false,
+            exclude: DEFAULT_EXCLUDE.iter().map(|x| x.to_string()).collect(),
+            hide_dotfiles: false,
+            hide_empty_folders: false,
+            prune_empty_containers: false,
+            merge_roots: false,
+        }
+    }
+}
+
+impl LibraryConfig {
+    /// How titles under `path` are sorted, from the root it belongs to.
+    pub fn collation_for(&self, path: &Path) -> Collation {
+        self.roots
+            .iter()
+            .find(|x| path.starts_with(&x.path))
+            .map(|x| x.collation)
+            .unwrap_or_default()
+    }
+
+    /// Whether the root at `path` is listed flattened.
+    pub fn is_flattened(&self, path: &Path) -> bool {
+        self.roots.iter().any(|x| x.flatten && x.path == path)
+    }
+}
+
+/// Which network interfaces agni announces itself on and answers discovery from.
+///
+/// An empty `allow`/`allow_subnets` list means "everything"; `deny` rules always win.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct InterfaceConfig {
+    pub allow: Vec<String>,
+    pub deny: Vec<String>,
+    #[serde(deserialize_with = "deserialize_subnets")]
+    pub allow_subnets: Vec<IpNetwork>,
+    #[serde(deserialize_with = "deserialize_subnets")]
+    pub deny_subnets: Vec<IpNetwork>,
+    pub ipv6: bool,
+}
+
+impl Default for InterfaceConfig {
+    fn default() -> Self {
+        InterfaceConfig {
+            allow: vec![],
+            deny: vec![],
+            allow_subnets: vec![],
+            deny_subnets: vec![],
+            ipv6: true,
+        }
+    }
+}
+
+/// Parses a list of subnets, failing on any that is invalid: dropping a `deny` entry
+/// would lift the deny.
+pub fn deserialize_subnets<'de, D>(deserializer: D) -> Result<Vec<IpNetwork>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|x| {
+            x.parse::<IpNetwork>()
+                .map_err(|e| serde::de::Error::custom(format!("invalid subnet {:?}: {}", x, e)))
+        })
+        .collect()
+}
+
+pub fn parse_subnets(subnets: &[String]) -> Vec<IpNetwork> {
+    subnets
+        .iter()
+        .filter_map(|x| match x.parse::<IpNetwork>() {
+            Ok(network) => Some(network),
+            Err(e) => {
+                log::warn!("Ignoring invalid subnet {:?}: {}", x, e);
+                None
+            }
+        })
+        .collect()
+}
+
+impl InterfaceConfig {
+    pub fn is_interface_allowed(&self, name: &str) -> bool {
+        if self.deny.iter().any(|x| x == name) {
+            return false;
+        }
+        self.allow.is_empty() || self.allow.iter().any(|x| x == name)
+    }
+
+    pub fn is_address_allowed(&self, network: &IpNetwork) -> bool {
+        let ip = network.ip();
+        if ip.is_ipv6() && !self.ipv6 {
+            return false;
+        }
+        if self.deny_subnets.iter().any(|x| x.contains(ip)) {
+            return false;
+        }
+        self.allow_subnets.is_empty() || self.allow_subnets.iter().any(|x| x.contains(ip))
+    }
+}
+
+/// Loads the config from `$AGNI_CONFIG`, falling back to `agni.json` in the working
+/// directory. A missing file yields the defaults; one that cannot be read or does not
+/// parse is an error, as carrying on without its access rules would share everything.
+pub fn load_config() -> io::Result<Config> {
+    let path = env::var(CONFIG_ENV).unwrap_or_else(|_| DEFAULT_CONFIG_PATH.to_string());
+    read_config(&path)
+}
+
+fn read_config(path: &str) -> io::Result<Config> {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Failed to parse config {}: {}", path, e),
+            )
+        }),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            log::info!("No config found at {}, using defaults", path);
+            Ok(Config::default())
+        }
+        Err(e) => Err(io::Error::new(
+            e.kind(),
+            format!("Failed to read config {}: {}", path, e),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn invalid_subnets_are_rejected() {
+        let config = r#"{"interfaces": {"deny_subnets": ["172.17.0.0/16", "172.300.0.0/16"]}}"#;
+        let error = serde_json::from_str::<Config>(config).unwrap_err();
+        assert!(error.to_string().contains("172.300.0.0/16"), "{}", error);
+
+        let config = r#"{"interfaces": {"allow_subnets": ["192.168.1.0/24"]}}"#;
+        let config = serde_json::from_str::<Config>(config).unwrap();
+        let allowed = |x: &str| config.interfaces.is_address_allowed(&x.parse().unwrap());
+        assert!(allowed("192.168.1.7/24"));
+        assert!(!allowed("10.0.0.7/8"));
+    }
+
+    #[test]
+    fn only_missing_configs_fall_back_to_the_defaults() {
+        let dir = std::env::temp_dir().join(format!("agni-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let missing = dir.join("agni.json");
+        assert!(read_config(&missing.to_string_lossy()).is_ok());
+
+        // A directory is there, but cannot be read as a file.
+        let path = dir.to_string_lossy();
+        let error = read_config(&path).unwrap_err();
+        assert!(error.to_string().contains(&*path), "{}", error);
+
+        fs::write(&missing, "{").unwrap();
+        assert!(read_config(&missing.to_string_lossy()).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,126 @@
+use actix_web::dev::{BodySize, MessageBody, ResponseBody};
+use actix_web::web::Bytes;
+use actix_web::Error;
+use std::collections::BTreeMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+
+/// ConnectionID reported when PrepareForConnection is not implemented, as the
+/// ConnectionManager spec requires.
+pub const DEFAULT_CONNECTION_ID: i32 = 0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionInfo {
+    pub protocol_info: String,
+    pub peer: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct Connections {
+    last_id: i32,
+    active: BTreeMap<i32, ConnectionInfo>,
+}
+
+/// Tracks the HTTP streams currently being served, for GetCurrentConnectionIDs and
+/// GetCurrentConnectionInfo.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionTracker {
+    connections: Arc<Mutex<Connections>>,
+}
+
+impl ConnectionTracker {
+    pub fn open(&self, info: ConnectionInfo) -> ConnectionGuard {
+        let mut connections = self.connections.lock().unwrap();
+        connections.last_id = connections.last_id.checked_add(1).unwrap_or(1);
+        let id = connections.last_id;
+        connections.active.insert(id, info);
+        ConnectionGuard {
+            tracker: self.clone(),
+            id,
+        }
+    }
+
+    pub fn ids(&self) -> Vec<i32> {
+        let connections = self.connections.lock().unwrap();
+        std::iter::once(DEFAULT_CONNECTION_ID)
+            .chain(connections.active.keys().copied())
+            .collect()
+    }
+
+    pub fn get(&self, id: i32) -> Option<ConnectionInfo> {
+        self.connections.lock().unwrap().active.get(&id).cloned()
+    }
+}
+
+/// Removes its connection from the tracker when dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard {
+    tracker: ConnectionTracker,
+    id: i32,
+}
+
+impl ConnectionGuard {
+    pub fn id(&self) -> i32 {
+        self.id
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut connections = self.tracker.connections.lock().unwrap();
+        connections.active.remove(&self.id);
+    }
+}
+
+/// A response body that keeps its connection registered until it is fully sent or
+/// the client goes away.
+pub struct TrackedBody<B> {
+    body: ResponseBody<B>,
+    _guard: ConnectionGuard,
+    sent: u64,
+    on_finish: Option<Box<dyn FnOnce(u64)>>,
+}
+
+impl<B> TrackedBody<B> {
+    pub fn new(body: ResponseBody<B>, guard: ConnectionGuard) -> Self {
+        TrackedBody {
+            body,
+            _guard: guard,
+            sent: 0,
+            on_finish: None,
+        }
+    }
+
+    /// Calls `f` with the number of bytes sent once the response ends or the client
+    /// goes away.
+    pub fn on_finish(mut self, f: impl FnOnce(u64) + 'static) -> Self {
+        self.on_finish = Some(Box::new(f));
+        self
+    }
+}
+
+impl<B> Drop for TrackedBody<B> {
+    fn drop(&mut self) {
+        if let Some(f) = self.on_finish.take() {
+            f(self.sent);
+        }
+    }
+}
+
+impl<B: MessageBody + Unpin> MessageBody for TrackedBody<B> {
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Error>>> {
+        let poll = Pin::new(&mut self.body).poll_next(cx);
+        if let Poll::Ready(Some(Ok(bytes))) = &poll {
+            self.sent += bytes.len() as u64;
+        }
+        poll
+    }
+}
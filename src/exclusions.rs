@@ -0,0 +1,230 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use crate::config::LibraryConfig;
+use crate::util::fnv1a;
+
+/// Per-directory exclusion rules, in gitignore syntax.
+pub const IGNORE_FILE: &str = ".agniignore";
+
+/// The rules of an `.agniignore` file, and a hash of its contents.
+type IgnoreFile = (Arc<Gitignore>, u64);
+
+/// Decides which directory entries the scanner leaves out of the index: those matched
+/// by an `.agniignore` in their directory or above, by the global `exclude` patterns,
+/// or, with `hide_dotfiles`, named with a leading dot.
+pub struct Exclusions {
+    /// The global patterns, read relative to each shared folder.
+    global: Vec<(PathBuf, Gitignore)>,
+    hide_dotfiles: bool,
+    /// `.agniignore` files read so far, by directory; `None` where there is none.
+    ignore_files: Mutex<HashMap<PathBuf, Option<IgnoreFile>>>,
+    /// The hashes of the `.agniignore` files the previous scan read.
+    previous: Mutex<HashMap<PathBuf, Option<u64>>>,
+}
+
+impl Exclusions {
+    pub fn new(config: &LibraryConfig) -> Self {
+        let global = config
+            .roots
+            .iter()
+            .map(|root| {
+                let mut builder = GitignoreBuilder::new(&root.path);
+                for pattern in &config.exclude {
+                    if let Err(e) = builder.add_line(None, pattern) {
+                        log::warn!("Ignoring exclude pattern {:?}: {}", pattern, e);
+                    }
+                }
+                let global = builder.build().unwrap_or_else(|e| {
+                    log::warn!("Cannot use the exclude patterns: {}", e);
+                    Gitignore::empty()
+                });
+                (root.path.clone(), global)
+            })
+            .collect();
+        Exclusions {
+            global,
+            hide_dotfiles: config.hide_dotfiles,
+            ignore_files: Mutex::default(),
+            previous: Mutex::default(),
+        }
+    }
+
+    /// Forgets the `.agniignore` files read, so a rescan picks up edits, keeping what
+    /// they held for [`Exclusions::changed`].
+    pub fn clear(&self) {
+        let read = std::mem::take(&mut *self.ignore_files.lock().unwrap());
+        *self.previous.lock().unwrap() = read
+            .into_iter()
+            .map(|(dir, x)| (dir, x.map(|(_, hash)| hash)))
+            .collect();
+    }
+
+    fn ignore_file(&self, dir: &Path) -> Option<IgnoreFile> {
+        if let Some(x) = self.ignore_files.lock().unwrap().get(dir) {
+            return x.clone();
+        }
+        let path = dir.join(IGNORE_FILE);
+        let ignore_file = match fs::read(&path) {
+            Ok(contents) => {
+                let (ignore_file, error) = Gitignore::new(&path);
+                if let Some(e) = error {
+                    log::warn!("Cannot fully read {}: {}", path.display(), e);
+                }
+                Some((Arc::new(ignore_file), fnv1a(&contents)))
+            }
+            _ => None,
+        };
+        self.ignore_files
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), ignore_file.clone());
+        ignore_file
+    }
+
+    /// Whether the `.agniignore` files that apply in `dir` changed since the previous
+    /// scan, so that what it indexed then must be looked at again.
+    pub fn changed(&self, dir: &Path) -> bool {
+        let root = match self.global.iter().find(|(x, _)| dir.starts_with(x)) {
+            Some((x, _)) => x,
+            None => return false,
+        };
+        // Every file is read, even past a change, so the next scan can compare them.
+        let mut changed = false;
+        for dir in dir.ancestors() {
+            let hash = self.ignore_file(dir).map(|(_, hash)| hash);
+            changed |= self.previous.lock().unwrap().get(dir) != Some(&hash);
+            if dir == root {
+                break;
+            }
+        }
+        changed
+    }
+
+    /// Whether the entry at `path` is left out. The `.agniignore` nearest to it decides
+    /// first, so a deeper file can bring back with `!` what one above excludes.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        let hidden = path
+            .file_name()
+            .is_some_and(|x| x.to_string_lossy().starts_with('.'));
+        if self.hide_dotfiles && hidden {
+            return true;
+        }
+        let (root, global) = match self.global.iter().find(|(x, _)| path.starts_with(x)) {
+            Some(x) => x,
+            None => return false,
+        };
+        for dir in path.ancestors().skip(1) {
+            let matched = self
+                .ignore_file(dir)
+                .map(|(x, _)| match x.matched(path, is_dir) {
+                    Match::None => None,
+                    x => Some(x.is_ignore()),
+                });
+            if let Some(Some(excluded)) = matched {
+                return excluded;
+            }
+            if dir == root {
+                break;
+            }
+        }
+        global.matched(path, is_dir).is_ignore()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RootConfig;
+
+    fn exclusions(root: &Path, exclude: Vec<String>) -> Exclusions {
+        Exclusions::new(&LibraryConfig {
+            roots: vec![RootConfig {
+                title: "Media".to_string(),
+                path: root.to_path_buf(),
+                collation: Default::default(),
+                flatten: false,
+            }],
+            exclude,
+            ..LibraryConfig::default()
+        })
+    }
+
+    fn temp_root(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("agni-{}-{}", name, std::process::id()));
+        fs::create_dir_all(dir.join("Music/Live")).unwrap();
+        dir
+    }
+
+    #[test]
+    fn default_excludes() {
+        let root = Path::new("/media");
+        let exclusions = exclusions(root, LibraryConfig::default().exclude);
+        let excluded = |path: &str, is_dir| exclusions.is_excluded(&root.join(path), is_dir);
+        assert!(excluded("Music/.git", true));
+        assert!(excluded("@eaDir", true));
+        assert!(excluded("Photos/@eaDir", true));
+        assert!(excluded("lost+found", true));
+        assert!(excluded(".Trash-1000", true));
+        assert!(!excluded("Music/a.mp3", false));
+        assert!(!excluded(".hidden.mp3", false));
+        // The defaults name folders only.
+        assert!(!excluded("@eaDir", false));
+        assert!(!exclusions.is_excluded(Path::new("/elsewhere/.git"), true));
+    }
+
+    #[test]
+    fn directory_only_patterns() {
+        let root = temp_root("exclude-dirs");
+        let exclusions = exclusions(&root, vec!["Live/".to_string(), "*.ogg".to_string()]);
+        assert!(exclusions.is_excluded(&root.join("Music/Live"), true));
+        assert!(!exclusions.is_excluded(&root.join("Music/Live"), false));
+        assert!(exclusions.is_excluded(&root.join("Music/a.ogg"), false));
+        assert!(!exclusions.is_excluded(&root.join("Music/a.mp3"), false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn nearest_ignore_file_decides() {
+        let root = temp_root("exclude-nested");
+        fs::write(root.join(IGNORE_FILE), "*.flac\nLive/\n").unwrap();
+        fs::write(root.join("Music/Live").join(IGNORE_FILE), "!keep.flac\n").unwrap();
+        let exclusions = exclusions(&root, vec!["*.wav".to_string()]);
+        let excluded = |path: &str, is_dir| exclusions.is_excluded(&root.join(path), is_dir);
+        assert!(excluded("a.flac", false));
+        assert!(excluded("Music/b.flac", false));
+        assert!(excluded("Music/Live", true));
+        assert!(excluded("Music/Live/c.flac", false));
+        assert!(!excluded("Music/Live/keep.flac", false));
+        assert!(excluded("Music/Live/d.wav", false));
+        assert!(!excluded("Music/e.mp3", false));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn changed_ignore_files() {
+        let root = temp_root("exclude-changed");
+        let live = root.join("Music/Live");
+        let exclusions = exclusions(&root, vec![]);
+        // Nothing was read before the first scan.
+        assert!(exclusions.changed(&live));
+        exclusions.clear();
+        assert!(!exclusions.changed(&live));
+
+        fs::write(root.join("Music").join(IGNORE_FILE), "*.flac\n").unwrap();
+        exclusions.clear();
+        assert!(exclusions.changed(&live));
+        assert!(!exclusions.changed(&root));
+        exclusions.clear();
+        assert!(!exclusions.changed(&live));
+
+        fs::write(root.join("Music").join(IGNORE_FILE), "*.wav\n").unwrap();
+        exclusions.clear();
+        assert!(exclusions.changed(&live));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
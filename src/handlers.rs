@@ -1,64 +1,165 @@
+use crate::access::Access;
+use crate::archives::{member_stream, open_member, split_member};
+use crate::bookmarks::{media_duration, BookmarkStore};
+use crate::clients::ClientProfile;
+use crate::collation::Collation;
+use crate::config::LibraryConfig;
+use crate::connections::{ConnectionInfo, TrackedBody, DEFAULT_CONNECTION_ID};
+use crate::library::{Library, Object, STORAGE_FOLDER};
+use crate::media_types::{media_type_for, protocol_info, MUSIC_TRACK, VIDEO_ITEM};
+use crate::merged;
+use crate::playlists::{self, PLAYLIST_CONTAINER};
+use crate::scanner::{ScanStatus, SCAN_ERRORS_LIMIT};
+use crate::search::{Criteria, INVALID_SEARCH_CRITERIA, SEARCH_CAPABILITIES};
+use crate::soap::{
+    action_response, fault, get_action, get_argument, ACTION_FAILED, INVALID_ACTION, INVALID_ARGS,
+};
+use crate::ssdp::config_id;
+use crate::stacks::concatenated_stream;
+use crate::streams::{self, Opened, StreamTitles, RADIO_ID};
+use crate::subtitles::{find_subtitles_on_disk, subtitle_type_for};
+use crate::thumbnails::get_thumbnail;
+use crate::transcoding::stdout_stream;
+use crate::tv::{self, TvNode};
 use crate::types::{
-    AppState, Body, BrowseResponse, DidlLite, Envelope, ListItemWrapper, CONTENT_DIRECTORY_XMLNS,
-    ENVELOPE_ENCODING_STYLE, ENVELOPE_XMLNS, ENVELOPE_XMLNS_S, XMLNS_DC, XMLNS_DIDL, XMLNS_UPNP,
+    AppState, Container, DidlLite, Flag, ListItem, ListItemWrapper, CONNECTION_MANAGER_XMLNS,
+    CONTENT_DIRECTORY_XMLNS, XMLNS_DC, XMLNS_DIDL, XMLNS_DLNA, XMLNS_SEC, XMLNS_UPNP,
+};
+use crate::util::{
+    decode_path, file_size, file_url, list_item, sort_objects, source_protocol_info, title_for,
+    FILES_PATH, STREAMS_PATH, THUMBNAILS_PATH,
 };
-use crate::util::read_directory;
+use crate::views::{view_objects, Playback};
 use actix_files::NamedFile;
+use actix_web::dev::{Body as ResponseBodyKind, ResponseBody};
+use actix_web::error::BlockingError;
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ExtendedValue, HeaderName,
+    HeaderValue, ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, RANGE,
+};
+use actix_web::http::Method;
 use actix_web::{
     get, post,
     web::{self, Bytes},
     HttpRequest, HttpResponse, Responder, Result as ActixResult,
 };
 use log;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 const ROOT_XML: &str = include_str!("root.xml");
 const CONTENT_DESC_XML: &str = include_str!("content_desc.xml");
 const CONNECTION_DESC_XML: &str = include_str!("connection_desc.xml");
-const SOAP_ACTION: &str = "Soapaction";
+/// Samsung renderers ask for a video's subtitle URL with this request header...
+const GET_CAPTION_INFO_SEC: &str = "getcaptioninfo.sec";
+/// ...and expect it back in this response header.
+const CAPTION_INFO_SEC: &str = "captioninfo.sec";
+/// ContentDirectory error for an unknown ObjectID.
+const NO_SUCH_OBJECT: u16 = 701;
+/// ContentDirectory error for a Search in an unknown container.
+const NO_SUCH_CONTAINER: u16 = 710;
+pub const SORT_CAPABILITIES: &str = "dc:title,dc:date,upnp:class";
+/// ConnectionManager error for an unknown ConnectionID.
+const INVALID_CONNECTION_REFERENCE: u16 = 706;
+
+/// CONFIGID of the description documents served below.
+pub fn description_config_id() -> u32 {
+    config_id(&[ROOT_XML, CONTENT_DESC_XML, CONNECTION_DESC_XML])
+}
+
+/// DIDL-Lite for `list_items[starting_index..]`, at most `requested_count` of them
+/// (0 meaning all), trimmed further to the client's DIDL size limit. Returns the
+/// document and the number of objects in it.
+fn get_didl_result(
+    list_items: &[ListItemWrapper],
+    starting_index: usize,
+    requested_count: usize,
+    profile: &ClientProfile,
+    bookmarks: &BookmarkStore,
+    stream_titles: &StreamTitles,
+) -> (String, usize) {
+    use strong_xml::XmlWrite;
 
-fn get_browse_response(list_items: &Vec<ListItemWrapper>) -> String {
-    let didl_result = DidlLite {
+    let requested_count = if requested_count == 0 {
+        usize::MAX
+    } else {
+        requested_count
+    };
+    let mut didl_result = DidlLite {
         xmlns_dc: XMLNS_DC.to_string(),
         xmlns_upnp: XMLNS_UPNP.to_string(),
+        xmlns_sec: XMLNS_SEC.to_string(),
+        xmlns_dlna: XMLNS_DLNA.to_string(),
         xmlns: XMLNS_DIDL.to_string(),
-        list_items: list_items.iter().map(|x| x.list_item.clone()).collect(),
-    };
-    let response = Envelope {
-        encoding_style: ENVELOPE_ENCODING_STYLE.to_string(),
-        xmlns: ENVELOPE_XMLNS.to_string(),
-        xmlns_s: ENVELOPE_XMLNS_S.to_string(),
-        body: Body {
-            browse_response: BrowseResponse {
-                xmlns_u: CONTENT_DIRECTORY_XMLNS.to_string(),
-                number_returned: 1,
-                total_matches: 1,
-                update_id: 1,
-                result: "{didl-result}".to_string(),
-            },
-        },
+        list_items: vec![],
     };
-    use strong_xml::XmlWrite;
-
-    let didl_result = didl_result
-        .to_string()
-        .unwrap()
-        .replace('<', "&lt;")
-        .replace('>', "&gt;")
-        .replace('"', "&quot;");
+    let mut page: Vec<ListItem> = list_items
+        .iter()
+        .skip(starting_index)
+        .take(requested_count)
+        .map(|x| {
+            let mut list_item = profile.apply(&x.list_item);
+            bookmarks.apply(&mut list_item, x.file.as_deref());
+            stream_titles.apply(&mut list_item, x.id);
+            list_item
+        })
+        .collect();
+    if let Some(max_didl_size) = profile.max_didl_size {
+        let mut size = didl_result.to_string().map_or(0, |x| x.len());
+        let fitting = page
+            .iter()
+            .take_while(|x| {
+                size += x.to_string().map_or(0, |x| x.len());
+                size <= max_didl_size
+            })
+            .count();
+        // Always return something so that a paging client makes progress.
+        page.truncate(fitting.max(1));
+    }
+    let number_returned = page.len();
+    didl_result.list_items = page;
+    (didl_result.to_string().unwrap(), number_returned)
+}
 
-    response
-        .to_string()
-        .unwrap()
-        .replace("{didl-result}", &didl_result)
+/// The response to a Browse or Search that matched `list_items`.
+fn get_browse_response(
+    action: &str,
+    list_items: &[ListItemWrapper],
+    arguments: &BrowseArguments,
+    update_id: u32,
+    profile: &ClientProfile,
+    bookmarks: &BookmarkStore,
+    stream_titles: &StreamTitles,
+) -> String {
+    let (didl_result, number_returned) = get_didl_result(
+        list_items,
+        arguments.starting_index,
+        arguments.requested_count,
+        profile,
+        bookmarks,
+        stream_titles,
+    );
+    action_response(
+        CONTENT_DIRECTORY_XMLNS,
+        action,
+        &[
+            ("Result", didl_result),
+            ("NumberReturned", number_returned.to_string()),
+            ("TotalMatches", list_items.len().to_string()),
+            ("UpdateID", update_id.to_string()),
+        ],
+    )
 }
 
 #[get("/root.xml")]
 async fn root_handler(app_state: web::Data<Arc<Mutex<AppState>>>) -> impl Responder {
-    let uuid_string = app_state.lock().unwrap().uuid.clone().to_string();
+    let device = app_state.lock().unwrap().device;
     let body = ROOT_XML
         .replace("{name}", "Actix-Rednithin-Dev")
-        .replace("{uuid}", &uuid_string.clone());
+        .replace("{uuid}", &device.uuid.to_string())
+        .replace("{config_id}", &device.config_id.to_string());
     HttpResponse::Ok().content_type("text/xml").body(body)
 }
 
@@ -69,100 +170,566 @@ async fn content_desc_handler() -> impl Responder {
         .body(CONTENT_DESC_XML)
 }
 
-#[post("/content/control")]
-async fn content_handler(
-    app_state: web::Data<Arc<Mutex<AppState>>>,
-    bytes: Bytes,
-    req: HttpRequest,
-) -> HttpResponse {
-    let hostname = req.connection_info().host().to_owned();
-    let soap_action_header = req
-        .headers()
-        .get(SOAP_ACTION)
-        .unwrap()
-        .to_str()
-        .unwrap()
-        .to_string();
-    let action = match soap_action_header
-        .trim_matches('"')
-        .split("#")
-        .collect::<Vec<&str>>()
-        .get(1)
-    {
-        Some(&x) => x,
-        _ => "",
-    };
+/// Paging and ordering arguments shared by Browse and Search.
+struct BrowseArguments {
+    starting_index: usize,
+    requested_count: usize,
+    sort_criteria: String,
+}
 
-    let body_vec = bytes.to_vec();
-    let body_string = String::from_utf8_lossy(&body_vec);
+impl BrowseArguments {
+    fn parse(xml_doc: &roxmltree::Document) -> Self {
+        let index_argument = |name| {
+            get_argument(xml_doc, name)
+                .and_then(|x| x.trim().parse::<usize>().ok())
+                .unwrap_or(0)
+        };
+        BrowseArguments {
+            starting_index: index_argument("StartingIndex"),
+            requested_count: index_argument("RequestedCount"),
+            sort_criteria: get_argument(xml_doc, "SortCriteria").unwrap_or_default(),
+        }
+    }
 
-    let xml_doc = roxmltree::Document::parse(&body_string).unwrap();
-    let elem = xml_doc
-        .descendants()
-        .find(|x| x.tag_name().name() == "ObjectID")
-        .unwrap();
-    let object_id = if let Ok(x) = elem.text().unwrap().to_string().parse::<u64>() {
-        x
-    } else {
-        std::u64::MAX
-    };
+    /// The part of `list_items` that was asked for; `RequestedCount` 0 means all.
+    fn page<'a>(&self, list_items: &'a mut [ListItemWrapper]) -> &'a mut [ListItemWrapper] {
+        let start = self.starting_index.min(list_items.len());
+        let end = match self.requested_count {
+            0 => list_items.len(),
+            x => start.saturating_add(x).min(list_items.len()),
+        };
+        &mut list_items[start..end]
+    }
+}
+
+fn object_id_argument(xml_doc: &roxmltree::Document, name: &str) -> Option<u64> {
+    get_argument(xml_doc, name).and_then(|x| x.trim().parse::<u64>().ok())
+}
+
+fn library_failed(e: rusqlite::Error) -> HttpResponse {
+    log::error!("Library query failed: {}", e);
+    fault(ACTION_FAILED, "Action Failed")
+}
+
+/// Fills in `childCount` for the indexed folders and playlists among `list_items`, and
+/// for the merged folders over `roots`.
+fn count_children(
+    library: &Library,
+    access: &Access,
+    roots: &[(u64, PathBuf)],
+    list_items: &mut [ListItemWrapper],
+) -> rusqlite::Result<()> {
+    for wrapper in list_items {
+        let container = match &mut wrapper.list_item {
+            ListItem::Container(x) if x.child_count.is_none() => x,
+            _ => continue,
+        };
+        container.child_count = match container.class.as_str() {
+            STORAGE_FOLDER if merged::is_merged(wrapper.id) => {
+                match merged::children(library, roots, wrapper.id)? {
+                    Some(mut children) => {
+                        children.retain(|x| access.allows_object(x));
+                        retain_merged(
+                            library,
+                            roots,
+                            &mut children,
+                            Library::retain_containers_with_media,
+                        )?;
+                        Some(children.len() as u64)
+                    }
+                    None => None,
+                }
+            }
+            STORAGE_FOLDER => Some(library.media_child_count(wrapper.id)?),
+            PLAYLIST_CONTAINER => match library.get(wrapper.id)? {
+                Some(x) => Some(
+                    playlists::children(library, &x)?
+                        .iter()
+                        .filter(|x| access.allows_object(x))
+                        .count() as u64,
+                ),
+                None => None,
+            },
+            _ => None,
+        };
+    }
+    Ok(())
+}
+
+/// Applies `retain` to `objects`, keeping a merged folder over `roots` when it keeps
+/// any of the folders behind it.
+fn retain_merged(
+    library: &Library,
+    roots: &[(u64, PathBuf)],
+    objects: &mut Vec<Object>,
+    retain: fn(&Library, &mut Vec<Object>) -> rusqlite::Result<()>,
+) -> rusqlite::Result<()> {
+    let mut kept = Vec::with_capacity(objects.len());
+    for object in objects.drain(..) {
+        let mut sources = match merged::folders(library, roots, object.id)? {
+            Some(x) => x,
+            None => vec![object.clone()],
+        };
+        retain(library, &mut sources)?;
+        if !sources.is_empty() {
+            kept.push(object);
+        }
+    }
+    *objects = kept;
+    Ok(())
+}
 
-    if object_id == std::u64::MAX {
-        return HttpResponse::NotFound().body("Lol");
+/// Leaves out empty folders and containers with no media, as configured, looking
+/// through merged folders over `roots` to the folders behind them.
+fn hide_empty(
+    library: &Library,
+    config: &LibraryConfig,
+    roots: &[(u64, PathBuf)],
+    objects: &mut Vec<Object>,
+) -> rusqlite::Result<()> {
+    if config.hide_empty_folders {
+        retain_merged(library, roots, objects, Library::retain_nonempty_folders)?;
+    }
+    if config.prune_empty_containers {
+        retain_merged(
+            library,
+            roots,
+            objects,
+            Library::retain_containers_with_media,
+        )?;
     }
+    Ok(())
+}
 
-    let mut response: Option<String> = None;
+/// Whether a client with `access` sees the static container `wrapper`: a shared folder
+/// it may see, or a view over them. Radio lies outside the shared folders, so only
+/// unrestricted clients see it.
+fn shows_static(access: &Access, wrapper: &ListItemWrapper) -> bool {
+    match &wrapper.dir {
+        Some(dir) => access.allows(dir),
+        None if wrapper.id == RADIO_ID => !access.is_restricted(),
+        None => !access.is_denied(),
+    }
+}
 
-    let executed = {
+/// Objects in an indexed container, or in one of the static ones, with the update ID
+/// of the container.
+fn children(
+    app_state: &Arc<Mutex<AppState>>,
+    object_id: u64,
+    hostname: &str,
+    sort_criteria: &str,
+    access: &Access,
+) -> Result<Option<(Vec<ListItemWrapper>, u32)>, rusqlite::Error> {
+    let (static_children, static_object, library, transcoders, config, roots) = {
         let mut locked_app_state = app_state.lock().unwrap();
-        if let Some(list_items) = locked_app_state.cache.get_mut(&object_id) {
-            response = Some(get_browse_response(list_items));
-            true
-        } else {
-            false
-        }
+        (
+            locked_app_state.cache.get_mut(&object_id).cloned(),
+            locked_app_state.item_map.get(&object_id).cloned(),
+            locked_app_state.library.clone(),
+            locked_app_state.transcoders.clone(),
+            locked_app_state.config.clone(),
+            access.roots(&locked_app_state.roots),
+        )
     };
-    if !executed {
-        let list_item = {
-            let locked_app_state = app_state.lock().unwrap();
-            locked_app_state.item_map.get(&object_id).unwrap().clone()
-        };
-        let id_counter = {
-            let locked_app_state = app_state.lock().unwrap();
-            locked_app_state.id_counter
+    if static_object
+        .as_ref()
+        .is_some_and(|x| !shows_static(access, x))
+    {
+        return Ok(None);
+    }
+    if let Some(mut list_items) = static_children {
+        list_items.retain(|x| shows_static(access, x));
+        return Ok(Some((list_items, library.system_update_id()?)));
+    }
+    let view = static_object.and_then(|x| x.view);
+    if let Some(node) = TvNode::from_id(object_id) {
+        let objects = match tv::children(&library, node, access)? {
+            Some(x) => x,
+            None => return Ok(None),
         };
-        let x = read_directory(
-            hostname,
-            list_item.dir.clone().unwrap(),
-            object_id,
-            id_counter,
+        let list_items = objects
+            .iter()
+            .map(|x| list_item(hostname, x, object_id, &transcoders))
+            .collect();
+        return Ok(Some((list_items, library.system_update_id()?)));
+    }
+    // Views, playlists and stations keep their own order unless the client asks for
+    // another.
+    let (mut objects, update_id, collation, ordered) = match view {
+        Some(view) => {
+            let playback = Playback::for_view(view, &app_state.lock().unwrap().bookmarks);
+            let objects = view_objects(view, &library, &playback, access)?;
+            (
+                objects,
+                library.system_update_id()?,
+                Collation::default(),
+                true,
+            )
+        }
+        None if object_id == RADIO_ID && access.is_restricted() => return Ok(None),
+        None if object_id == RADIO_ID => (
+            streams::stations(&config.radio),
+            library.system_update_id()?,
+            Collation::default(),
+            true,
+        ),
+        None if merged::is_merged(object_id) => {
+            match merged::children(&library, &roots, object_id)? {
+                Some(x) => (x, library.system_update_id()?, Collation::default(), false),
+                None => return Ok(None),
+            }
+        }
+        None => match library.get(object_id)? {
+            Some(x) if !access.allows(&x.path) => return Ok(None),
+            Some(root) if config.library.is_flattened(&root.path) => {
+                let mut media = library.descendants(&root.path)?;
+                media.retain(|x| x.class != STORAGE_FOLDER);
+                (
+                    media,
+                    library.system_update_id()?,
+                    config.library.collation_for(&root.path),
+                    false,
+                )
+            }
+            Some(playlist) if playlist.class == PLAYLIST_CONTAINER => (
+                playlists::children(&library, &playlist)?,
+                library.system_update_id()?,
+                config.library.collation_for(&playlist.path),
+                true,
+            ),
+            Some(container) if container.is_container() => (
+                library.children(object_id)?,
+                container.update_id,
+                config.library.collation_for(&container.path),
+                false,
+            ),
+            _ => return Ok(None),
+        },
+    };
+    objects.retain(|x| access.allows_object(x));
+    hide_empty(&library, &config.library, &roots, &mut objects)?;
+    if !ordered || !sort_criteria.is_empty() {
+        sort_objects(&mut objects, sort_criteria, collation);
+    }
+    let list_items = objects
+        .iter()
+        .map(|x| list_item(hostname, x, object_id, &transcoders))
+        .collect();
+    Ok(Some((list_items, update_id)))
+}
+
+/// The object itself, for BrowseMetadata.
+fn metadata(
+    app_state: &Arc<Mutex<AppState>>,
+    object_id: u64,
+    hostname: &str,
+    access: &Access,
+) -> Result<Option<ListItemWrapper>, rusqlite::Error> {
+    let (static_object, library, transcoders, stations, roots, root_count) = {
+        let mut locked_app_state = app_state.lock().unwrap();
+        (
+            locked_app_state.item_map.get(&object_id).cloned(),
+            locked_app_state.library.clone(),
+            locked_app_state.transcoders.clone(),
+            locked_app_state.config.radio.clone(),
+            locked_app_state.roots.clone(),
+            locked_app_state
+                .cache
+                .get_mut(&0)
+                .map_or(0, |x| x.iter().filter(|x| shows_static(access, x)).count()),
         )
-        .await;
-        let list_items = x.list_items;
+    };
+    if object_id == 0 {
+        return Ok(Some(ListItemWrapper {
+            list_item: ListItem::Container(Container {
+                id: 0,
+                parent_id: 0,
+                restricted: Flag(true),
+                searchable: Flag(true),
+                child_count: Some(root_count as u64),
+                title: "root".to_string(),
+                class: "object.container".to_string(),
+                album_art: None,
+                description: None,
+                date: None,
+                genres: vec![],
+            }),
+            id: 0,
+            dir: None,
+            file: None,
+            view: None,
+        }));
+    }
+    if let Some(x) = static_object {
+        return Ok(Some(x).filter(|x| shows_static(access, x)));
+    }
+    if let Some(node) = TvNode::from_id(object_id) {
+        return Ok(tv::object(&library, node, access)?
+            .map(|x| list_item(hostname, &x, x.parent_id, &transcoders)));
+    }
+    if let Some(x) = streams::station(&stations, object_id).filter(|_| !access.is_restricted()) {
+        return Ok(Some(list_item(hostname, &x, x.parent_id, &transcoders)));
+    }
+    if let Some(x) = playlists::entry(&library, object_id)? {
+        let playlist = library.get(x.parent_id)?;
+        if !playlist.is_some_and(|playlist| access.allows(&playlist.path))
+            || !access.allows_object(&x)
         {
-            let mut locked_app_state = app_state.lock().unwrap();
-            locked_app_state.id_counter = x.id_counter;
+            return Ok(None);
         }
-        for item in &list_items {
-            let mut locked_app_state = app_state.lock().unwrap();
-            locked_app_state.item_map.insert(item.id, item.clone());
-        }
-        // let mut locked_app_state = app_state.lock().unwrap();
-        // locked_app_state.cache.insert(object_id, list_items.clone());
-        response = Some(get_browse_response(&list_items));
+        return Ok(Some(list_item(hostname, &x, x.parent_id, &transcoders)));
+    }
+    if let Some(x) = merged::object(&library, &access.roots(&roots), object_id)? {
+        return Ok(Some(list_item(hostname, &x, x.parent_id, &transcoders)));
     }
+    Ok(library
+        .get(object_id)?
+        .filter(|x| access.allows_object(x))
+        .map(|x| list_item(hostname, &x, x.parent_id, &transcoders)))
+}
+
+async fn browse(
+    app_state: &Arc<Mutex<AppState>>,
+    xml_doc: &roxmltree::Document<'_>,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let hostname = req.connection_info().host().to_owned();
+    let object_id = match object_id_argument(xml_doc, "ObjectID") {
+        Some(x) => x,
+        None => return fault(NO_SUCH_OBJECT, "No such object"),
+    };
+    log::info!("ObjectID: {}", object_id);
+    let arguments = BrowseArguments::parse(xml_doc);
+    let browse_flag = get_argument(xml_doc, "BrowseFlag").unwrap_or_default();
+    let access = app_state.lock().unwrap().access.for_request(req);
 
-    let response = response.unwrap();
-    let response = r#"<?xml version="1.0" encoding="UTF-8"?>
-    "#
-    .to_string()
-        + &response;
+    let state = app_state.clone();
+    let found = web::block(move || -> rusqlite::Result<_> {
+        let app_state = &state;
+        let found = if browse_flag == "BrowseMetadata" {
+            match metadata(app_state, object_id, &hostname, &access)? {
+                Some(x) => {
+                    let update_id = app_state.lock().unwrap().library.system_update_id()?;
+                    Some((vec![x], update_id))
+                }
+                None => None,
+            }
+        } else {
+            children(
+                app_state,
+                object_id,
+                &hostname,
+                &arguments.sort_criteria,
+                &access,
+            )?
+        };
+        let (mut list_items, update_id) = match found {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+        let (library, roots) = {
+            let locked_app_state = app_state.lock().unwrap();
+            (
+                locked_app_state.library.clone(),
+                access.roots(&locked_app_state.roots),
+            )
+        };
+        count_children(&library, &access, &roots, arguments.page(&mut list_items))?;
+        Ok(Some((list_items, update_id, arguments)))
+    })
+    .await;
+    let (list_items, update_id, arguments) = match found {
+        Ok(Some(x)) => x,
+        Ok(None) => return fault(NO_SUCH_OBJECT, "No such object"),
+        Err(BlockingError::Error(e)) => return library_failed(e),
+        Err(BlockingError::Canceled) => return fault(ACTION_FAILED, "Action Failed"),
+    };
 
+    let locked_app_state = app_state.lock().unwrap();
+    let profile = locked_app_state.client_profiles.for_request(req);
+    let response = get_browse_response(
+        "Browse",
+        &list_items,
+        &arguments,
+        update_id,
+        &profile,
+        &locked_app_state.bookmarks,
+        &locked_app_state.stream_titles,
+    );
+    log::info!("-----The Response Body-----\n{}\n", response);
+    HttpResponse::Ok().content_type("text/xml").body(response)
+}
+
+async fn search(
+    app_state: &Arc<Mutex<AppState>>,
+    xml_doc: &roxmltree::Document<'_>,
+    req: &HttpRequest,
+) -> HttpResponse {
+    let hostname = req.connection_info().host().to_owned();
+    let container_id = match object_id_argument(xml_doc, "ContainerID") {
+        Some(x) => x,
+        None => return fault(NO_SUCH_CONTAINER, "No such container"),
+    };
+    let criteria = get_argument(xml_doc, "SearchCriteria").unwrap_or_default();
+    let criteria = match Criteria::parse(&criteria) {
+        Some(x) => x,
+        None => {
+            return fault(
+                INVALID_SEARCH_CRITERIA,
+                "Unsupported or invalid search criteria",
+            )
+        }
+    };
+    let arguments = BrowseArguments::parse(xml_doc);
+
+    let (library, transcoders, library_config, roots, access) = {
+        let locked_app_state = app_state.lock().unwrap();
+        (
+            locked_app_state.library.clone(),
+            locked_app_state.transcoders.clone(),
+            locked_app_state.config.library.clone(),
+            locked_app_state.roots.clone(),
+            locked_app_state.access.for_request(req),
+        )
+    };
+    let roots = access.roots(&roots);
+    let found = web::block(move || -> rusqlite::Result<_> {
+        let mut collation = Collation::default();
+        let folders = if container_id == 0 {
+            // Everything is indexed below some root, so only restricted clients need
+            // the search narrowed to theirs.
+            access
+                .is_restricted()
+                .then(|| roots.iter().map(|(_, x)| x.clone()).collect())
+        } else if merged::is_merged(container_id) {
+            match merged::folders(&library, &roots, container_id)? {
+                Some(x) => Some(x.into_iter().map(|x| x.path).collect()),
+                None => return Ok(None),
+            }
+        } else {
+            match library.get(container_id)? {
+                Some(container) if container.is_container() && access.allows(&container.path) => {
+                    collation = library_config.collation_for(&container.path);
+                    Some(vec![container.path])
+                }
+                _ => return Ok(None),
+            }
+        };
+        let mut values = vec![];
+        let condition = criteria.to_sql(&mut values);
+        let mut objects = library.search(folders.as_deref(), &condition, values)?;
+        let update_id = library.system_update_id()?;
+        objects.retain(|x| access.allows_object(x));
+        hide_empty(&library, &library_config, &roots, &mut objects)?;
+        sort_objects(&mut objects, &arguments.sort_criteria, collation);
+        let mut list_items: Vec<ListItemWrapper> = objects
+            .iter()
+            .map(|x| list_item(&hostname, x, x.parent_id, &transcoders))
+            .collect();
+        count_children(&library, &access, &roots, arguments.page(&mut list_items))?;
+        Ok(Some((list_items, update_id, arguments)))
+    })
+    .await;
+    let (list_items, update_id, arguments) = match found {
+        Ok(Some(x)) => x,
+        Ok(None) => return fault(NO_SUCH_CONTAINER, "No such container"),
+        Err(BlockingError::Error(e)) => return library_failed(e),
+        Err(BlockingError::Canceled) => return fault(ACTION_FAILED, "Action Failed"),
+    };
+
+    let locked_app_state = app_state.lock().unwrap();
+    let profile = locked_app_state.client_profiles.for_request(req);
+    let response = get_browse_response(
+        "Search",
+        &list_items,
+        &arguments,
+        update_id,
+        &profile,
+        &locked_app_state.bookmarks,
+        &locked_app_state.stream_titles,
+    );
+    HttpResponse::Ok().content_type("text/xml").body(response)
+}
+
+#[post("/content/control")]
+async fn content_handler(
+    app_state: web::Data<Arc<Mutex<AppState>>>,
+    bytes: Bytes,
+    req: HttpRequest,
+) -> HttpResponse {
+    let action = get_action(&req);
+    let body_string = String::from_utf8_lossy(&bytes);
     log::info!("-----The Request Body-----\n{}\n", body_string);
     log::info!("Action: {}", action);
-    log::info!("ObjectID: {}", object_id);
-    log::info!("-----The Response Body-----\n{}\n", response);
 
+    let xml_doc = match roxmltree::Document::parse(&body_string) {
+        Ok(x) => x,
+        Err(_) => return fault(INVALID_ARGS, "Invalid Args"),
+    };
+    let simple_response = |arguments: &[(&str, String)]| {
+        let response = action_response(CONTENT_DIRECTORY_XMLNS, &action, arguments);
+        HttpResponse::Ok().content_type("text/xml").body(response)
+    };
+    match action.as_str() {
+        "Browse" => browse(&app_state, &xml_doc, &req).await,
+        "Search" => search(&app_state, &xml_doc, &req).await,
+        "GetSearchCapabilities" => {
+            simple_response(&[("SearchCaps", SEARCH_CAPABILITIES.to_string())])
+        }
+        "GetSortCapabilities" => simple_response(&[("SortCaps", SORT_CAPABILITIES.to_string())]),
+        "GetSystemUpdateID" => match app_state.lock().unwrap().library.system_update_id() {
+            Ok(x) => simple_response(&[("Id", x.to_string())]),
+            Err(e) => library_failed(e),
+        },
+        "X_SetBookmark" => match object_id_argument(&xml_doc, "ObjectID") {
+            Some(object_id) => set_bookmark(&app_state, &xml_doc, &req, object_id).await,
+            None => fault(NO_SUCH_OBJECT, "No such object"),
+        },
+        _ => fault(INVALID_ACTION, "Invalid Action"),
+    }
+}
+
+/// Samsung's `X_SetBookmark`: remembers where playback of an object stopped.
+async fn set_bookmark(
+    app_state: &Arc<Mutex<AppState>>,
+    xml_doc: &roxmltree::Document<'_>,
+    req: &HttpRequest,
+    object_id: u64,
+) -> HttpResponse {
+    let position = match get_argument(xml_doc, "PosSecond").and_then(|x| x.trim().parse().ok()) {
+        Some(x) => x,
+        None => return fault(INVALID_ARGS, "Invalid Args"),
+    };
+    let (library, access) = {
+        let locked_app_state = app_state.lock().unwrap();
+        (
+            locked_app_state.library.clone(),
+            locked_app_state.access.for_request(req),
+        )
+    };
+    let object = web::block(move || match playlists::entry(&library, object_id)? {
+        Some(x) => Ok(Some(x)),
+        None => library.get(object_id),
+    })
+    .await;
+    let file = match object {
+        Ok(Some(x))
+            if !x.is_container() && !x.path.as_os_str().is_empty() && access.allows_object(&x) =>
+        {
+            x.path
+        }
+        Ok(_) => return fault(NO_SUCH_OBJECT, "No such object"),
+        Err(BlockingError::Error(e)) => return library_failed(e),
+        Err(BlockingError::Canceled) => return fault(ACTION_FAILED, "Action Failed"),
+    };
+    log::info!("Bookmark for {} at {}s", file.display(), position);
+    app_state
+        .lock()
+        .unwrap()
+        .bookmarks
+        .set_position(&file, position);
+    let response = action_response(CONTENT_DIRECTORY_XMLNS, "X_SetBookmark", &[]);
     HttpResponse::Ok().content_type("text/xml").body(response)
 }
 
@@ -174,25 +741,461 @@ async fn connection_desc_handler() -> impl Responder {
 }
 
 #[post("/connection/control")]
-async fn connection_handler(bytes: Bytes) -> HttpResponse {
-    println!("{}", String::from_utf8_lossy(&bytes.to_vec()));
-    let response = r#"<?xml version="1.0" encoding="UTF-8"?>
-<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" xmlns="urn:schemas-upnp-org:service-1-0" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/">
-   <s:Body>
-      <u:GetProtocolInfoResponse xmlns:u="urn:schemas-upnp-org:service:ConnectionManager:1">
-         <Source></Source>
-         <Sink></Sink>
-      </u:GetProtocolInfoResponse>
-   </s:Body>
-</s:Envelope>
-"#;
+async fn connection_handler(
+    app_state: web::Data<Arc<Mutex<AppState>>>,
+    bytes: Bytes,
+    req: HttpRequest,
+) -> HttpResponse {
+    let action = get_action(&req);
+    let body_string = String::from_utf8_lossy(&bytes);
+    log::info!("ConnectionManager action: {}", action);
+
+    let connections = app_state.lock().unwrap().connections.clone();
+    let arguments = match action.as_str() {
+        "GetProtocolInfo" => {
+            let locked_app_state = app_state.lock().unwrap();
+            let profile = locked_app_state.client_profiles.for_request(&req);
+            let source = source_protocol_info(&locked_app_state.transcoders, &profile);
+            vec![("Source", source), ("Sink", String::new())]
+        }
+        "GetCurrentConnectionIDs" => {
+            let ids: Vec<String> = connections.ids().iter().map(|x| x.to_string()).collect();
+            vec![("ConnectionIDs", ids.join(","))]
+        }
+        "GetCurrentConnectionInfo" => {
+            let connection_id = roxmltree::Document::parse(&body_string)
+                .ok()
+                .and_then(|x| get_argument(&x, "ConnectionID"))
+                .and_then(|x| x.trim().parse::<i32>().ok());
+            let info = match connection_id {
+                Some(DEFAULT_CONNECTION_ID) => Some(ConnectionInfo {
+                    protocol_info: String::new(),
+                    peer: None,
+                }),
+                Some(x) => connections.get(x),
+                None => return fault(INVALID_ARGS, "Invalid Args"),
+            };
+            let info = match info {
+                Some(x) => x,
+                None => return fault(INVALID_CONNECTION_REFERENCE, "Invalid connection reference"),
+            };
+            vec![
+                ("RcsID", "-1".to_string()),
+                ("AVTransportID", "-1".to_string()),
+                ("ProtocolInfo", info.protocol_info),
+                ("PeerConnectionManager", String::new()),
+                ("PeerConnectionID", "-1".to_string()),
+                ("Direction", "Output".to_string()),
+                ("Status", "OK".to_string()),
+            ]
+        }
+        _ => return fault(INVALID_ACTION, "Invalid Action"),
+    };
+
+    let response = action_response(CONNECTION_MANAGER_XMLNS, &action, &arguments);
     HttpResponse::Ok().content_type("text/xml").body(response)
 }
 
-#[get("/agni-files{filename:.*}")]
-async fn serve_directories(req: HttpRequest) -> ActixResult<NamedFile> {
-    let path: std::path::PathBuf = req.match_info().query("filename").parse().unwrap();
-    Ok(NamedFile::open(path)?)
+/// Whether a media request starts playback from the top, rather than probing or
+/// seeking within a file that is already playing.
+fn is_playback_start(req: &HttpRequest) -> bool {
+    if req.method() != Method::GET {
+        return false;
+    }
+    match req.headers().get(RANGE).and_then(|x| x.to_str().ok()) {
+        Some(range) => range.trim() == "bytes=0-",
+        None => true,
+    }
+}
+
+/// The bytes `start..end` asked for by a `Range: bytes=` header, or `None` for the
+/// whole of `length`. Several ranges at once are answered with the whole, too. `Err`
+/// if none of the range lies within `length`.
+fn requested_range(req: &HttpRequest, length: u64) -> Result<Option<(u64, u64)>, ()> {
+    let range = match req
+        .headers()
+        .get(RANGE)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim().strip_prefix("bytes="))
+    {
+        Some(x) if !x.contains(',') => x,
+        _ => return Ok(None),
+    };
+    let (first, last) = range.split_once('-').ok_or(())?;
+    let (first, last) = (first.trim(), last.trim());
+    let (start, end) = if first.is_empty() {
+        let suffix: u64 = last.parse().map_err(|_| ())?;
+        (length.saturating_sub(suffix), length)
+    } else {
+        let start: u64 = first.parse().map_err(|_| ())?;
+        let end = match last {
+            "" => length,
+            x => x
+                .parse::<u64>()
+                .map_err(|_| ())?
+                .saturating_add(1)
+                .min(length),
+        };
+        (start, end)
+    };
+    if start < end {
+        Ok(Some((start, end)))
+    } else {
+        Err(())
+    }
+}
+
+/// Serves `parts`, given as path and length, as one file that can be read by range.
+fn concatenated_response(req: &HttpRequest, parts: Vec<(PathBuf, u64)>) -> HttpResponse {
+    let length = parts.iter().map(|(_, x)| x).sum();
+    let (mut response, start, end) = match requested_range(req, length) {
+        Ok(None) => (HttpResponse::Ok(), 0, length),
+        Ok(Some((start, end))) => {
+            let mut response = HttpResponse::PartialContent();
+            response.header(
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end - 1, length),
+            );
+            (response, start, end)
+        }
+        Err(()) => {
+            return HttpResponse::RangeNotSatisfiable()
+                .header(CONTENT_RANGE, format!("bytes */{}", length))
+                .finish()
+        }
+    };
+    response
+        .header(ACCEPT_RANGES, "bytes")
+        .no_chunking(end - start)
+        .streaming(concatenated_stream(parts, start, end))
+}
+
+#[derive(Deserialize)]
+struct FileQuery {
+    transcode: Option<String>,
+}
+
+/// Opens `path` to be served. A file name that is not UTF-8 or holds control
+/// characters would make an invalid `Content-Disposition`, so it is cleaned up first.
+fn open_file(path: &Path) -> std::io::Result<NamedFile> {
+    let file = NamedFile::open(path)?;
+    let name = path.file_name().unwrap_or_default();
+    if name
+        .to_str()
+        .is_some_and(|x| !x.chars().any(char::is_control))
+    {
+        return Ok(file);
+    }
+    let name: String = title_for(name)
+        .chars()
+        .filter(|x| !x.is_control())
+        .collect();
+    let mut parameters = vec![DispositionParam::Filename(name.clone())];
+    if !name.is_ascii() {
+        parameters.push(DispositionParam::FilenameExt(ExtendedValue {
+            charset: Charset::Ext("UTF-8".to_string()),
+            language_tag: None,
+            value: name.into_bytes(),
+        }));
+    }
+    Ok(file.set_content_disposition(ContentDisposition {
+        disposition: DispositionType::Inline,
+        parameters,
+    }))
+}
+
+/// A member of an archive, decompressed as it is sent. It cannot be seeked, so byte
+/// ranges are not offered.
+fn member_response(archive: &Path, name: &Path) -> std::io::Result<HttpResponse> {
+    let (reader, size) = open_member(archive, name)?;
+    Ok(HttpResponse::Ok()
+        .no_chunking(size)
+        .streaming(member_stream(reader)))
+}
+
+async fn serve_directories(
+    app_state: web::Data<Arc<Mutex<AppState>>>,
+    query: web::Query<FileQuery>,
+    req: HttpRequest,
+) -> ActixResult<HttpResponse> {
+    let path = decode_path(req.uri().path().trim_start_matches(FILES_PATH));
+    let (connections, transcoder, profile, library, access) = {
+        let locked_app_state = app_state.lock().unwrap();
+        let profile = locked_app_state.client_profiles.for_request(&req);
+        let transcoder = query.transcode.as_ref().and_then(|name| {
+            locked_app_state
+                .transcoders
+                .iter()
+                .find(|x| x.name() == name && x.applies_to(&path))
+                .filter(|x| profile.allows_transcoder(x.name()))
+                .cloned()
+        });
+        (
+            locked_app_state.connections.clone(),
+            transcoder,
+            profile,
+            locked_app_state.library.clone(),
+            locked_app_state.access.for_request(&req),
+        )
+    };
+    if !access.allows_file(&path) {
+        return Ok(HttpResponse::Forbidden().body("Access denied"));
+    }
+    // A DVD or Blu-ray folder is served as its main title, the parts read one after
+    // another.
+    let parts: Option<Vec<(PathBuf, u64)>> = if path.is_dir() {
+        library
+            .get_by_path(&path)
+            .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?
+            .map(|x| x.metadata.parts)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                x.into_iter()
+                    .map(|x| {
+                        let size = file_size(&x);
+                        (x, size)
+                    })
+                    .collect()
+            })
+    } else {
+        None
+    };
+    let media_type = media_type_for(
+        parts
+            .as_ref()
+            .and_then(|x| x.first())
+            .map_or(&path, |(x, _)| x),
+    );
+    if query.transcode.is_some() && transcoder.is_none() {
+        return Ok(HttpResponse::NotFound().body("Unknown transcoding profile"));
+    }
+
+    let caption_info = if profile.caption_info && req.headers().contains_key(GET_CAPTION_INFO_SEC) {
+        let hostname = req.connection_info().host().to_owned();
+        find_subtitles_on_disk(&path)
+            .first()
+            .map(|x| file_url(&hostname, &x.path))
+    } else {
+        None
+    };
+
+    // Byte offsets only say how far playback got for the file as stored.
+    let tracks_progress = transcoder.is_none()
+        && req.method() == Method::GET
+        && media_type.is_some_and(|x| x.class == VIDEO_ITEM || x.class == MUSIC_TRACK);
+
+    let (mut response, protocol_info) = if let Some(transcoder) = transcoder {
+        if !path.is_file() {
+            return Ok(HttpResponse::NotFound().finish());
+        }
+        let stream = stdout_stream(transcoder.spawn(&path)?)?;
+        let response = HttpResponse::Ok()
+            .content_type(profile.mime(transcoder.mime()))
+            .streaming(stream);
+        (response, profile.protocol_info(&transcoder.protocol_info()))
+    } else {
+        let mime = media_type
+            .map(|x| x.mime)
+            .or_else(|| subtitle_type_for(&path).map(|(_, mime)| mime));
+        let mut response = match (&parts, split_member(&path)) {
+            (Some(parts), _) => concatenated_response(&req, parts.clone()),
+            (None, Some((archive, name))) => member_response(archive, name)?,
+            (None, None) => open_file(&path)?.into_response(&req)?,
+        };
+        let mime = mime.map(|x| profile.mime(x));
+        if let Some(value) = mime.and_then(|x| HeaderValue::from_str(x).ok()) {
+            response.headers_mut().insert(CONTENT_TYPE, value);
+        }
+        (response, mime.map(protocol_info).unwrap_or_default())
+    };
+
+    if let Some(url) = caption_info {
+        if let Ok(value) = HeaderValue::from_str(&url) {
+            response
+                .headers_mut()
+                .insert(HeaderName::from_static(CAPTION_INFO_SEC), value);
+        }
+    }
+    for (name, value) in &profile.headers {
+        if let (Ok(name), Ok(value)) = (
+            HeaderName::from_bytes(name.as_bytes()),
+            HeaderValue::from_str(value),
+        ) {
+            response.headers_mut().insert(name, value);
+        }
+    }
+    if is_playback_start(&req) && media_type.is_some() {
+        app_state.lock().unwrap().bookmarks.record_play(&path);
+    }
+    let guard = connections.open(ConnectionInfo {
+        protocol_info,
+        peer: req.peer_addr().map(|x| x.to_string()),
+    });
+    let progress = if tracks_progress {
+        let size = match &parts {
+            Some(parts) => parts.iter().map(|(_, x)| x).sum(),
+            None => file_size(&path),
+        };
+        let start = requested_range(&req, size)
+            .ok()
+            .flatten()
+            .map_or(0, |(start, _)| start);
+        Some((app_state.get_ref().clone(), library, start, size))
+    } else {
+        None
+    };
+    Ok(response.map_body(|_, body| {
+        let mut body = TrackedBody::new(body, guard);
+        if let Some((app_state, library, start, size)) = progress {
+            body = body.on_finish(move |sent| {
+                // Finding the duration may mean reading the file.
+                tokio::task::spawn_blocking(move || {
+                    let duration = media_duration(&path).or_else(|| {
+                        let indexed = library.get_by_path(&path).ok().flatten()?;
+                        indexed.metadata.duration.map(Duration::from_secs)
+                    });
+                    app_state.lock().unwrap().bookmarks.record_progress(
+                        &path,
+                        start,
+                        start + sent,
+                        size,
+                        duration,
+                    );
+                });
+            });
+        }
+        ResponseBody::Other(ResponseBodyKind::from_message(body))
+    }))
+}
+
+/// Relays the remote stream behind a station, playlist entry or `.strm`/`.url` file,
+/// without its ICY metadata. Titles it announces show up in the item's description.
+async fn serve_stream(
+    app_state: web::Data<Arc<Mutex<AppState>>>,
+    id: web::Path<u64>,
+    req: HttpRequest,
+) -> ActixResult<HttpResponse> {
+    let id = id.into_inner();
+    let (connections, library, stations, titles, access) = {
+        let locked_app_state = app_state.lock().unwrap();
+        (
+            locked_app_state.connections.clone(),
+            locked_app_state.library.clone(),
+            locked_app_state.config.radio.clone(),
+            locked_app_state.stream_titles.clone(),
+            locked_app_state.access.for_request(&req),
+        )
+    };
+    let internal_error =
+        |e: rusqlite::Error| actix_web::error::ErrorInternalServerError(e.to_string());
+    let object = streams::stream_object(&library, &stations, id).map_err(internal_error)?;
+    let (object, url) = match object.and_then(|x| Some((x.clone(), x.metadata.url?))) {
+        Some(x) => x,
+        None => return Ok(HttpResponse::NotFound().finish()),
+    };
+    // Stations go with "Radio", and the streams in a playlist with the playlist.
+    let allowed = if object.parent_id == RADIO_ID {
+        !access.is_restricted()
+    } else if object.path.as_os_str().is_empty() {
+        let playlist = library.get(object.parent_id).map_err(internal_error)?;
+        playlist.is_some_and(|x| access.allows(&x.path))
+    } else {
+        access.allows(&object.path)
+    };
+    if !allowed {
+        return Ok(HttpResponse::Forbidden().body("Access denied"));
+    }
+    let redirect = |url: &str| HttpResponse::Found().header("Location", url).finish();
+    let guessed_mime = streams::url_mime(&url).unwrap_or("application/octet-stream");
+    if req.method() == Method::HEAD {
+        return Ok(HttpResponse::Ok().content_type(guessed_mime).finish());
+    }
+    let stream = match streams::open(&url).await {
+        Ok(Opened::Stream(x)) => x,
+        Ok(Opened::Redirect(x)) => return Ok(redirect(&x)),
+        Err(e) => {
+            log::warn!("Cannot open {}: {}", url, e);
+            return Ok(HttpResponse::BadGateway().finish());
+        }
+    };
+    let mime = stream
+        .content_type
+        .clone()
+        .unwrap_or_else(|| guessed_mime.to_string());
+    let guard = connections.open(ConnectionInfo {
+        protocol_info: protocol_info(&mime),
+        peer: req.peer_addr().map(|x| x.to_string()),
+    });
+    let body = stream.into_body(move |title| {
+        if titles.set(id, title) {
+            if let Err(e) = library.touch(object.parent_id) {
+                log::warn!("Cannot announce the title of {}: {}", url, e);
+            }
+        }
+    });
+    let response = HttpResponse::Ok().content_type(mime).streaming(body);
+    Ok(response.map_body(|_, body| {
+        ResponseBody::Other(ResponseBodyKind::from_message(TrackedBody::new(
+            body, guard,
+        )))
+    }))
+}
+
+async fn serve_thumbnails(
+    app_state: web::Data<Arc<Mutex<AppState>>>,
+    req: HttpRequest,
+) -> ActixResult<NamedFile> {
+    let path = decode_path(req.uri().path().trim_start_matches(THUMBNAILS_PATH));
+    let (data_dir, access) = {
+        let locked_app_state = app_state.lock().unwrap();
+        (
+            locked_app_state.config.data_dir.clone(),
+            locked_app_state.access.for_request(&req),
+        )
+    };
+    if !access.allows_file(&path) {
+        return Err(actix_web::error::ErrorForbidden("Access denied"));
+    }
+    let thumbnail = web::block(move || get_thumbnail(&data_dir, &path))
+        .await
+        .map_err(|e| actix_web::error::ErrorNotFound(e.to_string()))?;
+    Ok(NamedFile::open(thumbnail)?)
+}
+
+#[derive(Serialize)]
+struct ScanReport {
+    #[serde(flatten)]
+    status: ScanStatus,
+    /// `(path, error)` for each file the last scan could not read.
+    failed: Vec<(String, String)>,
+}
+
+/// Progress of the library scan and the files it failed on, as JSON. Restricted
+/// clients only hear of failures in their folders.
+#[get("/agni-scan")]
+async fn scan_status(
+    app_state: web::Data<Arc<Mutex<AppState>>>,
+    req: HttpRequest,
+) -> ActixResult<HttpResponse> {
+    let (scanner, library, access) = {
+        let app_state = app_state.lock().unwrap();
+        (
+            app_state.scanner.clone(),
+            app_state.library.clone(),
+            app_state.access.for_request(&req),
+        )
+    };
+    if access.is_denied() {
+        return Ok(HttpResponse::Forbidden().body("Access denied"));
+    }
+    let mut failed = web::block(move || library.scan_errors(SCAN_ERRORS_LIMIT))
+        .await
+        .map_err(|e| actix_web::error::ErrorInternalServerError(e.to_string()))?;
+    failed.retain(|(path, _)| access.allows(Path::new(path)));
+    Ok(HttpResponse::Ok().json(ScanReport {
+        status: scanner.status(),
+        failed,
+    }))
 }
 
 pub fn config(cfg: &mut web::ServiceConfig) {
@@ -201,5 +1204,120 @@ pub fn config(cfg: &mut web::ServiceConfig) {
     cfg.service(content_handler);
     cfg.service(connection_desc_handler);
     cfg.service(connection_handler);
-    cfg.service(serve_directories);
+    // Files are routed by prefix alone, as actix cannot match a pattern against a
+    // decoded path that is not UTF-8.
+    cfg.service(web::scope(FILES_PATH).default_service(web::to(serve_directories)));
+    cfg.service(web::scope(THUMBNAILS_PATH).default_service(web::to(serve_thumbnails)));
+    cfg.service(web::resource(format!("{}/{{id}}", STREAMS_PATH)).to(serve_stream));
+    cfg.service(scan_status);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::AccessRules;
+    use crate::config::RootConfig;
+    use crate::library::{Metadata, ScannedObject};
+
+    fn scanned(path: &str) -> ScannedObject {
+        let (path, class) = match path.strip_suffix('/') {
+            Some(x) => (x, STORAGE_FOLDER),
+            None => (path, VIDEO_ITEM),
+        };
+        ScannedObject {
+            path: PathBuf::from(path),
+            title: Path::new(path)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            class: class.to_string(),
+            size: 0,
+            modified: 0,
+            metadata: Metadata::default(),
+            inode: None,
+        }
+    }
+
+    /// Adds the shared folder `root` holding `paths`, parents first, a trailing `/`
+    /// marking folders.
+    fn index(library: &Library, root: &str, paths: &[&str]) -> (u64, PathBuf) {
+        let root_id = library.add_root(Path::new(root), root).unwrap();
+        let mut folders = vec![(root_id, PathBuf::from(root))];
+        let mut i = 0;
+        while i < folders.len() {
+            let (id, folder) = folders[i].clone();
+            let entries: Vec<ScannedObject> = paths
+                .iter()
+                .map(|x| scanned(x))
+                .filter(|x| x.path.parent() == Some(&folder))
+                .collect();
+            library.sync_directory(id, &entries).unwrap();
+            for entry in entries.iter().filter(|x| x.class == STORAGE_FOLDER) {
+                let id = library.get_by_path(&entry.path).unwrap().unwrap().id;
+                folders.push((id, entry.path.clone()));
+            }
+            i += 1;
+        }
+        (root_id, PathBuf::from(root))
+    }
+
+    #[test]
+    fn merged_folders_count_every_source() {
+        let library = Library::open_in_memory();
+        // "Films" is empty in both shared folders; "Music" only in the first, whose
+        // folder stands for it.
+        let roots = vec![
+            index(&library, "/a", &["/a/Films/", "/a/Music/", "/a/Music/Old/"]),
+            index(
+                &library,
+                "/b",
+                &["/b/Films/", "/b/Films/Old/", "/b/Music/", "/b/Music/x.mkv"],
+            ),
+        ];
+        let root_configs: Vec<RootConfig> = roots
+            .iter()
+            .map(|(_, path)| RootConfig {
+                title: path.to_string_lossy().into_owned(),
+                path: path.clone(),
+                collation: Default::default(),
+                flatten: false,
+            })
+            .collect();
+        let access = AccessRules::new(&[], &root_configs).unwrap().find("", None);
+        let top = merged::children(&library, &roots, merged::MERGED_ID)
+            .unwrap()
+            .unwrap();
+        let titles = |config: LibraryConfig| {
+            let mut objects = top.clone();
+            hide_empty(&library, &config, &roots, &mut objects).unwrap();
+            objects.into_iter().map(|x| x.title).collect::<Vec<_>>()
+        };
+
+        assert_eq!(titles(LibraryConfig::default()), ["Films", "Music"]);
+        let hide_empty_folders = LibraryConfig {
+            hide_empty_folders: true,
+            ..LibraryConfig::default()
+        };
+        assert_eq!(titles(hide_empty_folders), ["Films", "Music"]);
+        let prune_empty_containers = LibraryConfig {
+            prune_empty_containers: true,
+            ..LibraryConfig::default()
+        };
+        assert_eq!(titles(prune_empty_containers), ["Music"]);
+
+        let mut list_items: Vec<ListItemWrapper> = top
+            .iter()
+            .map(|x| list_item("localhost", x, merged::MERGED_ID, &[]))
+            .collect();
+        count_children(&library, &access, &roots, &mut list_items).unwrap();
+        let counts: Vec<Option<u64>> = list_items
+            .iter()
+            .map(|x| match &x.list_item {
+                ListItem::Container(x) => x.child_count,
+                _ => None,
+            })
+            .collect();
+        assert_eq!(counts, [Some(0), Some(1)]);
+    }
 }
@@ -0,0 +1,710 @@
+use rusqlite::functions::FunctionFlags;
+use rusqlite::types::Value;
+use rusqlite::{params, Connection, OptionalExtension, Row};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::playlists::{PlaylistEntry, PLAYLIST_CONTAINER};
+use crate::tv::Episode;
+use crate::util::{decode_path, encode_path, path_bytes, path_from_bytes};
+
+pub const LIBRARY_FILE: &str = "library.db";
+/// Indexed objects are numbered from here; lower IDs belong to the static containers
+/// built by `get_cache`.
+pub const FIRST_OBJECT_ID: u64 = 1000;
+pub const STORAGE_FOLDER: &str = "object.container.storageFolder";
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS objects (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    parent_id INTEGER NOT NULL,
+    path BLOB NOT NULL UNIQUE,
+    title TEXT NOT NULL,
+    class TEXT NOT NULL,
+    size INTEGER NOT NULL DEFAULT 0,
+    modified INTEGER NOT NULL DEFAULT 0,
+    metadata TEXT NOT NULL DEFAULT '{}',
+    update_id INTEGER NOT NULL DEFAULT 0
+);
+CREATE INDEX IF NOT EXISTS objects_parent ON objects (parent_id);
+CREATE TABLE IF NOT EXISTS state (
+    key TEXT PRIMARY KEY,
+    value INTEGER NOT NULL
+);
+";
+/// Brings databases created by older versions up to date, one batch per version.
+/// Paths are stored as their raw bytes, which need not be UTF-8.
+const MIGRATIONS: &[&str] = &[
+    "
+ALTER TABLE objects ADD COLUMN device INTEGER;
+ALTER TABLE objects ADD COLUMN inode INTEGER;
+CREATE INDEX objects_inode ON objects (device, inode);
+CREATE TABLE scan_errors (
+    path TEXT PRIMARY KEY,
+    error TEXT NOT NULL
+);
+",
+    "
+UPDATE objects SET path = CAST(path AS BLOB);
+",
+];
+const SYSTEM_UPDATE_ID: &str = "system_update_id";
+const BUMP_SYSTEM_UPDATE_ID: &str = "INSERT INTO state (key, value) VALUES (?1, 1)
+    ON CONFLICT (key) DO UPDATE SET value = value + 1";
+const COLUMNS: &str = "id, parent_id, path, title, class, size, modified, metadata, update_id";
+
+/// What the scanner learned about a file beyond its type, so that DIDL can be built
+/// without going back to the disk.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Metadata {
+    /// Whether a thumbnail can be made: folder art, the photo itself or embedded art.
+    pub has_art: bool,
+    /// Whether the tags carry cover art, kept so unchanged files need not be reread.
+    pub embedded_art: bool,
+    /// Sidecar subtitle files, default track first.
+    #[serde(with = "encoded_paths")]
+    pub subtitles: Vec<PathBuf>,
+    /// Show, season and episode, for videos named like episodes.
+    pub episode: Option<Episode>,
+    /// Plot, release date and genres, from a Kodi `.nfo` file.
+    pub description: Option<String>,
+    pub date: Option<String>,
+    pub genres: Vec<String>,
+    /// Files played in order: the parts of a stacked movie, the first being the
+    /// object itself, or the main title of a DVD or Blu-ray folder.
+    #[serde(with = "encoded_paths")]
+    pub parts: Vec<PathBuf>,
+    /// Playlists only: what they list.
+    pub entries: Vec<PlaylistEntry>,
+    /// A remote stream, for items that are not files.
+    pub url: Option<String>,
+    /// Length in seconds, where a playlist gives it.
+    pub duration: Option<u64>,
+}
+
+/// Keeps a path in JSON percent-encoded, as it need not be UTF-8.
+pub mod encoded_path {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&encode_path(path))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+        Ok(decode_path(&String::deserialize(deserializer)?))
+    }
+}
+
+/// Like [`encoded_path`], for a list of paths.
+mod encoded_paths {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(paths.iter().map(|x| encode_path(x)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<PathBuf>, D::Error> {
+        let paths = Vec::<String>::deserialize(deserializer)?;
+        Ok(paths.iter().map(|x| decode_path(x)).collect())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Object {
+    pub id: u64,
+    pub parent_id: u64,
+    pub path: PathBuf,
+    pub title: String,
+    pub class: String,
+    pub size: u64,
+    /// Modification time, in seconds since the Unix epoch.
+    pub modified: i64,
+    pub metadata: Metadata,
+    /// Containers only: bumped whenever their children change.
+    pub update_id: u32,
+}
+
+impl Object {
+    pub fn is_container(&self) -> bool {
+        self.class.starts_with("object.container")
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let metadata: String = row.get(7)?;
+        Ok(Object {
+            id: row.get::<_, i64>(0)? as u64,
+            parent_id: row.get::<_, i64>(1)? as u64,
+            path: path_from_bytes(row.get(2)?),
+            title: row.get(3)?,
+            class: row.get(4)?,
+            size: row.get::<_, i64>(5)? as u64,
+            modified: row.get(6)?,
+            metadata: serde_json::from_str(&metadata).unwrap_or_default(),
+            update_id: row.get(8)?,
+        })
+    }
+}
+
+/// A directory entry as found by the scanner.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScannedObject {
+    pub path: PathBuf,
+    pub title: String,
+    pub class: String,
+    pub size: u64,
+    pub modified: i64,
+    pub metadata: Metadata,
+    /// Device and inode number, to recognise the file after a move.
+    pub inode: Option<(u64, u64)>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SyncResult {
+    pub changed: bool,
+    /// Paths of children that were not among the scanned entries.
+    pub missing: Vec<PathBuf>,
+}
+
+/// SQL for whether the object in table `alias` is media: an item, or a playlist with
+/// entries.
+fn is_media(alias: &str) -> String {
+    format!(
+        "({0}.class NOT LIKE 'object.container%' OR ({0}.class = '{1}'
+            AND json_array_length({0}.metadata, '$.entries') > 0))",
+        alias, PLAYLIST_CONTAINER
+    )
+}
+
+/// `path` with a trailing `/`, as the bytes every path below it starts with.
+fn directory_prefix(path: &Path) -> Vec<u8> {
+    let mut prefix = path_bytes(path).to_vec();
+    if prefix.last() != Some(&b'/') {
+        prefix.push(b'/');
+    }
+    prefix
+}
+
+/// Bounds between which the paths below the directory at `path` sort: its prefix, and
+/// the prefix with `/` raised to `0`. The index on `path` finds these quickly.
+fn descendant_range(path: &Path) -> (Vec<u8>, Vec<u8>) {
+    let start = directory_prefix(path);
+    let mut end = start.clone();
+    *end.last_mut().unwrap() += 1;
+    (start, end)
+}
+
+/// The persistent index of everything under the shared roots, kept in SQLite in the
+/// data directory.
+pub struct Library {
+    connection: Mutex<Connection>,
+}
+
+impl Library {
+    pub fn open(data_dir: &Path) -> rusqlite::Result<Self> {
+        if let Err(e) = fs::create_dir_all(data_dir) {
+            log::warn!("Could not create {}: {}", data_dir.display(), e);
+        }
+        Self::with_connection(Connection::open(data_dir.join(LIBRARY_FILE))?)
+    }
+
+    /// An empty library that lives only as long as the value, for tests.
+    #[cfg(test)]
+    pub fn open_in_memory() -> Self {
+        Self::with_connection(Connection::open_in_memory().unwrap()).unwrap()
+    }
+
+    fn with_connection(connection: Connection) -> rusqlite::Result<Self> {
+        // SQLite's own lower() folds ASCII only.
+        connection.create_scalar_function(
+            "fold",
+            1,
+            FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+            |context| Ok(context.get::<String>(0)?.to_lowercase()),
+        )?;
+        connection.execute_batch(SCHEMA)?;
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            connection.execute_batch(migration)?;
+            connection.pragma_update(None, "user_version", i + 1)?;
+        }
+        connection.execute(
+            "INSERT INTO sqlite_sequence (name, seq)
+             SELECT 'objects', ?1 WHERE NOT EXISTS
+                (SELECT 1 FROM sqlite_sequence WHERE name = 'objects')",
+            params![FIRST_OBJECT_ID as i64 - 1],
+        )?;
+        Ok(Library {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    fn query(
+        &self,
+        filter: &str,
+        values: &[&dyn rusqlite::ToSql],
+    ) -> rusqlite::Result<Vec<Object>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement =
+            connection.prepare_cached(&format!("SELECT {} FROM objects {}", COLUMNS, filter))?;
+        let objects = statement
+            .query_map(values, Object::from_row)?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        Ok(objects)
+    }
+
+    pub fn get(&self, id: u64) -> rusqlite::Result<Option<Object>> {
+        Ok(self.query("WHERE id = ?1", &[&(id as i64)])?.pop())
+    }
+
+    pub fn get_by_path(&self, path: &Path) -> rusqlite::Result<Option<Object>> {
+        Ok(self.query("WHERE path = ?1", &[&path_bytes(path)])?.pop())
+    }
+
+    pub fn children(&self, parent_id: u64) -> rusqlite::Result<Vec<Object>> {
+        self.query("WHERE parent_id = ?1", &[&(parent_id as i64)])
+    }
+
+    /// Everything below the directory at `path`.
+    pub fn descendants(&self, path: &Path) -> rusqlite::Result<Vec<Object>> {
+        let prefix = directory_prefix(path);
+        self.query("WHERE substr(path, 1, length(?1)) = ?1", &[&prefix])
+    }
+
+    /// Objects below any of `folders`, or anywhere if `None`, that meet the SQL
+    /// `condition`, whose `?` placeholders take `values`.
+    pub fn search(
+        &self,
+        folders: Option<&[PathBuf]>,
+        condition: &str,
+        values: Vec<Value>,
+    ) -> rusqlite::Result<Vec<Object>> {
+        let mut parameters = vec![];
+        let scope = match folders {
+            None => "1".to_string(),
+            Some([]) => "0".to_string(),
+            Some(folders) => {
+                let mut ranges = vec![];
+                for folder in folders {
+                    let (start, end) = descendant_range(folder);
+                    parameters.extend([Value::Blob(start), Value::Blob(end)]);
+                    ranges.push("(path > ? AND path < ?)");
+                }
+                ranges.join(" OR ")
+            }
+        };
+        parameters.extend(values);
+        let parameters: Vec<&dyn rusqlite::ToSql> = parameters
+            .iter()
+            .map(|x| x as &dyn rusqlite::ToSql)
+            .collect();
+        self.query(&format!("WHERE ({}) AND {}", scope, condition), &parameters)
+    }
+
+    /// Whether the directory at `path` has no entries at all.
+    fn is_empty_folder(&self, path: &Path) -> rusqlite::Result<bool> {
+        let (start, end) = descendant_range(path);
+        let connection = self.connection.lock().unwrap();
+        connection.query_row(
+            "SELECT NOT EXISTS (SELECT 1 FROM objects WHERE path > ?1 AND path < ?2)",
+            params![start, end],
+            |row| row.get(0),
+        )
+    }
+
+    /// Whether media lies below the directory at `path`.
+    fn has_media(&self, path: &Path) -> rusqlite::Result<bool> {
+        let (start, end) = descendant_range(path);
+        let connection = self.connection.lock().unwrap();
+        connection.query_row(
+            &format!(
+                "SELECT EXISTS (SELECT 1 FROM objects d WHERE path > ?1 AND path < ?2 AND {})",
+                is_media("d")
+            ),
+            params![start, end],
+            |row| row.get(0),
+        )
+    }
+
+    /// How many children of container `id` are media or lead to some, as `childCount`.
+    pub fn media_child_count(&self, id: u64) -> rusqlite::Result<u64> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare_cached(&format!(
+            "SELECT COUNT(*) FROM objects c WHERE parent_id = ?1 AND ({} OR (class = ?2 AND
+                EXISTS (SELECT 1 FROM objects d WHERE d.path > CAST(c.path || '/' AS BLOB)
+                    AND d.path < CAST(c.path || '0' AS BLOB) AND {})))",
+            is_media("c"),
+            is_media("d")
+        ))?;
+        statement.query_row(params![id as i64, STORAGE_FOLDER], |row| {
+            row.get::<_, i64>(0).map(|x| x as u64)
+        })
+    }
+
+    /// Drops the folders of `objects` that have no entries.
+    pub fn retain_nonempty_folders(&self, objects: &mut Vec<Object>) -> rusqlite::Result<()> {
+        let mut kept = Vec::with_capacity(objects.len());
+        for object in objects.drain(..) {
+            if object.class != STORAGE_FOLDER || !self.is_empty_folder(&object.path)? {
+                kept.push(object);
+            }
+        }
+        *objects = kept;
+        Ok(())
+    }
+
+    /// Drops the containers of `objects` with no media below them: folders holding
+    /// nothing playable however deep, and playlists with no entries.
+    pub fn retain_containers_with_media(&self, objects: &mut Vec<Object>) -> rusqlite::Result<()> {
+        let mut kept = Vec::with_capacity(objects.len());
+        for object in objects.drain(..) {
+            let keep = match object.class.as_str() {
+                STORAGE_FOLDER => self.has_media(&object.path)?,
+                PLAYLIST_CONTAINER => !object.metadata.entries.is_empty(),
+                _ => true,
+            };
+            if keep {
+                kept.push(object);
+            }
+        }
+        *objects = kept;
+        Ok(())
+    }
+
+    pub fn all(&self) -> rusqlite::Result<Vec<Object>> {
+        self.query("", &[])
+    }
+
+    /// Files, most recently modified first.
+    pub fn recently_added(&self, limit: usize) -> rusqlite::Result<Vec<Object>> {
+        self.query(
+            "WHERE class NOT LIKE 'object.container%' ORDER BY modified DESC, path LIMIT ?1",
+            &[&(limit.min(i64::MAX as usize) as i64)],
+        )
+    }
+
+    pub fn items_of_class(&self, class: &str) -> rusqlite::Result<Vec<Object>> {
+        self.query("WHERE class = ?1 ORDER BY path", &[&class])
+    }
+
+    pub fn system_update_id(&self) -> rusqlite::Result<u32> {
+        let connection = self.connection.lock().unwrap();
+        let value: Option<u32> = connection
+            .query_row(
+                "SELECT value FROM state WHERE key = ?1",
+                params![SYSTEM_UPDATE_ID],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(value.unwrap_or(0))
+    }
+
+    /// Adds a shared root, or finds it if it is already indexed.
+    pub fn add_root(&self, path: &Path, title: &str) -> rusqlite::Result<u64> {
+        let path = path_bytes(path);
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO objects (parent_id, path, title, class) VALUES (0, ?1, ?2, ?3)
+             ON CONFLICT (path) DO UPDATE SET title = excluded.title",
+            params![path, title, STORAGE_FOLDER],
+        )?;
+        connection.query_row(
+            "SELECT id FROM objects WHERE path = ?1",
+            params![path],
+            |row| row.get::<_, i64>(0).map(|x| x as u64),
+        )
+    }
+
+    /// Makes the children of container `parent_id` match `scanned`: new entries are
+    /// added and changed ones updated in place, so they keep their IDs. An entry whose
+    /// inode belongs to an object that is gone from its old path is taken to be that
+    /// object, moved. Entries no longer found are only reported, as they may turn up
+    /// elsewhere later in the scan; see [`Library::remove`].
+    pub fn sync_directory(
+        &self,
+        parent_id: u64,
+        scanned: &[ScannedObject],
+    ) -> rusqlite::Result<SyncResult> {
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        let mut changed = false;
+        let missing;
+        {
+            let mut existing =
+                transaction.prepare_cached("SELECT path FROM objects WHERE parent_id = ?1")?;
+            let existing: Vec<PathBuf> = existing
+                .query_map(params![parent_id as i64], |row| {
+                    row.get(0).map(path_from_bytes)
+                })?
+                .collect::<rusqlite::Result<_>>()?;
+            missing = existing
+                .into_iter()
+                .filter(|x| !scanned.iter().any(|y| &y.path == x))
+                .collect();
+
+            let mut find_moved = transaction.prepare_cached(
+                "SELECT id, path, parent_id FROM objects
+                 WHERE device = ?1 AND inode = ?2 AND path != ?3",
+            )?;
+            let mut moved =
+                transaction.prepare_cached("UPDATE objects SET path = ?2 WHERE id = ?1")?;
+            let mut move_descendants = transaction.prepare_cached(
+                "UPDATE objects SET path = CAST(?2 || substr(path, length(?1) + 1) AS BLOB)
+                 WHERE substr(path, 1, length(?1)) = ?1",
+            )?;
+            let mut bump = transaction
+                .prepare_cached("UPDATE objects SET update_id = update_id + 1 WHERE id = ?1")?;
+            let mut remove_descendants = transaction
+                .prepare_cached("DELETE FROM objects WHERE substr(path, 1, length(?1)) = ?1")?;
+            let mut upsert = transaction.prepare_cached(
+                "INSERT INTO objects
+                    (parent_id, path, title, class, size, modified, metadata, device, inode)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT (path) DO UPDATE SET
+                    parent_id = excluded.parent_id, title = excluded.title,
+                    class = excluded.class, size = excluded.size,
+                    modified = excluded.modified, metadata = excluded.metadata,
+                    device = excluded.device, inode = excluded.inode
+                 WHERE parent_id IS NOT excluded.parent_id OR title IS NOT excluded.title
+                    OR class IS NOT excluded.class OR size IS NOT excluded.size
+                    OR modified IS NOT excluded.modified OR metadata IS NOT excluded.metadata
+                    OR device IS NOT excluded.device OR inode IS NOT excluded.inode",
+            )?;
+            for object in scanned {
+                let (device, inode) = match object.inode {
+                    Some((device, inode)) => (Some(device as i64), Some(inode as i64)),
+                    None => (None, None),
+                };
+                if let (Some(device), Some(inode)) = (device, inode) {
+                    let path = path_bytes(&object.path);
+                    let candidates: Vec<(i64, PathBuf, i64)> = find_moved
+                        .query_map(params![device, inode, path], |row| {
+                            Ok((row.get(0)?, path_from_bytes(row.get(1)?), row.get(2)?))
+                        })?
+                        .collect::<rusqlite::Result<_>>()?;
+                    let gone = candidates.into_iter().find(|(_, path, _)| !path.exists());
+                    if let Some((id, old_path, old_parent_id)) = gone {
+                        log::info!("{} moved to {}", old_path.display(), object.path.display());
+                        // Free the new path if an earlier scan indexed it separately.
+                        transaction
+                            .execute("DELETE FROM objects WHERE path = ?1", params![path])?;
+                        moved.execute(params![id, path])?;
+                        move_descendants.execute(params![
+                            directory_prefix(&old_path),
+                            directory_prefix(&object.path)
+                        ])?;
+                        bump.execute(params![old_parent_id])?;
+                    }
+                }
+                let metadata = serde_json::to_string(&object.metadata).unwrap_or_default();
+                let updated = upsert.execute(params![
+                    parent_id as i64,
+                    path_bytes(&object.path),
+                    object.title,
+                    object.class,
+                    object.size as i64,
+                    object.modified,
+                    metadata,
+                    device,
+                    inode,
+                ])? > 0;
+                // A folder now listed as an item, such as one found to hold a DVD,
+                // loses what was indexed below it.
+                if updated && !object.class.starts_with("object.container") {
+                    remove_descendants.execute(params![directory_prefix(&object.path)])?;
+                }
+                changed |= updated;
+            }
+            if changed {
+                bump.execute(params![parent_id as i64])?;
+                transaction.execute(BUMP_SYSTEM_UPDATE_ID, params![SYSTEM_UPDATE_ID])?;
+            }
+        }
+        transaction.commit()?;
+        Ok(SyncResult { changed, missing })
+    }
+
+    /// Sets the modification time indexed for object `id`, which the scanner compares
+    /// to tell whether a directory changed.
+    pub fn set_modified(&self, id: u64, modified: i64) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "UPDATE objects SET modified = ?2 WHERE id = ?1",
+            params![id as i64, modified],
+        )?;
+        Ok(())
+    }
+
+    /// Records that container `id` changed without a rescan, such as a stream having
+    /// started another song. Static containers only move the SystemUpdateID.
+    pub fn touch(&self, id: u64) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "UPDATE objects SET update_id = update_id + 1 WHERE id = ?1",
+            params![id as i64],
+        )?;
+        connection.execute(BUMP_SYSTEM_UPDATE_ID, params![SYSTEM_UPDATE_ID])?;
+        Ok(())
+    }
+
+    /// Drops the objects at `paths` and everything below them.
+    pub fn remove(&self, paths: &[PathBuf]) -> rusqlite::Result<()> {
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let mut connection = self.connection.lock().unwrap();
+        let transaction = connection.transaction()?;
+        {
+            let mut bump = transaction.prepare_cached(
+                "UPDATE objects SET update_id = update_id + 1
+                 WHERE id = (SELECT parent_id FROM objects WHERE path = ?1)",
+            )?;
+            let mut remove = transaction.prepare_cached(
+                "DELETE FROM objects WHERE path = ?1 OR substr(path, 1, length(?2)) = ?2",
+            )?;
+            for path in paths {
+                bump.execute(params![path_bytes(path)])?;
+                remove.execute(params![path_bytes(path), directory_prefix(path)])?;
+            }
+            transaction.execute(BUMP_SYSTEM_UPDATE_ID, params![SYSTEM_UPDATE_ID])?;
+        }
+        transaction.commit()
+    }
+
+    pub fn clear_scan_errors(&self) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute("DELETE FROM scan_errors", [])?;
+        Ok(())
+    }
+
+    pub fn record_scan_error(&self, path: &str, error: &str) -> rusqlite::Result<()> {
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT INTO scan_errors (path, error) VALUES (?1, ?2)
+             ON CONFLICT (path) DO UPDATE SET error = excluded.error",
+            params![path, error],
+        )?;
+        Ok(())
+    }
+
+    /// Files the last scan could not read, as `(path, error)`.
+    pub fn scan_errors(&self, limit: usize) -> rusqlite::Result<Vec<(String, String)>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection
+            .prepare_cached("SELECT path, error FROM scan_errors ORDER BY path LIMIT ?1")?;
+        let errors = statement
+            .query_map(params![limit as i64], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect();
+        errors
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::search::Criteria;
+
+    fn library() -> Library {
+        let library = Library::open_in_memory();
+        let root = library
+            .add_root(Path::new("/media/Music"), "Music")
+            .unwrap();
+        library
+            .add_root(Path::new("/media/Musical"), "Musical")
+            .unwrap();
+        let connection = library.connection.lock().unwrap();
+        for (path, title, class) in [
+            (
+                "/media/Music/Édith.mp3",
+                "Édith",
+                "object.item.audioItem.musicTrack",
+            ),
+            ("/media/Music/live.mkv", "Live", "object.item.videoItem"),
+            ("/media/Musical/cast.mp3", "Cast", "object.item.audioItem"),
+        ] {
+            connection
+                .execute(
+                    "INSERT INTO objects (parent_id, path, title, class) VALUES (?1, ?2, ?3, ?4)",
+                    params![root as i64, path.as_bytes(), title, class],
+                )
+                .unwrap();
+        }
+        drop(connection);
+        library
+    }
+
+    fn search(library: &Library, folders: Option<&[PathBuf]>, criteria: &str) -> Vec<String> {
+        let mut values = vec![];
+        let condition = Criteria::parse(criteria).unwrap().to_sql(&mut values);
+        let mut titles: Vec<String> = library
+            .search(folders, &condition, values)
+            .unwrap()
+            .into_iter()
+            .map(|x| x.title)
+            .collect();
+        titles.sort();
+        titles
+    }
+
+    #[test]
+    fn search_folds_case() {
+        let library = library();
+        assert_eq!(search(&library, None, r#"dc:title = "ÉDITH""#), ["Édith"]);
+        assert_eq!(
+            search(&library, None, r#"dc:title contains "dit""#),
+            ["Édith"]
+        );
+        assert_eq!(
+            search(&library, None, r#"dc:title doesNotContain "dit""#),
+            ["Cast", "Live", "Music", "Musical"]
+        );
+    }
+
+    #[test]
+    fn search_derived_classes() {
+        let library = library();
+        assert_eq!(
+            search(
+                &library,
+                None,
+                r#"upnp:class derivedfrom "object.item.audioItem""#
+            ),
+            ["Cast", "Édith"]
+        );
+        assert_eq!(
+            search(
+                &library,
+                None,
+                r#"upnp:class derivedfrom "object.item.audio""#
+            ),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn search_within_folders() {
+        let library = library();
+        let music = [PathBuf::from("/media/Music")];
+        assert_eq!(
+            search(
+                &library,
+                Some(&music),
+                r#"upnp:class derivedfrom "object.item""#
+            ),
+            ["Live", "Édith"]
+        );
+        let both = [
+            PathBuf::from("/media/Music"),
+            PathBuf::from("/media/Musical"),
+        ];
+        assert_eq!(
+            search(&library, Some(&both), "*"),
+            ["Cast", "Live", "Édith"]
+        );
+        assert_eq!(search(&library, Some(&[]), "*"), Vec::<String>::new());
+    }
+}
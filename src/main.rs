@@ -1,67 +1,155 @@
 use actix_web::{middleware::Logger, App, HttpServer};
-use pretty_env_logger;
 use std::collections::HashMap;
 use std::env;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
-use tokio;
 use uuid::Uuid;
 
+pub mod access;
+pub mod archives;
+pub mod bookmarks;
 pub mod broadcast;
+pub mod clients;
+pub mod collation;
+pub mod config;
+pub mod connections;
+pub mod exclusions;
 pub mod handlers;
+pub mod library;
+pub mod media_types;
+pub mod merged;
+pub mod nfo;
+pub mod playlists;
+pub mod scanner;
+pub mod search;
+pub mod soap;
+pub mod ssdp;
+pub mod stacks;
+pub mod streams;
+pub mod subtitles;
+pub mod thumbnails;
+pub mod transcoding;
+pub mod tv;
 pub mod types;
 pub mod util;
+pub mod views;
 
-use handlers::config;
+use access::AccessRules;
+use bookmarks::{BookmarkStore, SAVE_INTERVAL};
+use clients::ClientProfiles;
+use config::load_config;
+use connections::ConnectionTracker;
+use library::Library;
+use scanner::Scanner;
+use ssdp::{next_boot_id, Device};
+use transcoding::Transcoder;
 
 use types::AppState;
 
-use util::get_cache;
+use util::{get_cache, tcp_listener_v6};
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     env::set_var("RUST_LOG", "info");
     pretty_env_logger::init();
 
-    let uuid = Uuid::new_v4();
+    let config = load_config()?;
+    let device = Device {
+        uuid: Uuid::new_v4(),
+        boot_id: next_boot_id(&config.data_dir),
+        config_id: handlers::description_config_id(),
+    };
+
+    let access = AccessRules::new(&config.access, &config.library.roots).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Invalid access rule: {}", e),
+        )
+    })?;
+    let access = Arc::new(access);
 
-    let handle1 = tokio::spawn(async move {
-        broadcast::listen_to_discover_messages(uuid.clone()).await;
+    let interface_config = config.interfaces.clone();
+    let discovery_access = access.clone();
+    let _handle1 = tokio::spawn(async move {
+        broadcast::listen_to_discover_messages(device, interface_config, discovery_access).await;
     });
 
+    let interface_config = config.interfaces.clone();
     let handle2 = tokio::spawn(async move {
         let mut interval = tokio::time::interval(tokio::time::Duration::from_millis(2000));
         loop {
             interval.tick().await;
-            broadcast::broadcast_presence(uuid.clone(), None).await;
+            broadcast::broadcast_presence(&device, &interface_config, None).await;
         }
     });
 
-    let mut cache = get_cache();
+    let library = Arc::new(Library::open(&config.data_dir).map_err(std::io::Error::other)?);
+    let cache = get_cache(&library, &config.library, &config.radio);
     let mut item_map = HashMap::new();
-    for items in cache.get_mut(&0) {
+    for (_, items) in cache.iter() {
         for item in items {
             item_map.insert(item.id, item.clone());
         }
     }
 
-    let id_counter = (cache.get_mut(&0).unwrap().len() + 1) as u64;
+    let roots: Vec<(u64, PathBuf)> = cache
+        .iter()
+        .flat_map(|(_, items)| items)
+        .filter_map(|x| Some((x.id, x.dir.clone()?)))
+        .collect();
+    let scanner = Arc::new(Scanner::new(
+        library.clone(),
+        roots.clone(),
+        &config.library,
+    ));
+    tokio::spawn(Scanner::run(scanner.clone()));
 
     let app_state = AppState {
         cache,
-        id_counter,
         item_map,
-        uuid,
+        device,
+        connections: ConnectionTracker::default(),
+        config: config.clone(),
+        transcoders: config
+            .transcoding
+            .iter()
+            .map(|x| Arc::new(x.clone()) as Arc<dyn Transcoder>)
+            .collect(),
+        access,
+        client_profiles: Arc::new(ClientProfiles::load(config.client_profiles.as_deref())),
+        bookmarks: BookmarkStore::load(&config.data_dir, config.watched_marker.clone()),
+        library,
+        roots,
+        scanner,
+        stream_titles: Arc::default(),
     };
     let app_state = Arc::new(Mutex::new(app_state));
 
-    let handle3 = HttpServer::new(move || {
+    let bookmarks_state = app_state.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(SAVE_INTERVAL);
+        loop {
+            interval.tick().await;
+            bookmarks::write_pending(&bookmarks_state).await;
+        }
+    });
+    let shutdown_state = app_state.clone();
+
+    let mut server = HttpServer::new(move || {
         App::new()
             .wrap(Logger::default())
             .data(app_state.clone())
-            .configure(config)
+            .configure(handlers::config)
     })
-    .bind("0.0.0.0:3030")?
-    .run();
+    .bind("0.0.0.0:3030")?;
+    if config.interfaces.ipv6 {
+        // Hosts with IPv6 disabled are still served over IPv4.
+        match tcp_listener_v6(3030) {
+            Ok(listener) => server = server.listen(listener)?,
+            Err(e) => log::warn!("Could not listen on [::]:3030, serving IPv4 only: {}", e),
+        }
+    }
+    let handle3 = server.run();
 
     tokio::select! {
         // _ = handle1 => 0,
@@ -69,5 +157,8 @@ async fn main() -> std::io::Result<()> {
         _ = handle3 => 0,
     };
 
+    bookmarks::write_pending(&shutdown_state).await;
+    broadcast::broadcast_byebye(&device, &config.interfaces).await;
+
     Ok(())
 }
@@ -0,0 +1,62 @@
+use std::path::Path;
+
+pub const VIDEO_ITEM: &str = "object.item.videoItem";
+pub const MUSIC_TRACK: &str = "object.item.audioItem.musicTrack";
+pub const PHOTO: &str = "object.item.imageItem.photo";
+/// Remote streams, such as internet radio.
+pub const AUDIO_BROADCAST: &str = "object.item.audioItem.audioBroadcast";
+pub const VIDEO_BROADCAST: &str = "object.item.videoItem.videoBroadcast";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MediaType {
+    pub extension: &'static str,
+    pub mime: &'static str,
+    pub class: &'static str,
+}
+
+const fn media_type(extension: &'static str, mime: &'static str, class: &'static str) -> MediaType {
+    MediaType {
+        extension,
+        mime,
+        class,
+    }
+}
+
+/// Every file type agni serves, keyed by lower-case extension.
+pub const MEDIA_TYPES: &[MediaType] = &[
+    media_type("mkv", "video/x-matroska", VIDEO_ITEM),
+    media_type("mp4", "video/mp4", VIDEO_ITEM),
+    media_type("m4v", "video/mp4", VIDEO_ITEM),
+    media_type("avi", "video/x-msvideo", VIDEO_ITEM),
+    media_type("mov", "video/quicktime", VIDEO_ITEM),
+    media_type("webm", "video/webm", VIDEO_ITEM),
+    media_type("ts", "video/mp2t", VIDEO_ITEM),
+    media_type("m2ts", "video/mp2t", VIDEO_ITEM),
+    media_type("mpg", "video/mpeg", VIDEO_ITEM),
+    media_type("mpeg", "video/mpeg", VIDEO_ITEM),
+    media_type("wmv", "video/x-ms-wmv", VIDEO_ITEM),
+    media_type("vob", "video/mpeg", VIDEO_ITEM),
+    media_type("mp3", "audio/mpeg", MUSIC_TRACK),
+    media_type("flac", "audio/flac", MUSIC_TRACK),
+    media_type("m4a", "audio/mp4", MUSIC_TRACK),
+    media_type("aac", "audio/aac", MUSIC_TRACK),
+    media_type("ogg", "audio/ogg", MUSIC_TRACK),
+    media_type("opus", "audio/ogg", MUSIC_TRACK),
+    media_type("wav", "audio/wav", MUSIC_TRACK),
+    media_type("wma", "audio/x-ms-wma", MUSIC_TRACK),
+    media_type("jpg", "image/jpeg", PHOTO),
+    media_type("jpeg", "image/jpeg", PHOTO),
+    media_type("png", "image/png", PHOTO),
+    media_type("gif", "image/gif", PHOTO),
+    media_type("bmp", "image/bmp", PHOTO),
+    media_type("webp", "image/webp", PHOTO),
+];
+
+pub fn media_type_for<P: AsRef<Path>>(path: P) -> Option<&'static MediaType> {
+    let extension = path.as_ref().extension()?.to_str()?.to_lowercase();
+    MEDIA_TYPES.iter().find(|x| x.extension == extension)
+}
+
+pub fn protocol_info(mime: &str) -> String {
+    format!("http-get:*:{}:*", mime)
+}
@@ -0,0 +1,243 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use crate::library::{Library, Object, STORAGE_FOLDER};
+
+/// The static container that merges the shared folders, numbered after "Radio".
+pub const MERGED_ID: u64 = 7;
+pub const MERGED_TITLE: &str = "All Folders";
+/// Merged folders are numbered from here, out of reach of the index: the rest of the
+/// ID is the index ID of the folder standing for them, the one in the first shared
+/// folder that has it.
+const FIRST_MERGED_ID: u64 = 1 << 52;
+
+pub fn is_merged(id: u64) -> bool {
+    id == MERGED_ID || (FIRST_MERGED_ID..FIRST_MERGED_ID << 1).contains(&id)
+}
+
+/// `relative` under each root, for the root itself when `relative` is empty.
+fn under_roots<'a>(
+    roots: &'a [(u64, PathBuf)],
+    relative: &'a Path,
+) -> impl Iterator<Item = PathBuf> + 'a {
+    roots
+        .iter()
+        .map(move |(_, root)| match relative.as_os_str().is_empty() {
+            true => root.clone(),
+            false => root.join(relative),
+        })
+}
+
+/// Where the folder `folder` sits relative to the root it is under.
+fn relative_path(roots: &[(u64, PathBuf)], folder: &Object) -> Option<PathBuf> {
+    roots
+        .iter()
+        .find_map(|(_, root)| folder.path.strip_prefix(root).ok())
+        .map(Path::to_path_buf)
+}
+
+/// The folder standing for merged container `id`, and its path relative to the roots.
+fn standing_folder(
+    library: &Library,
+    roots: &[(u64, PathBuf)],
+    id: u64,
+) -> rusqlite::Result<Option<(Object, PathBuf)>> {
+    if id == MERGED_ID || !is_merged(id) {
+        return Ok(None);
+    }
+    let folder = match library.get(id & !FIRST_MERGED_ID)? {
+        Some(x) if x.class == STORAGE_FOLDER => x,
+        _ => return Ok(None),
+    };
+    Ok(relative_path(roots, &folder).map(|x| (folder, x)))
+}
+
+/// The folders behind merged container `id`, one from each root that has it, in the
+/// order of the roots.
+pub fn folders(
+    library: &Library,
+    roots: &[(u64, PathBuf)],
+    id: u64,
+) -> rusqlite::Result<Option<Vec<Object>>> {
+    let relative = match id {
+        MERGED_ID => PathBuf::new(),
+        _ => match standing_folder(library, roots, id)? {
+            Some((_, x)) => x,
+            None => return Ok(None),
+        },
+    };
+    let mut folders = vec![];
+    for path in under_roots(roots, &relative) {
+        if let Some(x) = library.get_by_path(&path)? {
+            if x.class == STORAGE_FOLDER {
+                folders.push(x);
+            }
+        }
+    }
+    Ok(Some(folders).filter(|x| !x.is_empty()))
+}
+
+/// What merged container `id` lists: the files of all its folders, and their
+/// subfolders with those of the same name merged in turn.
+pub fn children(
+    library: &Library,
+    roots: &[(u64, PathBuf)],
+    id: u64,
+) -> rusqlite::Result<Option<Vec<Object>>> {
+    let folders = match folders(library, roots, id)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let mut names = HashSet::new();
+    let mut children = vec![];
+    for folder in folders {
+        for child in library.children(folder.id)? {
+            if child.class != STORAGE_FOLDER {
+                children.push(child);
+            } else if names.insert(child.path.file_name().map(|x| x.to_os_string())) {
+                children.push(Object {
+                    id: FIRST_MERGED_ID | child.id,
+                    parent_id: id,
+                    ..child
+                });
+            }
+        }
+    }
+    Ok(Some(children))
+}
+
+/// The merged folder `id`, for BrowseMetadata.
+pub fn object(
+    library: &Library,
+    roots: &[(u64, PathBuf)],
+    id: u64,
+) -> rusqlite::Result<Option<Object>> {
+    let (folder, relative) = match standing_folder(library, roots, id)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let parent = relative.parent().unwrap_or(Path::new(""));
+    let mut parent_id = MERGED_ID;
+    if !parent.as_os_str().is_empty() {
+        for path in under_roots(roots, parent) {
+            if let Some(x) = library.get_by_path(&path)? {
+                parent_id = FIRST_MERGED_ID | x.id;
+                break;
+            }
+        }
+    }
+    Ok(Some(Object {
+        id,
+        parent_id,
+        ..folder
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::{Metadata, ScannedObject};
+
+    fn scanned(path: &str, class: &str) -> ScannedObject {
+        ScannedObject {
+            path: PathBuf::from(path),
+            title: Path::new(path)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            class: class.to_string(),
+            size: 0,
+            modified: 0,
+            metadata: Metadata::default(),
+            inode: None,
+        }
+    }
+
+    /// Adds the folders and files at `paths`, a trailing `/` marking folders.
+    fn index(library: &Library, root: &str, paths: &[&str]) -> u64 {
+        let root_id = library.add_root(Path::new(root), root).unwrap();
+        for path in paths {
+            let (path, class) = match path.strip_suffix('/') {
+                Some(x) => (x, STORAGE_FOLDER),
+                None => (*path, "object.item.videoItem"),
+            };
+            let parent = Path::new(path).parent().unwrap();
+            let parent_id = library.get_by_path(parent).unwrap().unwrap().id;
+            let mut siblings: Vec<ScannedObject> = library
+                .children(parent_id)
+                .unwrap()
+                .into_iter()
+                .map(|x| scanned(&x.path.to_string_lossy(), &x.class))
+                .collect();
+            siblings.push(scanned(path, class));
+            library.sync_directory(parent_id, &siblings).unwrap();
+        }
+        root_id
+    }
+
+    fn titles(objects: Vec<Object>) -> Vec<String> {
+        let mut titles: Vec<String> = objects.into_iter().map(|x| x.title).collect();
+        titles.sort();
+        titles
+    }
+
+    #[test]
+    fn folders_of_the_same_name_merge() {
+        let library = Library::open_in_memory();
+        let a = index(
+            &library,
+            "/a",
+            &[
+                "/a/Films/",
+                "/a/Films/Heat.mkv",
+                "/a/Films/Old/",
+                "/a/x.mkv",
+            ],
+        );
+        let b = index(
+            &library,
+            "/b",
+            &[
+                "/b/Films/",
+                "/b/Films/Ronin.mkv",
+                "/b/Films/Old/",
+                "/b/Music/",
+            ],
+        );
+        let roots = vec![(a, PathBuf::from("/a")), (b, PathBuf::from("/b"))];
+
+        let top = children(&library, &roots, MERGED_ID).unwrap().unwrap();
+        assert_eq!(titles(top.clone()), ["Films", "Music", "x.mkv"]);
+        let films = top.iter().find(|x| x.title == "Films").unwrap();
+        assert!(is_merged(films.id));
+        assert_eq!(films.parent_id, MERGED_ID);
+
+        let merged = children(&library, &roots, films.id).unwrap().unwrap();
+        assert_eq!(titles(merged.clone()), ["Heat.mkv", "Old", "Ronin.mkv"]);
+        assert_eq!(
+            folders(&library, &roots, films.id).unwrap().unwrap().len(),
+            2
+        );
+
+        let old = merged.iter().find(|x| x.title == "Old").unwrap();
+        let object = object(&library, &roots, old.id).unwrap().unwrap();
+        assert_eq!((object.id, object.parent_id), (old.id, films.id));
+        assert_eq!(children(&library, &roots, old.id).unwrap(), Some(vec![]));
+    }
+
+    #[test]
+    fn other_ids_are_not_merged() {
+        let library = Library::open_in_memory();
+        let a = index(&library, "/a", &["/a/x.mkv", "/a/Films/"]);
+        let roots = vec![(a, PathBuf::from("/a"))];
+        let file = library.get_by_path(Path::new("/a/x.mkv")).unwrap().unwrap();
+        assert!(!is_merged(file.id));
+        assert_eq!(
+            children(&library, &roots, FIRST_MERGED_ID | file.id).unwrap(),
+            None
+        );
+        assert_eq!(object(&library, &roots, MERGED_ID).unwrap(), None);
+        assert_eq!(children(&library, &[], MERGED_ID).unwrap(), None);
+    }
+}
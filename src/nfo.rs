@@ -0,0 +1,170 @@
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::media_types::{media_type_for, VIDEO_ITEM};
+
+/// Kodi metadata file of a show folder.
+pub const TVSHOW_NFO: &str = "tvshow.nfo";
+/// Kodi metadata file shared by a folder holding a single movie.
+pub const MOVIE_NFO: &str = "movie.nfo";
+
+/// What a Kodi `.nfo` file says about a movie, show or episode.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct Nfo {
+    pub title: Option<String>,
+    pub plot: Option<String>,
+    /// `YYYY-MM-DD`, or just the year.
+    pub date: Option<String>,
+    pub genres: Vec<String>,
+    /// For episodes: the show, season and episode number.
+    pub show_title: Option<String>,
+    pub season: Option<u32>,
+    pub episode: Option<u32>,
+}
+
+/// The `.nfo` file describing the video at `path`: `<name>.nfo` among `siblings`, or
+/// `movie.nfo` if the video is the only one in its folder.
+pub fn find_nfo(path: &Path, siblings: &[PathBuf]) -> Option<PathBuf> {
+    let own = path.with_extension("nfo");
+    if siblings.contains(&own) {
+        return Some(own);
+    }
+    let movie = path.with_file_name(MOVIE_NFO);
+    let videos = siblings
+        .iter()
+        .filter(|x| media_type_for(x).is_some_and(|x| x.class == VIDEO_ITEM))
+        .count();
+    Some(movie).filter(|x| videos == 1 && siblings.contains(x))
+}
+
+/// Parses a movie, tvshow or episodedetails `.nfo`. Files that are not XML, such as
+/// the ones holding only a scraper URL, yield `None`.
+pub fn read_nfo(path: &Path) -> Option<Nfo> {
+    let contents = fs::read_to_string(path).ok()?;
+    let document = match roxmltree::Document::parse(contents.trim_start_matches('\u{feff}')) {
+        Ok(x) => x,
+        Err(e) => {
+            log::debug!("Ignoring {}: {}", path.display(), e);
+            return None;
+        }
+    };
+    let root = document.root_element();
+    if !matches!(
+        root.tag_name().name(),
+        "movie" | "tvshow" | "episodedetails"
+    ) {
+        return None;
+    }
+    let text = |name: &str| {
+        root.children()
+            .find(|x| x.has_tag_name(name))
+            .and_then(|x| x.text())
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+    };
+    let date = ["premiered", "aired", "year"]
+        .iter()
+        .filter_map(|x| text(x))
+        .find(|x| x.len() >= 4 && x.is_char_boundary(4) && x[..4].parse::<u32>().is_ok());
+    Some(Nfo {
+        title: text("title"),
+        plot: text("plot").or_else(|| text("outline")),
+        date,
+        genres: root
+            .children()
+            .filter(|x| x.has_tag_name("genre"))
+            .filter_map(|x| x.text())
+            .flat_map(|x| x.split(" / "))
+            .map(|x| x.trim().to_string())
+            .filter(|x| !x.is_empty())
+            .collect(),
+        show_title: text("showtitle"),
+        season: text("season").and_then(|x| x.parse().ok()),
+        episode: text("episode").and_then(|x| x.parse().ok()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn own_nfo_before_movie_nfo() {
+        let siblings = |names: &[&str]| -> Vec<PathBuf> {
+            names.iter().map(|x| Path::new("/films").join(x)).collect()
+        };
+        let film = Path::new("/films/Heat.mkv");
+        assert_eq!(
+            find_nfo(film, &siblings(&["Heat.mkv", "Heat.nfo", "movie.nfo"])),
+            Some(PathBuf::from("/films/Heat.nfo"))
+        );
+        assert_eq!(
+            find_nfo(film, &siblings(&["Heat.mkv", "movie.nfo", "poster.jpg"])),
+            Some(PathBuf::from("/films/movie.nfo"))
+        );
+        // movie.nfo cannot tell two films apart.
+        assert_eq!(
+            find_nfo(film, &siblings(&["Heat.mkv", "Ronin.mkv", "movie.nfo"])),
+            None
+        );
+        assert_eq!(find_nfo(film, &siblings(&["Heat.mkv"])), None);
+    }
+
+    #[test]
+    fn reads_movies_and_episodes() {
+        let dir = std::env::temp_dir().join(format!("agni-nfo-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let read = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            read_nfo(&path)
+        };
+
+        let movie = read(
+            "movie.nfo",
+            "\u{feff}<?xml version=\"1.0\"?>\n<movie>\n  <title> Heat </title>\n  \
+             <outline>Short.</outline>\n  <year>1995</year>\n  \
+             <genre>Crime / Drama</genre>\n  <genre>Thriller</genre>\n  <genre> </genre>\n</movie>",
+        )
+        .unwrap();
+        assert_eq!(
+            movie,
+            Nfo {
+                title: Some("Heat".to_string()),
+                plot: Some("Short.".to_string()),
+                date: Some("1995".to_string()),
+                genres: vec![
+                    "Crime".to_string(),
+                    "Drama".to_string(),
+                    "Thriller".to_string()
+                ],
+                ..Nfo::default()
+            }
+        );
+
+        let episode = read(
+            "episode.nfo",
+            "<episodedetails><title>Pilot</title><showtitle>Firefly</showtitle>\
+             <season>1</season><episode>2</episode><plot>Plot.</plot>\
+             <aired>2002-09-20</aired><premiered>unknown</premiered></episodedetails>",
+        )
+        .unwrap();
+        assert_eq!(episode.show_title.as_deref(), Some("Firefly"));
+        assert_eq!((episode.season, episode.episode), (Some(1), Some(2)));
+        assert_eq!(episode.plot.as_deref(), Some("Plot."));
+        // A date that does not parse gives way to the next one.
+        assert_eq!(episode.date.as_deref(), Some("2002-09-20"));
+
+        assert_eq!(
+            read("scraper.nfo", "https://www.themoviedb.org/movie/949"),
+            None
+        );
+        assert_eq!(
+            read("other.nfo", "<musicvideo><title>x</title></musicvideo>"),
+            None
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
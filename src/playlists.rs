@@ -0,0 +1,491 @@
+use percent_encoding::percent_decode;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use crate::library::{encoded_path, Library, Metadata, Object};
+use crate::media_types::AUDIO_BROADCAST;
+use crate::util::path_from_bytes;
+
+pub const PLAYLIST_CONTAINER: &str = "object.container.playlistContainer";
+const PLAYLIST_EXTENSIONS: &[&str] = &["m3u", "m3u8", "pls", "xspf"];
+/// Playlist entries are numbered from here: the playlist's ID goes in bits 16 to 55
+/// and the entry's position below, so a file listed twice is two objects.
+const FIRST_ENTRY_ID: u64 = 1 << 56;
+/// Entries past this are not listed, as their IDs would not fit.
+const MAX_ENTRIES: usize = 0xFFFF;
+
+/// Where a playlist entry points.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Location {
+    /// A local file, made absolute.
+    File(#[serde(with = "encoded_path")] PathBuf),
+    /// An HTTP stream, such as internet radio.
+    Url(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlaylistEntry {
+    pub location: Location,
+    pub title: Option<String>,
+    /// In seconds.
+    pub duration: Option<u64>,
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| PLAYLIST_EXTENSIONS.contains(&x.to_lowercase().as_str()))
+}
+
+/// UTF-8 if it is valid, else Latin-1, which older `.m3u` and `.pls` files use.
+fn decode_text(bytes: &[u8]) -> String {
+    match std::str::from_utf8(bytes) {
+        Ok(x) => x.to_string(),
+        Err(_) => bytes.iter().map(|&x| x as char).collect(),
+    }
+}
+
+/// `path` with `.` and `..` worked out, so it can be found in the index.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            x => normalized.push(x),
+        }
+    }
+    normalized
+}
+
+fn is_url(location: &[u8]) -> bool {
+    let location = String::from_utf8_lossy(location).to_lowercase();
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+/// Resolves an entry as written in a playlist in `dir`: a URL, a `file://` URI, or a
+/// path, possibly relative and with Windows separators. XSPF writes paths as URIs,
+/// hence `uri`.
+fn resolve(dir: &Path, location: &[u8], uri: bool) -> Option<Location> {
+    let location = location.trim_ascii();
+    if location.is_empty() {
+        return None;
+    }
+    if is_url(location) {
+        return Some(Location::Url(decode_text(location)));
+    }
+    let path: Vec<u8> = match location.strip_prefix(b"file://") {
+        Some(x) => {
+            let x = x.strip_prefix(b"localhost").unwrap_or(x);
+            percent_decode(x).collect()
+        }
+        None if uri => percent_decode(location).collect(),
+        None => location
+            .iter()
+            .map(|&x| if x == b'\\' { b'/' } else { x })
+            .collect(),
+    };
+    Some(Location::File(normalize(&dir.join(path_from_bytes(path)))))
+}
+
+/// A length in seconds; playlists write `-1` for unknown.
+fn parse_seconds(value: &str) -> Option<u64> {
+    value.trim().parse().ok()
+}
+
+/// Splits `#EXTINF:123 tvg-id="x",Title` into the duration and the title. Attributes
+/// may quote commas.
+fn parse_extinf(info: &str) -> (Option<u64>, Option<String>) {
+    let mut quoted = false;
+    let comma = info.char_indices().find(|&(_, x)| {
+        if x == '"' {
+            quoted = !quoted;
+        }
+        x == ',' && !quoted
+    });
+    let (head, title) = match comma {
+        Some((i, _)) => (&info[..i], Some(info[i + 1..].trim().to_string())),
+        None => (info, None),
+    };
+    let duration = head.split_whitespace().next().and_then(parse_seconds);
+    (duration, title.filter(|x| !x.is_empty()))
+}
+
+fn parse_m3u(dir: &Path, contents: &[u8]) -> Vec<PlaylistEntry> {
+    let mut entries = vec![];
+    let mut info = (None, None);
+    for line in contents.split(|&x| x == b'\n') {
+        let line = line.trim_ascii();
+        if let Some(x) = line.strip_prefix(b"#EXTINF:") {
+            info = parse_extinf(&decode_text(x));
+        } else if line.starts_with(b"#") {
+            continue;
+        } else if let Some(location) = resolve(dir, line, false) {
+            let (duration, title) = std::mem::take(&mut info);
+            entries.push(PlaylistEntry {
+                location,
+                title,
+                duration,
+            });
+        }
+    }
+    entries
+}
+
+/// An entry of a `.pls` file, put together from keys that may come in any order.
+#[derive(Default)]
+struct PlsEntry {
+    location: Option<Location>,
+    title: Option<String>,
+    duration: Option<u64>,
+}
+
+/// `File1=`, `Title1=` and `Length1=` keys, in order of their number.
+fn parse_pls(dir: &Path, contents: &[u8]) -> Vec<PlaylistEntry> {
+    let mut entries: BTreeMap<u32, PlsEntry> = BTreeMap::new();
+    for line in contents.split(|&x| x == b'\n') {
+        let (key, value) = match line.iter().position(|&x| x == b'=') {
+            Some(i) => (decode_text(&line[..i]), &line[i + 1..]),
+            None => continue,
+        };
+        let key = key.trim().to_lowercase();
+        let (name, number) = match key.find(|x: char| x.is_ascii_digit()) {
+            Some(i) => (&key[..i], key[i..].parse().ok()),
+            None => continue,
+        };
+        let entry = match number {
+            Some(x) => entries.entry(x).or_default(),
+            None => continue,
+        };
+        match name {
+            "file" => entry.location = resolve(dir, value, false),
+            "title" => {
+                entry.title = Some(decode_text(value.trim_ascii())).filter(|x| !x.is_empty())
+            }
+            "length" => entry.duration = parse_seconds(&decode_text(value)),
+            _ => {}
+        }
+    }
+    entries
+        .into_values()
+        .filter_map(|x| {
+            Some(PlaylistEntry {
+                location: x.location?,
+                title: x.title,
+                duration: x.duration,
+            })
+        })
+        .collect()
+}
+
+/// The tracks of an XSPF playlist; durations there are in milliseconds.
+fn parse_xspf(path: &Path, dir: &Path, contents: &[u8]) -> Vec<PlaylistEntry> {
+    let contents = decode_text(contents);
+    let document = match roxmltree::Document::parse(contents.trim_start_matches('\u{feff}')) {
+        Ok(x) => x,
+        Err(e) => {
+            log::warn!("Cannot parse {}: {}", path.display(), e);
+            return vec![];
+        }
+    };
+    document
+        .descendants()
+        .filter(|x| x.has_tag_name("track"))
+        .filter_map(|track| {
+            let text = |name: &str| {
+                track
+                    .children()
+                    .find(|x| x.has_tag_name(name))
+                    .and_then(|x| x.text())
+                    .map(str::trim)
+                    .filter(|x| !x.is_empty())
+            };
+            Some(PlaylistEntry {
+                location: resolve(dir, text("location")?.as_bytes(), true)?,
+                title: text("title").map(str::to_string),
+                duration: text("duration")
+                    .and_then(|x| x.parse::<u64>().ok())
+                    .map(|x| x / 1000),
+            })
+        })
+        .collect()
+}
+
+/// The entries of the playlist at `path`, in order.
+pub fn read_playlist(path: &Path) -> io::Result<Vec<PlaylistEntry>> {
+    let contents = fs::read(path)?;
+    let contents = contents.strip_prefix(b"\xef\xbb\xbf").unwrap_or(&contents);
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    let extension = path.extension().map(|x| x.to_string_lossy().to_lowercase());
+    let mut entries = match extension.as_deref() {
+        Some("pls") => parse_pls(dir, contents),
+        Some("xspf") => parse_xspf(path, dir, contents),
+        _ => parse_m3u(dir, contents),
+    };
+    entries.truncate(MAX_ENTRIES);
+    Ok(entries)
+}
+
+fn entry_id(playlist_id: u64, index: usize) -> u64 {
+    FIRST_ENTRY_ID | playlist_id << 16 | index as u64
+}
+
+/// The object for entry `index` of `playlist`: the indexed file, or a broadcast item
+/// for a URL, under an ID of its own. Files that are not in the index are left out.
+fn entry_object(
+    library: &Library,
+    playlist: &Object,
+    index: usize,
+    entry: &PlaylistEntry,
+) -> rusqlite::Result<Option<Object>> {
+    let mut object = match &entry.location {
+        Location::File(path) => match library.get_by_path(path)? {
+            Some(x) if !x.is_container() => x,
+            _ => return Ok(None),
+        },
+        Location::Url(url) => Object {
+            id: 0,
+            parent_id: 0,
+            path: PathBuf::new(),
+            title: url.clone(),
+            class: AUDIO_BROADCAST.to_string(),
+            size: 0,
+            modified: 0,
+            metadata: Metadata {
+                url: Some(url.clone()),
+                ..Metadata::default()
+            },
+            update_id: 0,
+        },
+    };
+    object.id = entry_id(playlist.id, index);
+    object.parent_id = playlist.id;
+    if let Some(title) = &entry.title {
+        object.title = title.clone();
+    }
+    object.metadata.duration = entry.duration.or(object.metadata.duration);
+    Ok(Some(object))
+}
+
+/// What the playlist `playlist` lists, in its order.
+pub fn children(library: &Library, playlist: &Object) -> rusqlite::Result<Vec<Object>> {
+    let mut children = vec![];
+    for (index, entry) in playlist.metadata.entries.iter().enumerate() {
+        children.extend(entry_object(library, playlist, index, entry)?);
+    }
+    Ok(children)
+}
+
+/// An entry of a playlist by its ID, for BrowseMetadata and proxying streams.
+pub fn entry(library: &Library, id: u64) -> rusqlite::Result<Option<Object>> {
+    if id < FIRST_ENTRY_ID {
+        return Ok(None);
+    }
+    let (playlist_id, index) = ((id & !FIRST_ENTRY_ID) >> 16, (id & 0xFFFF) as usize);
+    let playlist = match library.get(playlist_id)? {
+        Some(x) if x.class == PLAYLIST_CONTAINER => x,
+        _ => return Ok(None),
+    };
+    match playlist.metadata.entries.get(index) {
+        Some(entry) => entry_object(library, &playlist, index, entry),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::library::ScannedObject;
+
+    fn file(path: &str) -> Location {
+        Location::File(PathBuf::from(path))
+    }
+
+    fn entry(location: Location, title: Option<&str>, duration: Option<u64>) -> PlaylistEntry {
+        PlaylistEntry {
+            location,
+            title: title.map(str::to_string),
+            duration,
+        }
+    }
+
+    #[test]
+    fn extinf_attributes_may_quote_commas() {
+        assert_eq!(
+            parse_extinf(r#"-1 tvg-name="News, Weather" group-title="A,B",BBC One"#),
+            (None, Some("BBC One".to_string()))
+        );
+        assert_eq!(
+            parse_extinf("215,Artist - Song, Live"),
+            (Some(215), Some("Artist - Song, Live".to_string()))
+        );
+        assert_eq!(parse_extinf("42"), (Some(42), None));
+        assert_eq!(parse_extinf("42, "), (Some(42), None));
+    }
+
+    #[test]
+    fn m3u_entries() {
+        let contents = b"#EXTM3U\r\n#EXTINF:215,First\r\n01 - First.mp3\r\n\
+            # a comment\r\n..\\Other\\02.mp3\r\n\r\n./sub/../03 caf\xe9.mp3\r\n\
+            http://radio.example/stream\r\n";
+        assert_eq!(
+            parse_m3u(Path::new("/music/Album"), contents),
+            [
+                entry(
+                    file("/music/Album/01 - First.mp3"),
+                    Some("First"),
+                    Some(215)
+                ),
+                entry(file("/music/Other/02.mp3"), None, None),
+                entry(
+                    Location::File(path_from_bytes(b"/music/Album/03 caf\xe9.mp3".to_vec())),
+                    None,
+                    None
+                ),
+                entry(
+                    Location::Url("http://radio.example/stream".to_string()),
+                    None,
+                    None
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn pls_keys_in_any_order() {
+        let contents = b"[playlist]\nNumberOfEntries=3\nTitle2=Second\nFile10=/ten.mp3\n\
+            File2=two.mp3\nLength2=-1\nFile1=one.mp3\nLength1=30\nTitle3=No file\nVersion=2\n";
+        assert_eq!(
+            parse_pls(Path::new("/music"), contents),
+            [
+                entry(file("/music/one.mp3"), None, Some(30)),
+                entry(file("/music/two.mp3"), Some("Second"), None),
+                entry(file("/ten.mp3"), None, None),
+            ]
+        );
+    }
+
+    #[test]
+    fn xspf_locations_are_uris() {
+        let contents = br#"<?xml version="1.0" encoding="UTF-8"?>
+            <playlist version="1" xmlns="http://xspf.org/ns/0/"><trackList>
+              <track><location>file:///music/Caf%C3%A9%20Tacvba/01%20%231.mp3</location>
+                <title>Uno</title><duration>215500</duration></track>
+              <track><location>file://localhost/music/a%20b.mp3</location></track>
+              <track><location>Relative%20Track.ogg</location></track>
+              <track><title>No location</title></track>
+            </trackList></playlist>"#;
+        assert_eq!(
+            parse_xspf(Path::new("/music/x.xspf"), Path::new("/music"), contents),
+            [
+                entry(
+                    file("/music/Caf\u{e9} Tacvba/01 #1.mp3"),
+                    Some("Uno"),
+                    Some(215)
+                ),
+                entry(file("/music/a b.mp3"), None, None),
+                entry(file("/music/Relative Track.ogg"), None, None),
+            ]
+        );
+    }
+
+    fn scanned(path: &str, class: &str, metadata: Metadata) -> ScannedObject {
+        ScannedObject {
+            path: PathBuf::from(path),
+            title: Path::new(path)
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .into_owned(),
+            class: class.to_string(),
+            size: 0,
+            modified: 0,
+            metadata,
+            inode: None,
+        }
+    }
+
+    #[test]
+    fn entries_have_ids_of_their_own() {
+        let library = Library::open_in_memory();
+        let root = library.add_root(Path::new("/music"), "Music").unwrap();
+        let entries = vec![
+            entry(file("/music/a.mp3"), None, None),
+            entry(file("/music/missing.mp3"), None, None),
+            entry(file("/music/a.mp3"), Some("Again"), Some(60)),
+            entry(
+                Location::Url("http://radio.example/stream".to_string()),
+                Some("Radio"),
+                None,
+            ),
+        ];
+        library
+            .sync_directory(
+                root,
+                &[
+                    scanned("/music/a.mp3", "object.item.audioItem", Metadata::default()),
+                    scanned(
+                        "/music/mix.m3u",
+                        PLAYLIST_CONTAINER,
+                        Metadata {
+                            entries,
+                            ..Metadata::default()
+                        },
+                    ),
+                ],
+            )
+            .unwrap();
+        let file = library
+            .get_by_path(Path::new("/music/a.mp3"))
+            .unwrap()
+            .unwrap();
+        let playlist = library
+            .get_by_path(Path::new("/music/mix.m3u"))
+            .unwrap()
+            .unwrap();
+
+        let children = children(&library, &playlist).unwrap();
+        let listed: Vec<(u64, &str)> = children.iter().map(|x| (x.id, x.title.as_str())).collect();
+        assert_eq!(
+            listed,
+            [
+                (entry_id(playlist.id, 0), "a.mp3"),
+                (entry_id(playlist.id, 2), "Again"),
+                (entry_id(playlist.id, 3), "Radio"),
+            ]
+        );
+        // A file listed twice is two objects, each found again by its ID.
+        for child in &children {
+            assert!(child.id >= FIRST_ENTRY_ID);
+            assert_eq!(child.parent_id, playlist.id);
+            assert_eq!(
+                super::entry(&library, child.id).unwrap().as_ref(),
+                Some(child)
+            );
+        }
+        assert_eq!(children[0].path, file.path);
+        assert_eq!(children[1].path, file.path);
+        assert_eq!(children[1].metadata.duration, Some(60));
+        assert_eq!(
+            children[2].metadata.url.as_deref(),
+            Some("http://radio.example/stream")
+        );
+
+        assert_eq!(
+            super::entry(&library, entry_id(playlist.id, 1)).unwrap(),
+            None
+        );
+        assert_eq!(
+            super::entry(&library, entry_id(playlist.id, 4)).unwrap(),
+            None
+        );
+        assert_eq!(super::entry(&library, entry_id(file.id, 0)).unwrap(), None);
+        assert_eq!(super::entry(&library, file.id).unwrap(), None);
+    }
+}
@@ -0,0 +1,729 @@
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::archives::{image_members, is_archive};
+use crate::config::LibraryConfig;
+use crate::exclusions::Exclusions;
+use crate::library::{Library, Metadata, Object, ScannedObject, STORAGE_FOLDER};
+use crate::media_types::{media_type_for, PHOTO, VIDEO_ITEM};
+use crate::nfo::{find_nfo, read_nfo, Nfo, MOVIE_NFO, TVSHOW_NFO};
+use crate::playlists::{is_playlist, read_playlist, PLAYLIST_CONTAINER};
+use crate::stacks::{disc_parts, find_stacks, Stack};
+use crate::streams::{broadcast_class, is_stream_link, read_stream_link};
+use crate::subtitles::find_subtitles;
+use crate::thumbnails::{find_art_source, find_folder_art, find_poster, ArtSource};
+use crate::tv::{parse_episode, Episode};
+use crate::util::{file_size, title_for};
+
+/// How many scan errors `/agni-scan` lists.
+pub const SCAN_ERRORS_LIMIT: usize = 100;
+
+/// Progress of the current or last library scan, as reported by `/agni-scan`. Times
+/// are seconds since the Unix epoch.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ScanStatus {
+    pub running: bool,
+    pub started: Option<u64>,
+    pub finished: Option<u64>,
+    pub next_scan: Option<u64>,
+    pub directories: usize,
+    pub files: usize,
+    pub errors: usize,
+}
+
+/// A directory to scan: its index ID, path and the modification time indexed for it
+/// before this scan, `0` if none.
+type Directory = (u64, PathBuf, i64);
+
+/// What scanning a directory leaves to do: its subdirectories, and the children that
+/// were not found again.
+#[derive(Debug, Default)]
+struct DirectoryScan {
+    subdirectories: Vec<Directory>,
+    missing: Vec<PathBuf>,
+}
+
+impl DirectoryScan {
+    fn extend(&mut self, other: DirectoryScan) {
+        self.subdirectories.extend(other.subdirectories);
+        self.missing.extend(other.missing);
+    }
+}
+
+/// Keeps the library index in step with the shared folders, rescanning on a schedule.
+pub struct Scanner {
+    library: Arc<Library>,
+    /// Index ID and path of each shared folder.
+    roots: Vec<(u64, PathBuf)>,
+    threads: usize,
+    interval: u64,
+    /// Whether archives are listed as folders.
+    archives: bool,
+    exclusions: Exclusions,
+    status: Mutex<ScanStatus>,
+    /// Whether a scan has finished since startup, after which directories that have
+    /// not changed are skipped.
+    scanned: AtomicBool,
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |x| x.as_secs())
+}
+
+fn modified(metadata: &fs::Metadata) -> i64 {
+    metadata
+        .modified()
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |x| x.as_secs() as i64)
+}
+
+/// The modification time to index for a directory just read, or `0` if it is so
+/// recent that a later change could still share it, as times only count seconds.
+fn settled(modified: i64) -> i64 {
+    if modified < now() as i64 - 1 {
+        modified
+    } else {
+        0
+    }
+}
+
+#[cfg(unix)]
+fn inode(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Whether the indexed file `object` still has the size and modification time it was
+/// indexed with. A stacked movie or disc folder goes by the size of all its parts.
+fn unchanged_on_disk(object: &Object) -> bool {
+    let metadata = match fs::metadata(&object.path) {
+        Ok(x) => x,
+        Err(_) => return false,
+    };
+    let size = match object.metadata.parts.is_empty() {
+        true => metadata.len(),
+        false => object.metadata.parts.iter().map(|x| file_size(x)).sum(),
+    };
+    modified(&metadata) == object.modified && size == object.size
+}
+
+/// Where the video at `path` belongs as an episode: from its `.nfo` when that gives
+/// season and episode, else from its name.
+fn episode_for(path: &Path, nfo: &Nfo) -> Option<Episode> {
+    let parsed = parse_episode(path);
+    match (nfo.season, nfo.episode) {
+        (Some(season), Some(episode)) => Some(Episode {
+            series: nfo
+                .show_title
+                .clone()
+                .or_else(|| parsed.map(|x| x.series))?,
+            season,
+            episode: Some(episode),
+            date: nfo.date.clone(),
+        }),
+        _ => parsed,
+    }
+}
+
+/// Describes one directory entry for the index. `previous` is what the index holds
+/// for it; when size and modification time still match, the entry itself is not read
+/// again, only looked up against its sidecar files.
+/// Titles, plots, dates and genres from Kodi `.nfo` files win over the file name. The
+/// first part of a `stack` stands for the whole movie, and a DVD or Blu-ray folder is
+/// a single video rather than a folder. Playlists are containers of what they list,
+/// `.strm`/`.url` files items for the stream they point to, and with `archives` set,
+/// archives folders of their images.
+fn scan_entry(
+    path: &Path,
+    title: String,
+    metadata: &fs::Metadata,
+    siblings: &[PathBuf],
+    stack: Option<&Stack>,
+    previous: Option<&Object>,
+    archives: bool,
+) -> Option<ScannedObject> {
+    if metadata.is_dir() {
+        if let Some(parts) = disc_parts(path) {
+            let nfo = read_nfo(&path.join(MOVIE_NFO)).unwrap_or_default();
+            return Some(ScannedObject {
+                path: path.to_path_buf(),
+                title: nfo.title.unwrap_or(title),
+                class: VIDEO_ITEM.to_string(),
+                size: parts.iter().map(|x| file_size(x)).sum(),
+                modified: modified(metadata),
+                metadata: Metadata {
+                    has_art: find_folder_art(path).is_some(),
+                    description: nfo.plot,
+                    date: nfo.date,
+                    genres: nfo.genres,
+                    parts,
+                    ..Metadata::default()
+                },
+                inode: inode(metadata),
+            });
+        }
+        let nfo = read_nfo(&path.join(TVSHOW_NFO)).unwrap_or_default();
+        return Some(ScannedObject {
+            path: path.to_path_buf(),
+            title: nfo.title.unwrap_or(title),
+            class: STORAGE_FOLDER.to_string(),
+            size: 0,
+            modified: modified(metadata),
+            metadata: Metadata {
+                has_art: find_folder_art(path).is_some(),
+                description: nfo.plot,
+                date: nfo.date,
+                genres: nfo.genres,
+                ..Metadata::default()
+            },
+            inode: inode(metadata),
+        });
+    }
+    let unchanged = previous.filter(|x| x.modified == modified(metadata));
+    let unchanged_file = unchanged.filter(|x| x.size == metadata.len());
+    if is_playlist(path) {
+        let entries = match unchanged_file.filter(|x| x.class == PLAYLIST_CONTAINER) {
+            Some(x) => x.metadata.entries.clone(),
+            None => match read_playlist(path) {
+                Ok(x) => x,
+                Err(e) => {
+                    log::warn!("Cannot read {}: {}", path.display(), e);
+                    return None;
+                }
+            },
+        };
+        return Some(ScannedObject {
+            path: path.to_path_buf(),
+            title: path.file_stem().map_or(title, title_for),
+            class: PLAYLIST_CONTAINER.to_string(),
+            size: metadata.len(),
+            modified: modified(metadata),
+            metadata: Metadata {
+                entries,
+                ..Metadata::default()
+            },
+            inode: inode(metadata),
+        });
+    }
+    if archives && is_archive(path) {
+        let has_images = match unchanged_file.filter(|x| x.class == STORAGE_FOLDER) {
+            Some(x) => x.metadata.has_art,
+            None => match image_members(path) {
+                Ok(x) => !x.is_empty(),
+                Err(e) => {
+                    log::warn!("Cannot read {}: {}", path.display(), e);
+                    return None;
+                }
+            },
+        };
+        return Some(ScannedObject {
+            path: path.to_path_buf(),
+            title: path.file_stem().map_or(title, title_for),
+            class: STORAGE_FOLDER.to_string(),
+            size: metadata.len(),
+            modified: modified(metadata),
+            metadata: Metadata {
+                has_art: has_images,
+                ..Metadata::default()
+            },
+            inode: inode(metadata),
+        });
+    }
+    if is_stream_link(path) {
+        let url = match unchanged_file.and_then(|x| x.metadata.url.clone()) {
+            Some(x) => x,
+            None => match read_stream_link(path) {
+                Ok(x) => x?,
+                Err(e) => {
+                    log::warn!("Cannot read {}: {}", path.display(), e);
+                    return None;
+                }
+            },
+        };
+        return Some(ScannedObject {
+            path: path.to_path_buf(),
+            title: path.file_stem().map_or(title, title_for),
+            class: broadcast_class(&url).to_string(),
+            size: metadata.len(),
+            modified: modified(metadata),
+            metadata: Metadata {
+                has_art: path.parent().and_then(find_folder_art).is_some(),
+                url: Some(url),
+                ..Metadata::default()
+            },
+            inode: inode(metadata),
+        });
+    }
+    let media_type = media_type_for(path)?;
+    let is_video = media_type.class == VIDEO_ITEM;
+    let subtitles = if is_video {
+        find_subtitles(path, siblings)
+            .into_iter()
+            .map(|x| x.path)
+            .collect()
+    } else {
+        vec![]
+    };
+    let nfo = find_nfo(path, siblings)
+        .filter(|_| is_video)
+        .and_then(|x| read_nfo(&x))
+        .unwrap_or_default();
+    let episode = if is_video {
+        episode_for(path, &nfo)
+    } else {
+        None
+    };
+    let (title, size, parts) = match stack {
+        Some(x) => (
+            x.title.clone(),
+            x.parts.iter().map(|x| file_size(x)).sum(),
+            x.parts.clone(),
+        ),
+        None => (title, metadata.len(), vec![]),
+    };
+    let embedded_art = match unchanged.filter(|x| x.size == size) {
+        Some(x) => x.metadata.embedded_art,
+        None => matches!(find_art_source(path), Some(ArtSource::Embedded(_))),
+    };
+    let has_art = embedded_art
+        || media_type.class == PHOTO
+        || (is_video && find_poster(path).is_some())
+        || path.parent().and_then(find_folder_art).is_some();
+    Some(ScannedObject {
+        path: path.to_path_buf(),
+        title: nfo.title.unwrap_or(title),
+        class: media_type.class.to_string(),
+        size,
+        modified: modified(metadata),
+        metadata: Metadata {
+            has_art,
+            embedded_art,
+            subtitles,
+            date: nfo
+                .date
+                .or_else(|| episode.as_ref().and_then(|x| x.date.clone())),
+            episode,
+            description: nfo.plot,
+            genres: nfo.genres,
+            parts,
+            ..Metadata::default()
+        },
+        inode: inode(metadata),
+    })
+}
+
+impl Scanner {
+    pub fn new(library: Arc<Library>, roots: Vec<(u64, PathBuf)>, config: &LibraryConfig) -> Self {
+        Scanner {
+            library,
+            roots,
+            threads: config.scan_threads.max(1),
+            interval: config.scan_interval,
+            archives: config.archives,
+            exclusions: Exclusions::new(config),
+            status: Mutex::new(ScanStatus::default()),
+            scanned: AtomicBool::new(false),
+        }
+    }
+
+    pub fn status(&self) -> ScanStatus {
+        self.status.lock().unwrap().clone()
+    }
+
+    fn record_error(&self, path: &Path, error: &str) {
+        log::warn!("Cannot scan {}: {}", path.display(), error);
+        self.status.lock().unwrap().errors += 1;
+        if let Err(e) = self
+            .library
+            .record_scan_error(&path.to_string_lossy(), error)
+        {
+            log::error!("Cannot record scan error: {}", e);
+        }
+    }
+
+    /// Indexes the images of an archive listed as a folder. They take the archive's
+    /// modification time, and have no art but themselves.
+    fn scan_archive(
+        &self,
+        id: u64,
+        archive: &Path,
+        metadata: &fs::Metadata,
+    ) -> rusqlite::Result<DirectoryScan> {
+        let members = match image_members(archive) {
+            Ok(x) => x,
+            Err(e) => {
+                self.record_error(archive, &e.to_string());
+                self.library.set_modified(id, 0)?;
+                return Ok(DirectoryScan::default());
+            }
+        };
+        let scanned: Vec<ScannedObject> = members
+            .into_iter()
+            .map(|(name, size)| ScannedObject {
+                title: name.file_name().map_or_else(String::new, title_for),
+                path: archive.join(name),
+                class: PHOTO.to_string(),
+                size,
+                modified: modified(metadata),
+                metadata: Metadata {
+                    has_art: true,
+                    ..Metadata::default()
+                },
+                inode: None,
+            })
+            .collect();
+        {
+            let mut status = self.status.lock().unwrap();
+            status.directories += 1;
+            status.files += scanned.len();
+        }
+        let result = self.library.sync_directory(id, &scanned)?;
+        self.library.set_modified(id, settled(modified(metadata)))?;
+        Ok(DirectoryScan {
+            subdirectories: vec![],
+            missing: result.missing,
+        })
+    }
+
+    /// Takes a directory whose entries have not changed since it was indexed as it is,
+    /// leaving only its subdirectories to look at. `None` if a file in it was changed in
+    /// place, which leaves the directory's own modification time alone.
+    fn skip_directory(&self, id: u64) -> rusqlite::Result<Option<DirectoryScan>> {
+        let (folders, files): (Vec<Object>, Vec<Object>) = self
+            .library
+            .children(id)?
+            .into_iter()
+            .partition(|x| x.class == STORAGE_FOLDER);
+        if !files.iter().all(unchanged_on_disk) {
+            return Ok(None);
+        }
+        {
+            let mut status = self.status.lock().unwrap();
+            status.directories += 1;
+            status.files += files.len();
+        }
+        Ok(Some(DirectoryScan {
+            subdirectories: folders
+                .into_iter()
+                .map(|x| (x.id, x.path, x.modified))
+                .collect(),
+            missing: vec![],
+        }))
+    }
+
+    /// Indexes the entries of one directory. A directory that cannot be read is left as
+    /// indexed, and after the first scan one whose modification time is still
+    /// `indexed_modified` is skipped unless one of its files or the `.agniignore`
+    /// files that apply to it changed.
+    fn scan_directory(
+        &self,
+        id: u64,
+        dir: &Path,
+        indexed_modified: i64,
+    ) -> rusqlite::Result<DirectoryScan> {
+        let dir_metadata = match fs::metadata(dir) {
+            Ok(x) => x,
+            Err(e) => {
+                self.record_error(dir, &e.to_string());
+                return Ok(DirectoryScan::default());
+            }
+        };
+        let dir_modified = modified(&dir_metadata);
+        let unchanged = indexed_modified != 0 && dir_modified == indexed_modified;
+        if unchanged && self.scanned.load(Ordering::Relaxed) && !self.exclusions.changed(dir) {
+            if let Some(x) = self.skip_directory(id)? {
+                return Ok(x);
+            }
+        }
+        if dir_metadata.is_file() {
+            return self.scan_archive(id, dir, &dir_metadata);
+        }
+        let read_dir = match fs::read_dir(dir) {
+            Ok(x) => x,
+            Err(e) => {
+                self.record_error(dir, &e.to_string());
+                // Its parent has already indexed the new time, so make sure the next
+                // scan tries again.
+                self.library.set_modified(id, 0)?;
+                return Ok(DirectoryScan::default());
+            }
+        };
+        let previous: HashMap<PathBuf, Object> = self
+            .library
+            .children(id)?
+            .into_iter()
+            .map(|x| (x.path.clone(), x))
+            .collect();
+        let mut unreadable = vec![];
+        let mut entries: Vec<(PathBuf, String, fs::Metadata)> = vec![];
+        for entry in read_dir {
+            let entry = match entry {
+                Ok(x) => x,
+                Err(e) => {
+                    self.record_error(dir, &e.to_string());
+                    continue;
+                }
+            };
+            let path = entry.path();
+            let title = title_for(&entry.file_name());
+            match entry.metadata() {
+                Ok(metadata) if self.exclusions.is_excluded(&path, metadata.is_dir()) => {}
+                Ok(metadata) => entries.push((path, title, metadata)),
+                Err(e) => {
+                    self.record_error(&path, &e.to_string());
+                    unreadable.push(path);
+                }
+            }
+        }
+        let mut siblings: Vec<PathBuf> = entries
+            .iter()
+            .filter(|(_, _, metadata)| !metadata.is_dir())
+            .map(|(path, _, _)| path.clone())
+            .collect();
+        // Only the first part of a stack is listed.
+        let stacks = find_stacks(&siblings);
+        let later_parts: HashSet<&PathBuf> = stacks.iter().flat_map(|x| &x.parts[1..]).collect();
+        siblings.retain(|x| !later_parts.contains(x));
+        let scanned: Vec<ScannedObject> = entries
+            .into_iter()
+            .filter(|(path, _, _)| !later_parts.contains(path))
+            .filter_map(|(path, title, metadata)| {
+                let stack = stacks.iter().find(|x| x.parts[0] == path);
+                scan_entry(
+                    &path,
+                    title,
+                    &metadata,
+                    &siblings,
+                    stack,
+                    previous.get(&path),
+                    self.archives,
+                )
+            })
+            .collect();
+        {
+            let mut status = self.status.lock().unwrap();
+            status.directories += 1;
+            status.files += scanned.iter().filter(|x| x.class != STORAGE_FOLDER).count();
+        }
+        let result = self.library.sync_directory(id, &scanned)?;
+        self.library.set_modified(id, settled(dir_modified))?;
+
+        let subdirectories = self
+            .library
+            .children(id)?
+            .into_iter()
+            .filter(|x| x.class == STORAGE_FOLDER)
+            .map(|x| {
+                let indexed = previous.get(&x.path).map_or(0, |x| x.modified);
+                (x.id, x.path, indexed)
+            })
+            .collect();
+        let missing = result
+            .missing
+            .into_iter()
+            .filter(|x| !unreadable.contains(x))
+            .collect();
+        Ok(DirectoryScan {
+            subdirectories,
+            missing,
+        })
+    }
+
+    /// Brings the index up to date with everything under the roots, a directory level
+    /// at a time with up to `threads` directories read at once. Walks the disk, so run
+    /// it off the async executor.
+    pub fn scan(&self) {
+        {
+            let mut status = self.status.lock().unwrap();
+            *status = ScanStatus {
+                running: true,
+                started: Some(now()),
+                ..ScanStatus::default()
+            };
+        }
+        self.exclusions.clear();
+        if let Err(e) = self.library.clear_scan_errors() {
+            log::error!("Cannot clear scan errors: {}", e);
+        }
+        log::info!("Scanning library");
+
+        let mut level: Vec<Directory> = self
+            .roots
+            .iter()
+            .map(|(id, path)| {
+                let indexed = self.library.get(*id).ok().flatten();
+                (*id, path.clone(), indexed.map_or(0, |x| x.modified))
+            })
+            .collect();
+        let mut missing = vec![];
+        while !level.is_empty() {
+            let chunk_size = level.len().div_ceil(self.threads);
+            let results: Vec<DirectoryScan> = thread::scope(|scope| {
+                let workers: Vec<_> = level
+                    .chunks(chunk_size)
+                    .map(|chunk| {
+                        scope.spawn(move || {
+                            let mut result = DirectoryScan::default();
+                            for (id, dir, indexed_modified) in chunk {
+                                match self.scan_directory(*id, dir, *indexed_modified) {
+                                    Ok(x) => result.extend(x),
+                                    Err(e) => {
+                                        log::error!("Scanning {} failed: {}", dir.display(), e)
+                                    }
+                                }
+                            }
+                            result
+                        })
+                    })
+                    .collect();
+                workers.into_iter().filter_map(|x| x.join().ok()).collect()
+            });
+            let mut next = DirectoryScan::default();
+            results.into_iter().for_each(|x| next.extend(x));
+            level = next.subdirectories;
+            missing.extend(next.missing);
+        }
+
+        // Removals wait for the whole walk, so a file moved to a directory scanned later
+        // keeps its ID. Files still on disk go too when they are no longer listed, such as
+        // archives once `archives` is turned off.
+        if let Err(e) = self.library.remove(&missing) {
+            log::error!("Cannot remove deleted files from the index: {}", e);
+        }
+
+        self.scanned.store(true, Ordering::Relaxed);
+        let mut status = self.status.lock().unwrap();
+        status.running = false;
+        status.finished = Some(now());
+        status.next_scan = match self.interval {
+            0 => None,
+            x => Some(now() + x),
+        };
+        log::info!(
+            "Library scan finished: {} directories, {} files, {} errors",
+            status.directories,
+            status.files,
+            status.errors
+        );
+    }
+
+    /// Scans at startup, then every `interval` seconds unless that is `0`.
+    pub async fn run(scanner: Arc<Scanner>) {
+        loop {
+            let worker = scanner.clone();
+            if let Err(e) = tokio::task::spawn_blocking(move || worker.scan()).await {
+                log::error!("Library scan panicked: {}", e);
+            }
+            if scanner.interval == 0 {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_secs(scanner.interval)).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::RootConfig;
+    use crate::exclusions::IGNORE_FILE;
+
+    /// Backdates `path`, so that a change made in the same second is told apart.
+    fn backdate(path: &Path, seconds: u64) {
+        let time = SystemTime::now() - Duration::from_secs(seconds);
+        fs::File::open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn rescans_find_files_changed_in_place() {
+        let dir = std::env::temp_dir().join(format!("agni-scanner-{}", std::process::id()));
+        let album = dir.join("Album");
+        fs::create_dir_all(&album).unwrap();
+        fs::write(dir.join("a.mp3"), b"one").unwrap();
+        fs::write(album.join("b.mp3"), b"two").unwrap();
+        for path in [
+            dir.join("a.mp3"),
+            album.join("b.mp3"),
+            album.clone(),
+            dir.clone(),
+        ] {
+            backdate(&path, 100);
+        }
+
+        let library = Arc::new(Library::open_in_memory());
+        let root = library.add_root(&dir, "Music").unwrap();
+        let config = LibraryConfig {
+            roots: vec![RootConfig {
+                title: "Music".to_string(),
+                path: dir.clone(),
+                collation: Default::default(),
+                flatten: false,
+            }],
+            ..LibraryConfig::default()
+        };
+        let scanner = Scanner::new(library.clone(), vec![(root, dir.clone())], &config);
+        scanner.scan();
+        let size = |path: &Path| library.get_by_path(path).unwrap().map(|x| x.size);
+        assert_eq!(size(&album.join("b.mp3")), Some(3));
+
+        // Rewriting a file leaves its directory's modification time alone.
+        fs::write(album.join("b.mp3"), b"three").unwrap();
+        backdate(&album.join("b.mp3"), 50);
+        backdate(&album, 100);
+        scanner.scan();
+        assert_eq!(size(&album.join("b.mp3")), Some(5));
+        assert_eq!(size(&dir.join("a.mp3")), Some(3));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rescans_apply_new_ignore_rules() {
+        let dir = std::env::temp_dir().join(format!("agni-scanner-ignore-{}", std::process::id()));
+        let album = dir.join("Album");
+        fs::create_dir_all(&album).unwrap();
+        fs::write(album.join("b.mp3"), b"two").unwrap();
+        for path in [album.join("b.mp3"), album.clone(), dir.clone()] {
+            backdate(&path, 100);
+        }
+
+        let library = Arc::new(Library::open_in_memory());
+        let root = library.add_root(&dir, "Music").unwrap();
+        let config = LibraryConfig {
+            roots: vec![RootConfig {
+                title: "Music".to_string(),
+                path: dir.clone(),
+                collation: Default::default(),
+                flatten: false,
+            }],
+            ..LibraryConfig::default()
+        };
+        let scanner = Scanner::new(library.clone(), vec![(root, dir.clone())], &config);
+        scanner.scan();
+        let indexed = |path: &Path| library.get_by_path(path).unwrap().is_some();
+        assert!(indexed(&album.join("b.mp3")));
+
+        // The folder holding the file is unchanged; the rules above it are not.
+        fs::write(dir.join(IGNORE_FILE), "b.mp3\n").unwrap();
+        scanner.scan();
+        assert!(!indexed(&album.join("b.mp3")));
+
+        fs::remove_file(dir.join(IGNORE_FILE)).unwrap();
+        scanner.scan();
+        assert!(indexed(&album.join("b.mp3")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+use rusqlite::types::Value;
+
+/// ContentDirectory error for a SearchCriteria agni cannot parse, or that names a
+/// property or operator it does not support.
+pub const INVALID_SEARCH_CRITERIA: u16 = 708;
+pub const SEARCH_CAPABILITIES: &str = "dc:title,upnp:class,@refID";
+/// Searchable properties and the column holding each, folded to lower case. Indexed
+/// objects are never references, so `@refID` has no column.
+const PROPERTIES: &[(&str, Option<&str>)] = &[
+    ("dc:title", Some("fold(title)")),
+    ("upnp:class", Some("fold(class)")),
+    ("@refID", None),
+];
+const OPERATORS: &[&str] = &[
+    "=",
+    "!=",
+    "<",
+    "<=",
+    ">",
+    ">=",
+    "contains",
+    "doesNotContain",
+    "derivedfrom",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Open,
+    Close,
+    Word(String),
+    Quoted(String),
+}
+
+/// A parsed CDS `SearchCriteria`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Criteria {
+    All,
+    And(Box<Criteria>, Box<Criteria>),
+    Or(Box<Criteria>, Box<Criteria>),
+    Relation {
+        property: String,
+        operator: String,
+        value: String,
+    },
+    Exists {
+        property: String,
+        exists: bool,
+    },
+}
+
+fn tokenize(criteria: &str) -> Option<Vec<Token>> {
+    let mut tokens = vec![];
+    let mut chars = criteria.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next()? {
+                        '\\' => value.push(chars.next()?),
+                        '"' => break,
+                        x => value.push(x),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            x if x.is_whitespace() => {
+                chars.next();
+            }
+            _ => {
+                let mut word = String::new();
+                while let Some(&x) = chars.peek() {
+                    if x.is_whitespace() || x == '(' || x == ')' || x == '"' {
+                        break;
+                    }
+                    word.push(x);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word));
+            }
+        }
+    }
+    Some(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn peek_word(&self, word: &str) -> bool {
+        matches!(self.tokens.get(self.position), Some(Token::Word(x)) if x.eq_ignore_ascii_case(word))
+    }
+
+    // `and` binds tighter than `or`.
+    fn or(&mut self) -> Option<Criteria> {
+        let mut left = self.and()?;
+        while self.peek_word("or") {
+            self.position += 1;
+            left = Criteria::Or(Box::new(left), Box::new(self.and()?));
+        }
+        Some(left)
+    }
+
+    fn and(&mut self) -> Option<Criteria> {
+        let mut left = self.relation()?;
+        while self.peek_word("and") {
+            self.position += 1;
+            left = Criteria::And(Box::new(left), Box::new(self.relation()?));
+        }
+        Some(left)
+    }
+
+    fn relation(&mut self) -> Option<Criteria> {
+        match self.next()? {
+            Token::Open => {
+                let criteria = self.or()?;
+                match self.next()? {
+                    Token::Close => Some(criteria),
+                    _ => None,
+                }
+            }
+            Token::Word(property) => {
+                let operator = match self.next()? {
+                    Token::Word(x) => x,
+                    _ => return None,
+                };
+                column(&property)?;
+                if operator == "exists" {
+                    let exists = match self.next()? {
+                        Token::Word(x) if x == "true" => true,
+                        Token::Word(x) if x == "false" => false,
+                        _ => return None,
+                    };
+                    return Some(Criteria::Exists { property, exists });
+                }
+                if !OPERATORS.contains(&operator.as_str()) {
+                    return None;
+                }
+                match self.next()? {
+                    Token::Quoted(value) => Some(Criteria::Relation {
+                        property,
+                        operator,
+                        value,
+                    }),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+}
+
+impl Criteria {
+    /// Parses a SearchCriteria string, `None` if it is malformed.
+    pub fn parse(criteria: &str) -> Option<Self> {
+        if criteria.trim().is_empty() || criteria.trim() == "*" {
+            return Some(Criteria::All);
+        }
+        let mut parser = Parser {
+            tokens: tokenize(criteria)?,
+            position: 0,
+        };
+        let parsed = parser.or()?;
+        if parser.position == parser.tokens.len() {
+            Some(parsed)
+        } else {
+            None
+        }
+    }
+
+    /// The criteria as an SQL condition on the `objects` table, pushing the values of
+    /// its `?` placeholders onto `values`. Text is compared case-insensitively.
+    pub fn to_sql(&self, values: &mut Vec<Value>) -> String {
+        match self {
+            Criteria::All => "1".to_string(),
+            Criteria::And(a, b) => format!("({} AND {})", a.to_sql(values), b.to_sql(values)),
+            Criteria::Or(a, b) => format!("({} OR {})", a.to_sql(values), b.to_sql(values)),
+            Criteria::Exists { property, exists } => {
+                let present = matches!(column(property), Some(Some(_)));
+                if present == *exists { "1" } else { "0" }.to_string()
+            }
+            Criteria::Relation {
+                property,
+                operator,
+                value,
+            } => {
+                let column = match column(property) {
+                    Some(Some(x)) => x,
+                    // A property an object lacks matches no relation.
+                    _ => return "0".to_string(),
+                };
+                let value = value.to_lowercase();
+                let condition = match operator.as_str() {
+                    "contains" => format!("instr({}, ?) > 0", column),
+                    "doesNotContain" => format!("instr({}, ?) = 0", column),
+                    "derivedfrom" => {
+                        values.push(Value::Text(value.clone()));
+                        values.push(Value::Text(format!("{}.", value)));
+                        values.push(Value::Text(format!("{}.", value)));
+                        return format!("({0} = ? OR substr({0}, 1, length(?)) = ?)", column);
+                    }
+                    x => format!("{} {} ?", column, x),
+                };
+                values.push(Value::Text(value));
+                condition
+            }
+        }
+    }
+}
+
+/// The column of a supported `property`: `None` if it is unknown, `Some(None)` if
+/// no object has it.
+fn column(property: &str) -> Option<Option<&'static str>> {
+    PROPERTIES
+        .iter()
+        .find(|(name, _)| *name == property)
+        .map(|(_, column)| *column)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_criteria() {
+        let criteria = Criteria::parse(
+            r#"(upnp:class derivedfrom "object.item.audioItem" or dc:title contains "a\"b") and @refID exists false"#,
+        )
+        .unwrap();
+        let relation = |property: &str, operator: &str, value: &str| {
+            Box::new(Criteria::Relation {
+                property: property.to_string(),
+                operator: operator.to_string(),
+                value: value.to_string(),
+            })
+        };
+        assert_eq!(
+            criteria,
+            Criteria::And(
+                Box::new(Criteria::Or(
+                    relation("upnp:class", "derivedfrom", "object.item.audioItem"),
+                    relation("dc:title", "contains", "a\"b"),
+                )),
+                Box::new(Criteria::Exists {
+                    property: "@refID".to_string(),
+                    exists: false,
+                }),
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_unsupported_criteria() {
+        assert_eq!(Criteria::parse(r#"upnp:artist = "x""#), None);
+        assert_eq!(Criteria::parse("upnp:artist exists true"), None);
+        assert_eq!(Criteria::parse(r#"dc:title like "x""#), None);
+        assert_eq!(Criteria::parse(r#"dc:title = "x" and"#), None);
+        assert_eq!(Criteria::parse(r#"dc:title = "x"#), None);
+    }
+
+    #[test]
+    fn sql_binds_folded_values() {
+        let criteria =
+            Criteria::parse(r#"upnp:class derivedfrom "Object.Item" and dc:title != "É""#).unwrap();
+        let mut values = vec![];
+        assert_eq!(
+            criteria.to_sql(&mut values),
+            "((fold(class) = ? OR substr(fold(class), 1, length(?)) = ?) AND fold(title) != ?)"
+        );
+        let text = |x: &str| Value::Text(x.to_string());
+        assert_eq!(
+            values,
+            [
+                text("object.item"),
+                text("object.item."),
+                text("object.item."),
+                text("é")
+            ]
+        );
+    }
+
+    #[test]
+    fn sql_for_properties_objects_lack() {
+        let mut values = vec![];
+        let sql = |x: &str, values: &mut Vec<Value>| Criteria::parse(x).unwrap().to_sql(values);
+        assert_eq!(sql("@refID exists false", &mut values), "1");
+        assert_eq!(sql("@refID exists true", &mut values), "0");
+        assert_eq!(sql("dc:title exists false", &mut values), "0");
+        assert_eq!(sql(r#"@refID = "1""#, &mut values), "0");
+        assert!(values.is_empty());
+    }
+}
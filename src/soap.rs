@@ -0,0 +1,68 @@
+use actix_web::{HttpRequest, HttpResponse};
+
+use crate::types::{ENVELOPE_ENCODING_STYLE, ENVELOPE_XMLNS_S};
+
+pub const SOAP_ACTION: &str = "Soapaction";
+
+pub const INVALID_ACTION: u16 = 401;
+pub const INVALID_ARGS: u16 = 402;
+pub const ACTION_FAILED: u16 = 501;
+
+pub fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// The action name from a `SOAPACTION: "urn:...:service:X:1#Action"` header.
+pub fn get_action(req: &HttpRequest) -> String {
+    req.headers()
+        .get(SOAP_ACTION)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.trim_matches('"').split('#').nth(1))
+        .unwrap_or("")
+        .to_string()
+}
+
+pub fn get_argument(document: &roxmltree::Document, name: &str) -> Option<String> {
+    document
+        .descendants()
+        .find(|x| x.tag_name().name() == name)
+        .map(|x| x.text().unwrap_or("").to_string())
+}
+
+/// Wraps the out arguments of `action` in a SOAP envelope. Values are escaped here.
+pub fn action_response(service_type: &str, action: &str, arguments: &[(&str, String)]) -> String {
+    let mut body = String::new();
+    for (name, value) in arguments {
+        body.push_str(&format!("<{0}>{1}</{0}>", name, escape_xml(value)));
+    }
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<s:Envelope xmlns:s="{}" s:encodingStyle="{}"><s:Body><u:{}Response xmlns:u="{}">{}</u:{}Response></s:Body></s:Envelope>"#,
+        ENVELOPE_XMLNS_S, ENVELOPE_ENCODING_STYLE, action, service_type, body, action
+    )
+}
+
+pub fn fault(error_code: u16, description: &str) -> HttpResponse {
+    let body = format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<s:Envelope xmlns:s="{}" s:encodingStyle="{}"><s:Body><s:Fault><faultcode>s:Client</faultcode><faultstring>UPnPError</faultstring><detail><UPnPError xmlns="urn:schemas-upnp-org:control-1-0"><errorCode>{}</errorCode><errorDescription>{}</errorDescription></UPnPError></detail></s:Fault></s:Body></s:Envelope>"#,
+        ENVELOPE_XMLNS_S,
+        ENVELOPE_ENCODING_STYLE,
+        error_code,
+        escape_xml(description)
+    );
+    HttpResponse::InternalServerError()
+        .content_type("text/xml")
+        .body(body)
+}
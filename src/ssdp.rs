@@ -0,0 +1,484 @@
+use chrono::{DateTime, Utc};
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::time::Duration;
+use uuid::Uuid;
+
+pub const MAX_AGE: u32 = 1800;
+pub const SSDP_ALL: &str = "ssdp:all";
+pub const BOOT_ID_FILE: &str = "bootid";
+/// The longest MX a response is delayed by; UDA 1.1 caps it at 5 seconds.
+pub const MAX_MX: u32 = 5;
+
+/// Identifies this device instance towards control points (UDA 1.1).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Device {
+    pub uuid: Uuid,
+    pub boot_id: u32,
+    pub config_id: u32,
+}
+
+/// Reads the BOOTID persisted in `data_dir`, increments it and writes it back, so that
+/// each start announces a fresh value.
+pub fn next_boot_id(data_dir: &Path) -> u32 {
+    let path = data_dir.join(BOOT_ID_FILE);
+    let previous = fs::read_to_string(&path)
+        .ok()
+        .and_then(|x| x.trim().parse::<u32>().ok())
+        .unwrap_or(0);
+    let boot_id = (previous + 1) & 0x7fff_ffff;
+    if let Err(e) = fs::create_dir_all(data_dir).and_then(|_| fs::write(&path, boot_id.to_string()))
+    {
+        log::warn!("Could not persist BOOTID to {}: {}", path.display(), e);
+    }
+    boot_id
+}
+
+/// CONFIGID for a set of description documents: a stable hash limited to the
+/// 0..=16777215 range UDA 1.1 allows.
+pub fn config_id(documents: &[&str]) -> u32 {
+    let mut hash: u32 = 0x811c_9dc5;
+    for byte in documents.iter().flat_map(|x| x.bytes()) {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x0100_0193);
+    }
+    hash & 0x00ff_ffff
+}
+
+/// RFC 1123 date, the format required for the `DATE` header.
+pub fn http_date(date: &DateTime<Utc>) -> String {
+    date.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn os_version() -> String {
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+        .map(|x| x.trim().to_string())
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+/// `OS/version UPnP/1.1 product/version` as sent in the `SERVER` header.
+pub fn server_token() -> String {
+    format!(
+        "{}/{} UPnP/1.1 agni/{}",
+        std::env::consts::OS,
+        os_version(),
+        env!("CARGO_PKG_VERSION")
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SsdpMessage {
+    Alive {
+        host: SocketAddr,
+        nt: String,
+        usn: String,
+        location: String,
+        max_age: u32,
+        server: String,
+        boot_id: u32,
+        config_id: u32,
+    },
+    ByeBye {
+        host: SocketAddr,
+        nt: String,
+        usn: String,
+        boot_id: u32,
+        config_id: u32,
+    },
+    SearchResponse {
+        date: DateTime<Utc>,
+        location: String,
+        server: String,
+        st: String,
+        usn: String,
+        max_age: u32,
+        boot_id: u32,
+        config_id: u32,
+    },
+}
+
+impl SsdpMessage {
+    pub fn alive(device: &Device, host: SocketAddr, nt: &str, usn: &str, location: &str) -> Self {
+        SsdpMessage::Alive {
+            host,
+            nt: nt.to_string(),
+            usn: usn.to_string(),
+            location: location.to_string(),
+            max_age: MAX_AGE,
+            server: server_token(),
+            boot_id: device.boot_id,
+            config_id: device.config_id,
+        }
+    }
+
+    pub fn byebye(device: &Device, host: SocketAddr, nt: &str, usn: &str) -> Self {
+        SsdpMessage::ByeBye {
+            host,
+            nt: nt.to_string(),
+            usn: usn.to_string(),
+            boot_id: device.boot_id,
+            config_id: device.config_id,
+        }
+    }
+
+    pub fn search_response(device: &Device, st: &str, usn: &str, location: &str) -> Self {
+        SsdpMessage::SearchResponse {
+            date: Utc::now(),
+            location: location.to_string(),
+            server: server_token(),
+            st: st.to_string(),
+            usn: usn.to_string(),
+            max_age: MAX_AGE,
+            boot_id: device.boot_id,
+            config_id: device.config_id,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().into_bytes()
+    }
+}
+
+impl fmt::Display for SsdpMessage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SsdpMessage::Alive {
+                host,
+                nt,
+                usn,
+                location,
+                max_age,
+                server,
+                boot_id,
+                config_id,
+            } => {
+                write!(f, "NOTIFY * HTTP/1.1\r\n")?;
+                write!(f, "HOST: {}\r\n", host)?;
+                write!(f, "NT: {}\r\n", nt)?;
+                write!(f, "NTS: ssdp:alive\r\n")?;
+                write!(f, "LOCATION: {}\r\n", location)?;
+                write!(f, "USN: {}\r\n", usn)?;
+                write!(f, "CACHE-CONTROL: max-age={}\r\n", max_age)?;
+                write!(f, "SERVER: {}\r\n", server)?;
+                write!(f, "BOOTID.UPNP.ORG: {}\r\n", boot_id)?;
+                write!(f, "CONFIGID.UPNP.ORG: {}\r\n", config_id)?;
+            }
+            SsdpMessage::ByeBye {
+                host,
+                nt,
+                usn,
+                boot_id,
+                config_id,
+            } => {
+                write!(f, "NOTIFY * HTTP/1.1\r\n")?;
+                write!(f, "HOST: {}\r\n", host)?;
+                write!(f, "NT: {}\r\n", nt)?;
+                write!(f, "NTS: ssdp:byebye\r\n")?;
+                write!(f, "USN: {}\r\n", usn)?;
+                write!(f, "BOOTID.UPNP.ORG: {}\r\n", boot_id)?;
+                write!(f, "CONFIGID.UPNP.ORG: {}\r\n", config_id)?;
+            }
+            SsdpMessage::SearchResponse {
+                date,
+                location,
+                server,
+                st,
+                usn,
+                max_age,
+                boot_id,
+                config_id,
+            } => {
+                write!(f, "HTTP/1.1 200 OK\r\n")?;
+                write!(f, "CACHE-CONTROL: max-age={}\r\n", max_age)?;
+                write!(f, "DATE: {}\r\n", http_date(date))?;
+                write!(f, "EXT:\r\n")?;
+                write!(f, "LOCATION: {}\r\n", location)?;
+                write!(f, "SERVER: {}\r\n", server)?;
+                write!(f, "ST: {}\r\n", st)?;
+                write!(f, "USN: {}\r\n", usn)?;
+                write!(f, "BOOTID.UPNP.ORG: {}\r\n", boot_id)?;
+                write!(f, "CONFIGID.UPNP.ORG: {}\r\n", config_id)?;
+            }
+        }
+        write!(f, "\r\n")
+    }
+}
+
+/// An `M-SEARCH` request received from a control point.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SearchRequest {
+    pub st: String,
+    pub mx: Option<u32>,
+}
+
+impl SearchRequest {
+    pub fn parse(data: &[u8]) -> Option<SearchRequest> {
+        let message = String::from_utf8_lossy(data);
+        let mut lines = message.split("\r\n");
+        if !lines.next()?.trim().starts_with("M-SEARCH * HTTP/1.1") {
+            return None;
+        }
+
+        let mut st = None;
+        let mut man = None;
+        let mut mx = None;
+        for line in lines.take_while(|x| !x.is_empty()) {
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next()?.trim().to_ascii_uppercase();
+            let value = parts.next().unwrap_or("").trim();
+            match name.as_str() {
+                "ST" => st = Some(value.to_string()),
+                "MAN" => man = Some(value.trim_matches('"').to_string()),
+                "MX" => mx = value.parse().ok(),
+                _ => {}
+            }
+        }
+
+        if man.as_deref() != Some("ssdp:discover") {
+            return None;
+        }
+        Some(SearchRequest { st: st?, mx })
+    }
+
+    /// How long to wait before answering, `random` picking a point within MX so that
+    /// devices answering one multicast search do not all reply at once.
+    pub fn response_delay(&self, random: u32) -> Duration {
+        let mx = self.mx.unwrap_or(0).min(MAX_MX) as u64;
+        Duration::from_millis(random as u64 % (mx * 1000 + 1))
+    }
+
+    /// Whether a device or service advertising `nt` should answer this search.
+    pub fn matches(&self, nt: &str) -> bool {
+        self.st == SSDP_ALL || self.st == nt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    const UUID: &str = "3f2a7c1e-5b8d-4e6f-9a0b-1c2d3e4f5a6b";
+    const SERVER: &str = "linux/6.1.0 UPnP/1.1 agni/0.1.0";
+    const LOCATION: &str = "http://192.168.1.10:3030/agni/root.xml";
+    const MEDIA_SERVER: &str = "urn:schemas-upnp-org:device:MediaServer:1";
+
+    fn device() -> Device {
+        Device {
+            uuid: Uuid::parse_str(UUID).unwrap(),
+            boot_id: 7,
+            config_id: 1234567,
+        }
+    }
+
+    fn host() -> SocketAddr {
+        "239.255.255.250:1900".parse().unwrap()
+    }
+
+    fn usn() -> String {
+        format!("uuid:{}::{}", UUID, MEDIA_SERVER)
+    }
+
+    /// The message with the host-dependent SERVER token and the DATE fixed.
+    fn fixed(mut message: SsdpMessage) -> SsdpMessage {
+        match &mut message {
+            SsdpMessage::Alive { server, .. } => *server = SERVER.to_string(),
+            SsdpMessage::ByeBye { .. } => {}
+            SsdpMessage::SearchResponse { date, server, .. } => {
+                *date = Utc.ymd(2021, 3, 4).and_hms(5, 6, 7);
+                *server = SERVER.to_string();
+            }
+        }
+        message
+    }
+
+    #[test]
+    fn alive() {
+        let message = fixed(SsdpMessage::alive(
+            &device(),
+            host(),
+            MEDIA_SERVER,
+            &usn(),
+            LOCATION,
+        ));
+        assert_eq!(
+            message.to_string(),
+            "NOTIFY * HTTP/1.1\r\n\
+             HOST: 239.255.255.250:1900\r\n\
+             NT: urn:schemas-upnp-org:device:MediaServer:1\r\n\
+             NTS: ssdp:alive\r\n\
+             LOCATION: http://192.168.1.10:3030/agni/root.xml\r\n\
+             USN: uuid:3f2a7c1e-5b8d-4e6f-9a0b-1c2d3e4f5a6b::urn:schemas-upnp-org:device:MediaServer:1\r\n\
+             CACHE-CONTROL: max-age=1800\r\n\
+             SERVER: linux/6.1.0 UPnP/1.1 agni/0.1.0\r\n\
+             BOOTID.UPNP.ORG: 7\r\n\
+             CONFIGID.UPNP.ORG: 1234567\r\n\
+             \r\n"
+        );
+    }
+
+    #[test]
+    fn alive_v6() {
+        let host = "[ff02::c]:1900".parse().unwrap();
+        let message = SsdpMessage::alive(&device(), host, MEDIA_SERVER, &usn(), LOCATION);
+        assert!(message.to_string().contains("\r\nHOST: [ff02::c]:1900\r\n"));
+    }
+
+    #[test]
+    fn byebye() {
+        let message = fixed(SsdpMessage::byebye(&device(), host(), MEDIA_SERVER, &usn()));
+        assert_eq!(
+            message.to_string(),
+            "NOTIFY * HTTP/1.1\r\n\
+             HOST: 239.255.255.250:1900\r\n\
+             NT: urn:schemas-upnp-org:device:MediaServer:1\r\n\
+             NTS: ssdp:byebye\r\n\
+             USN: uuid:3f2a7c1e-5b8d-4e6f-9a0b-1c2d3e4f5a6b::urn:schemas-upnp-org:device:MediaServer:1\r\n\
+             BOOTID.UPNP.ORG: 7\r\n\
+             CONFIGID.UPNP.ORG: 1234567\r\n\
+             \r\n"
+        );
+    }
+
+    #[test]
+    fn search_response() {
+        let message = fixed(SsdpMessage::search_response(
+            &device(),
+            MEDIA_SERVER,
+            &usn(),
+            LOCATION,
+        ));
+        assert_eq!(
+            message.to_bytes(),
+            b"HTTP/1.1 200 OK\r\n\
+              CACHE-CONTROL: max-age=1800\r\n\
+              DATE: Thu, 04 Mar 2021 05:06:07 GMT\r\n\
+              EXT:\r\n\
+              LOCATION: http://192.168.1.10:3030/agni/root.xml\r\n\
+              SERVER: linux/6.1.0 UPnP/1.1 agni/0.1.0\r\n\
+              ST: urn:schemas-upnp-org:device:MediaServer:1\r\n\
+              USN: uuid:3f2a7c1e-5b8d-4e6f-9a0b-1c2d3e4f5a6b::urn:schemas-upnp-org:device:MediaServer:1\r\n\
+              BOOTID.UPNP.ORG: 7\r\n\
+              CONFIGID.UPNP.ORG: 1234567\r\n\
+              \r\n"
+        );
+    }
+
+    #[test]
+    fn server_token_format() {
+        let token = server_token();
+        let parts: Vec<&str> = token.split(' ').collect();
+        assert_eq!(parts.len(), 3);
+        assert!(parts[0].starts_with(std::env::consts::OS));
+        assert_eq!(parts[1], "UPnP/1.1");
+        assert_eq!(parts[2], format!("agni/{}", env!("CARGO_PKG_VERSION")));
+    }
+
+    #[test]
+    fn config_id_range() {
+        assert!(config_id(&["<root/>", "<scpd/>"]) <= 0x00ff_ffff);
+        assert_eq!(config_id(&["a"]), config_id(&["a"]));
+        assert_ne!(config_id(&["a"]), config_id(&["b"]));
+    }
+
+    fn search(st: &str, mx: Option<&str>) -> Vec<u8> {
+        let mut request = format!(
+            "M-SEARCH * HTTP/1.1\r\nHOST: 239.255.255.250:1900\r\nMAN: \"ssdp:discover\"\r\nST: {}\r\n",
+            st
+        );
+        if let Some(mx) = mx {
+            request.push_str(&format!("MX: {}\r\n", mx));
+        }
+        request.push_str("\r\n");
+        request.into_bytes()
+    }
+
+    #[test]
+    fn search_all() {
+        let request = SearchRequest::parse(&search(SSDP_ALL, None)).unwrap();
+        assert_eq!(
+            request,
+            SearchRequest {
+                st: SSDP_ALL.to_string(),
+                mx: None
+            }
+        );
+        assert!(request.matches("upnp:rootdevice"));
+        assert!(request.matches(&format!("uuid:{}", UUID)));
+        assert!(request.matches(MEDIA_SERVER));
+    }
+
+    #[test]
+    fn search_root_device() {
+        let request = SearchRequest::parse(&search("upnp:rootdevice", None)).unwrap();
+        assert!(request.matches("upnp:rootdevice"));
+        assert!(!request.matches(MEDIA_SERVER));
+    }
+
+    #[test]
+    fn search_uuid() {
+        let uuid = format!("uuid:{}", UUID);
+        let request = SearchRequest::parse(&search(&uuid, None)).unwrap();
+        assert!(request.matches(&uuid));
+        assert!(!request.matches("upnp:rootdevice"));
+        assert!(!request.matches("uuid:00000000-0000-0000-0000-000000000000"));
+    }
+
+    #[test]
+    fn search_type_with_mx() {
+        let request = SearchRequest::parse(&search(MEDIA_SERVER, Some("3"))).unwrap();
+        assert_eq!(
+            request,
+            SearchRequest {
+                st: MEDIA_SERVER.to_string(),
+                mx: Some(3)
+            }
+        );
+        assert!(request.matches(MEDIA_SERVER));
+        assert!(!request.matches("urn:schemas-upnp-org:service:ContentDirectory:1"));
+    }
+
+    #[test]
+    fn response_delay_within_mx() {
+        let request = |mx| SearchRequest {
+            st: SSDP_ALL.to_string(),
+            mx,
+        };
+        assert_eq!(request(None).response_delay(u32::MAX), Duration::ZERO);
+        assert_eq!(request(Some(0)).response_delay(u32::MAX), Duration::ZERO);
+        assert_eq!(request(Some(2)).response_delay(0), Duration::ZERO);
+        assert_eq!(
+            request(Some(2)).response_delay(1500),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(request(Some(2)).response_delay(2001), Duration::ZERO);
+        for random in (0..u32::MAX).step_by(9973) {
+            assert!(request(Some(120)).response_delay(random) <= Duration::from_secs(5));
+        }
+    }
+
+    #[test]
+    fn search_headers_ignore_case() {
+        let request =
+            b"M-SEARCH * HTTP/1.1\r\nman: \"ssdp:discover\"\r\nst: ssdp:all\r\nmx: 2\r\n\r\n";
+        assert_eq!(
+            SearchRequest::parse(request),
+            Some(SearchRequest {
+                st: SSDP_ALL.to_string(),
+                mx: Some(2)
+            })
+        );
+    }
+
+    #[test]
+    fn search_rejects_other_messages() {
+        let notify = SsdpMessage::byebye(&device(), host(), MEDIA_SERVER, &usn()).to_bytes();
+        assert_eq!(SearchRequest::parse(&notify), None);
+        let without_man = b"M-SEARCH * HTTP/1.1\r\nST: ssdp:all\r\n\r\n";
+        assert_eq!(SearchRequest::parse(without_man), None);
+        let without_st = b"M-SEARCH * HTTP/1.1\r\nMAN: \"ssdp:discover\"\r\n\r\n";
+        assert_eq!(SearchRequest::parse(without_st), None);
+    }
+}
@@ -0,0 +1,299 @@
+use actix_web::web::Bytes;
+use futures::stream::{self, Stream};
+use regex::{Regex, RegexBuilder};
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::media_types::{media_type_for, VIDEO_ITEM};
+use crate::util::{file_size, title_for};
+
+const CHUNK_SIZE: u64 = 64 * 1024;
+const VIDEO_TS: &str = "VIDEO_TS";
+const BDMV: &str = "BDMV";
+
+/// A movie split over several files, such as `Movie.cd1.mkv` and `Movie.cd2.mkv`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stack {
+    /// The name the parts share, without the part number.
+    pub title: String,
+    /// In playing order.
+    pub parts: Vec<PathBuf>,
+}
+
+fn stack_pattern() -> &'static Regex {
+    static PATTERN: OnceLock<Regex> = OnceLock::new();
+    PATTERN.get_or_init(|| {
+        // Movie.cd1, Movie - Part 2, Movie_disc3; the marker starts a word, so that
+        // `Script 1` is not part 1 of "Scri".
+        RegexBuilder::new(r"^(.*?)(?:^|[ ._-]+)(?:cd|dvd|part|pt|disc|disk)[ ._-]*(\d{1,2})(.*)$")
+            .case_insensitive(true)
+            .build()
+            .unwrap()
+    })
+}
+
+/// The videos among `files` that are parts of one movie. Parts belong together when
+/// their names only differ in the part number; a lone part is not a stack.
+pub fn find_stacks(files: &[PathBuf]) -> Vec<Stack> {
+    // Keyed by what surrounds the part number, joined by a `/` no file name holds.
+    let mut groups: BTreeMap<String, Vec<(u32, &PathBuf)>> = BTreeMap::new();
+    let videos = files
+        .iter()
+        .filter(|x| media_type_for(x).is_some_and(|x| x.class == VIDEO_ITEM));
+    for file in videos {
+        let (stem, extension) = match (file.file_stem(), file.extension()) {
+            (Some(stem), Some(extension)) => (stem.to_string_lossy(), extension.to_string_lossy()),
+            _ => continue,
+        };
+        let captures = match stack_pattern().captures(&stem) {
+            Some(x) => x,
+            None => continue,
+        };
+        let part = match captures[2].parse() {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let key = format!("{}/{}/{}", &captures[1], &captures[3], extension).to_lowercase();
+        groups.entry(key).or_default().push((part, file));
+    }
+    groups
+        .into_values()
+        .filter(|x| x.len() > 1)
+        .map(|mut parts| {
+            parts.sort();
+            let stem = parts[0].1.file_stem().unwrap_or_default().to_string_lossy();
+            let prefix = stack_pattern()
+                .captures(&stem)
+                .map(|x| x[1].trim_end_matches([' ', '.', '_', '-']).to_string())
+                .filter(|x| !x.is_empty());
+            Stack {
+                title: match prefix {
+                    Some(x) => title_for(OsStr::new(&x)),
+                    None => title_for(parts[0].1.file_name().unwrap_or_default()),
+                },
+                parts: parts.into_iter().map(|(_, x)| x.clone()).collect(),
+            }
+        })
+        .collect()
+}
+
+/// The VOBs of the longest title set on a DVD, `VTS_01_1.VOB` onwards. `VTS_01_0.VOB`
+/// holds the menu and is left out.
+fn dvd_title(video_ts: &Path) -> Option<Vec<PathBuf>> {
+    let mut title_sets: BTreeMap<String, Vec<(u32, PathBuf)>> = BTreeMap::new();
+    for entry in fs::read_dir(video_ts).ok()?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_uppercase();
+        let (title_set, part) = match name
+            .strip_prefix("VTS_")
+            .and_then(|x| x.strip_suffix(".VOB"))
+            .and_then(|x| x.split_once('_'))
+        {
+            Some(x) => x,
+            None => continue,
+        };
+        match part.parse() {
+            Ok(part) if part > 0 => title_sets
+                .entry(title_set.to_string())
+                .or_default()
+                .push((part, entry.path())),
+            _ => continue,
+        }
+    }
+    let mut parts = title_sets
+        .into_values()
+        .max_by_key(|x| x.iter().map(|(_, path)| file_size(path)).sum::<u64>())?;
+    parts.sort();
+    Some(parts.into_iter().map(|(_, x)| x).collect())
+}
+
+/// The largest stream of a Blu-ray, which is nearly always the main movie.
+fn bluray_title(bdmv: &Path) -> Option<Vec<PathBuf>> {
+    fs::read_dir(bdmv.join("STREAM"))
+        .ok()?
+        .flatten()
+        .map(|x| x.path())
+        .filter(|x| {
+            x.extension()
+                .is_some_and(|x| x.eq_ignore_ascii_case("m2ts"))
+        })
+        .max_by_key(|x| file_size(x))
+        .map(|x| vec![x])
+}
+
+/// The files to play, in order, when `dir` holds a DVD (`VIDEO_TS`) or Blu-ray
+/// (`BDMV`) copy.
+pub fn disc_parts(dir: &Path) -> Option<Vec<PathBuf>> {
+    let video_ts = dir.join(VIDEO_TS);
+    if video_ts.is_dir() {
+        return dvd_title(&video_ts);
+    }
+    let bdmv = dir.join(BDMV);
+    if bdmv.is_dir() {
+        return bluray_title(&bdmv);
+    }
+    None
+}
+
+/// Streams bytes `start..end` of `parts`, given as path and length, as if they were
+/// one file.
+pub fn concatenated_stream(
+    parts: Vec<(PathBuf, u64)>,
+    start: u64,
+    end: u64,
+) -> impl Stream<Item = io::Result<Bytes>> + Unpin {
+    let parts = Arc::new(parts);
+    // The part being read with where it ends in the whole, and the position reached.
+    let state: (Option<(File, u64)>, u64) = (None, start);
+    Box::pin(stream::unfold(Some(state), move |state| {
+        let parts = parts.clone();
+        async move {
+            let (file, position) = state?;
+            if position >= end {
+                return None;
+            }
+            let (mut file, part_end) = match file {
+                Some(x) => x,
+                None => {
+                    let mut part_start = 0;
+                    let (path, size) = parts.iter().find(|(_, size)| {
+                        part_start += size;
+                        position < part_start
+                    })?;
+                    let part_start = part_start - size;
+                    let opened = async {
+                        let mut file = File::open(path).await?;
+                        file.seek(SeekFrom::Start(position - part_start)).await?;
+                        Ok::<_, io::Error>(file)
+                    };
+                    match opened.await {
+                        Ok(x) => (x, part_start + size),
+                        Err(e) => return Some((Err(e), None)),
+                    }
+                }
+            };
+            let mut buf = vec![0; CHUNK_SIZE.min(end.min(part_end) - position) as usize];
+            match file.read(&mut buf).await {
+                Ok(0) => Some((
+                    Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "part ended early",
+                    )),
+                    None,
+                )),
+                Ok(n) => {
+                    buf.truncate(n);
+                    let position = position + n as u64;
+                    // Once a part is done, the next one is opened.
+                    let file = Some((file, part_end)).filter(|_| position < part_end);
+                    Some((Ok(Bytes::from(buf)), Some((file, position))))
+                }
+                Err(e) => Some((Err(e), None)),
+            }
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn stacks(names: &[&str]) -> Vec<(String, Vec<String>)> {
+        let files: Vec<PathBuf> = names.iter().map(|x| Path::new("/films").join(x)).collect();
+        find_stacks(&files)
+            .into_iter()
+            .map(|x| {
+                let parts = x
+                    .parts
+                    .iter()
+                    .map(|x| x.file_name().unwrap().to_string_lossy().into_owned())
+                    .collect();
+                (x.title, parts)
+            })
+            .collect()
+    }
+
+    fn stack(title: &str, parts: &[&str]) -> (String, Vec<String>) {
+        (
+            title.to_string(),
+            parts.iter().map(|x| x.to_string()).collect(),
+        )
+    }
+
+    #[test]
+    fn stacks_parts_in_order() {
+        assert_eq!(
+            stacks(&["Heat.cd2.mkv", "Heat.cd1.mkv", "Heat.nfo"]),
+            [stack("Heat", &["Heat.cd1.mkv", "Heat.cd2.mkv"])]
+        );
+        assert_eq!(
+            stacks(&["Ran - Part 10.avi", "Ran - Part 2.avi", "Ran - Part 1.avi"]),
+            [stack(
+                "Ran",
+                &["Ran - Part 1.avi", "Ran - Part 2.avi", "Ran - Part 10.avi"]
+            )]
+        );
+        assert_eq!(
+            stacks(&["Dune_disc1_x264.mkv", "Dune_DISC2_x264.mkv"]),
+            [stack(
+                "Dune",
+                &["Dune_disc1_x264.mkv", "Dune_DISC2_x264.mkv"]
+            )]
+        );
+        assert_eq!(
+            stacks(&["pt1.mkv", "pt2.mkv"]),
+            [stack("pt1.mkv", &["pt1.mkv", "pt2.mkv"])]
+        );
+    }
+
+    #[test]
+    fn not_stacks() {
+        let none: Vec<(String, Vec<String>)> = vec![];
+        assert_eq!(stacks(&["Script 1.mkv", "Script 2.mkv"]), none);
+        assert_eq!(stacks(&["Apt 1.mkv", "Apt 2.mkv"]), none);
+        assert_eq!(stacks(&["Abscd1.mkv", "Abscd2.mkv"]), none);
+        assert_eq!(stacks(&["Heat.cd1.mkv"]), none);
+        assert_eq!(stacks(&["Heat.cd1.mkv", "Heat.cd2.avi"]), none);
+        assert_eq!(stacks(&["Heat.cd1.mkv", "Ronin.cd2.mkv"]), none);
+        assert_eq!(stacks(&["Heat.cd1.srt", "Heat.cd2.srt"]), none);
+    }
+
+    #[tokio::test]
+    async fn concatenates_parts() {
+        let dir = std::env::temp_dir().join(format!("agni-stacks-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let parts: Vec<(PathBuf, u64)> = [&b"abc"[..], b"", b"defgh"]
+            .iter()
+            .enumerate()
+            .map(|(i, contents)| {
+                let path = dir.join(format!("part{}", i));
+                fs::write(&path, contents).unwrap();
+                (path, contents.len() as u64)
+            })
+            .collect();
+        let read = |start, end| {
+            let parts = parts.clone();
+            async move {
+                let chunks: Vec<_> = concatenated_stream(parts, start, end).collect().await;
+                chunks
+                    .into_iter()
+                    .map(|x| x.unwrap())
+                    .fold(vec![], |mut all, x| {
+                        all.extend_from_slice(&x);
+                        all
+                    })
+            }
+        };
+        assert_eq!(read(0, 8).await, b"abcdefgh");
+        assert_eq!(read(2, 5).await, b"cde");
+        assert_eq!(read(4, 6).await, b"ef");
+        assert_eq!(read(8, 8).await, b"");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,606 @@
+use actix_web::web::Bytes;
+use futures::stream::{self, Stream};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+use crate::config::Station;
+use crate::library::{Library, Metadata, Object};
+use crate::media_types::{media_type_for, AUDIO_BROADCAST, VIDEO_BROADCAST, VIDEO_ITEM};
+use crate::playlists;
+use crate::types::ListItem;
+
+/// The static "Radio" container, numbered after "TV Shows".
+pub const RADIO_ID: u64 = 6;
+pub const RADIO_TITLE: &str = "Radio";
+/// Stations from the config are numbered from here, below the index; only as many as
+/// fit before `FIRST_OBJECT_ID` are listed.
+const FIRST_STATION_ID: u64 = 100;
+const MAX_STATIONS: usize = 900;
+const STREAM_LINK_EXTENSIONS: &[&str] = &["strm", "url"];
+const MAX_REDIRECTS: usize = 5;
+/// Longest response head accepted from a stream server.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+/// How long a stream server may take to accept the connection, and then to send the
+/// response head.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const HEAD_TIMEOUT: Duration = Duration::from_secs(10);
+const CHUNK_SIZE: usize = 16 * 1024;
+
+pub fn is_stream_link(path: &Path) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| STREAM_LINK_EXTENSIONS.contains(&x.to_lowercase().as_str()))
+}
+
+fn is_http(url: &str) -> bool {
+    let url = url.to_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// The URL in a Kodi `.strm` file (its first line that is not a comment) or a Windows
+/// `.url` shortcut (its `URL=` line). Only HTTP URLs are taken.
+pub fn read_stream_link(path: &Path) -> io::Result<Option<String>> {
+    let contents = fs::read_to_string(path)?;
+    let contents = contents.trim_start_matches('\u{feff}');
+    let is_shortcut = path
+        .extension()
+        .is_some_and(|x| x.eq_ignore_ascii_case("url"));
+    let url = contents
+        .lines()
+        .map(str::trim)
+        .find_map(|x| match is_shortcut {
+            true => x
+                .get(..4)
+                .filter(|key| key.eq_ignore_ascii_case("url="))
+                .map(|_| &x[4..]),
+            false => Some(x).filter(|x| !x.is_empty() && !x.starts_with('#')),
+        });
+    Ok(url.filter(|x| is_http(x)).map(str::to_string))
+}
+
+/// Video streams by their extension, everything else is taken for radio.
+/// A remote stream only tells its type by its extension, if at all, until it is opened.
+pub fn url_mime(url: &str) -> Option<&'static str> {
+    media_type_for(url.split(['?', '#']).next().unwrap_or(url)).map(|x| x.mime)
+}
+
+pub fn broadcast_class(url: &str) -> &'static str {
+    match media_type_for(url.split(['?', '#']).next().unwrap_or(url)) {
+        Some(x) if x.class == VIDEO_ITEM => VIDEO_BROADCAST,
+        _ => AUDIO_BROADCAST,
+    }
+}
+
+fn station_object(id: u64, station: &Station) -> Object {
+    Object {
+        id,
+        parent_id: RADIO_ID,
+        path: PathBuf::new(),
+        title: station.title.clone(),
+        class: broadcast_class(&station.url).to_string(),
+        size: 0,
+        modified: 0,
+        metadata: Metadata {
+            url: Some(station.url.clone()),
+            ..Metadata::default()
+        },
+        update_id: 0,
+    }
+}
+
+/// The stations in the config, in their order.
+pub fn stations(stations: &[Station]) -> Vec<Object> {
+    stations
+        .iter()
+        .take(MAX_STATIONS)
+        .zip(FIRST_STATION_ID..)
+        .map(|(station, id)| station_object(id, station))
+        .collect()
+}
+
+pub fn station(stations: &[Station], id: u64) -> Option<Object> {
+    let index = id.wrapping_sub(FIRST_STATION_ID) as usize;
+    stations
+        .get(index)
+        .filter(|_| index < MAX_STATIONS)
+        .map(|x| station_object(id, x))
+}
+
+/// An item that is served by proxying a remote stream: a station, a URL in a playlist
+/// or a `.strm`/`.url` file.
+pub fn stream_object(
+    library: &Library,
+    stations: &[Station],
+    id: u64,
+) -> rusqlite::Result<Option<Object>> {
+    if let Some(x) = station(stations, id) {
+        return Ok(Some(x));
+    }
+    if let Some(x) = playlists::entry(library, id)? {
+        return Ok(Some(x));
+    }
+    Ok(library.get(id)?.filter(|x| x.metadata.url.is_some()))
+}
+
+/// What the streams being proxied say they are playing, by object ID, from their
+/// ICY `StreamTitle`.
+#[derive(Debug, Default)]
+pub struct StreamTitles {
+    titles: Mutex<HashMap<u64, String>>,
+}
+
+impl StreamTitles {
+    /// Whether `title` is news for the stream of `id`.
+    pub fn set(&self, id: u64, title: String) -> bool {
+        let mut titles = self.titles.lock().unwrap();
+        if titles.get(&id) == Some(&title) {
+            return false;
+        }
+        titles.insert(id, title);
+        true
+    }
+
+    /// Puts what the stream of `id` last played in the item's `dc:description`.
+    pub fn apply(&self, list_item: &mut ListItem, id: u64) {
+        if let ListItem::Item(item) = list_item {
+            if let Some(title) = self.titles.lock().unwrap().get(&id) {
+                item.description = Some(title.clone());
+            }
+        }
+    }
+}
+
+/// Cuts the ICY metadata blocks out of a stream sent with `icy-metaint`, collecting
+/// the `StreamTitle`s they carry.
+#[derive(Debug)]
+struct IcyParser {
+    interval: usize,
+    /// Audio bytes left before the next block.
+    audio_left: usize,
+    /// The block being read and how long it will be, once its length byte is known.
+    block: Option<(Vec<u8>, usize)>,
+}
+
+impl IcyParser {
+    fn new(interval: usize) -> Self {
+        IcyParser {
+            interval,
+            audio_left: interval,
+            block: None,
+        }
+    }
+
+    /// Splits `data` into audio, appended to `audio`, and the titles of the blocks
+    /// that ended in it.
+    fn feed(&mut self, mut data: &[u8], audio: &mut Vec<u8>) -> Vec<String> {
+        let mut titles = vec![];
+        while !data.is_empty() {
+            if self.audio_left > 0 {
+                let n = self.audio_left.min(data.len());
+                audio.extend_from_slice(&data[..n]);
+                self.audio_left -= n;
+                data = &data[n..];
+                continue;
+            }
+            let (block, length) = match &mut self.block {
+                Some(x) => x,
+                None => {
+                    // The length byte counts 16-byte units.
+                    self.block = Some((vec![], data[0] as usize * 16));
+                    data = &data[1..];
+                    self.block.as_mut().unwrap()
+                }
+            };
+            let n = (*length - block.len()).min(data.len());
+            block.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if block.len() == *length {
+                titles.extend(stream_title(block));
+                self.block = None;
+                self.audio_left = self.interval;
+            }
+        }
+        titles
+    }
+}
+
+/// The title in a metadata block such as `StreamTitle='Artist - Song';StreamUrl='';`.
+fn stream_title(block: &[u8]) -> Option<String> {
+    let block = String::from_utf8_lossy(block);
+    let block = block.trim_end_matches('\0');
+    let start = block.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &block[start..];
+    let end = rest.find("';").unwrap_or(rest.len());
+    Some(rest[..end].trim().to_string()).filter(|x| !x.is_empty())
+}
+
+/// An open stream, with the bytes read past the response head.
+pub struct RemoteStream {
+    connection: TcpStream,
+    buffered: Vec<u8>,
+    pub content_type: Option<String>,
+    metadata_interval: Option<usize>,
+}
+
+pub enum Opened {
+    Stream(RemoteStream),
+    /// Leads somewhere agni cannot follow, such as HTTPS.
+    Redirect(String),
+}
+
+/// Splits `http://host:port/path` into what to connect to, the `Host` header and the
+/// path.
+fn split_url(url: &str) -> Option<(String, String, String)> {
+    let scheme = url.get(..7).filter(|x| x.eq_ignore_ascii_case("http://"))?;
+    let rest = &url[scheme.len()..];
+    let (authority, path) = match rest.find(['/', '?']) {
+        Some(i) if rest[i..].starts_with('/') => (&rest[..i], rest[i..].to_string()),
+        Some(i) => (&rest[..i], format!("/{}", &rest[i..])),
+        None => (rest, "/".to_string()),
+    };
+    let path = path.split('#').next().unwrap_or_default().to_string();
+    if authority.is_empty() {
+        return None;
+    }
+    let has_port = authority
+        .rfind(':')
+        .is_some_and(|i| !authority[i..].contains(']'));
+    let address = match has_port {
+        true => authority.to_string(),
+        false => format!("{}:80", authority),
+    };
+    Some((address, authority.to_string(), path))
+}
+
+/// Where the `Location` of a redirect from `url` leads: absolute URLs as they are,
+/// and references resolved against `url` like a browser would.
+fn resolve_location(url: &str, location: &str) -> String {
+    let has_scheme = location.split_once(':').is_some_and(|(scheme, _)| {
+        scheme.starts_with(|x: char| x.is_ascii_alphabetic())
+            && scheme
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || "+-.".contains(x))
+    });
+    if has_scheme {
+        return location.to_string();
+    }
+    if location.starts_with("//") {
+        return format!("http:{}", location);
+    }
+    let (_, host, base) = split_url(url).unwrap_or_default();
+    let (base_path, _) = base.split_once('?').unwrap_or((&base, ""));
+    let (path, query) = match location.find(['?', '#']) {
+        Some(i) => location.split_at(i),
+        None => (location, ""),
+    };
+    let merged = match path {
+        "" if query.starts_with('#') => format!("{}{}", base, query),
+        "" => format!("{}{}", base_path, query),
+        x if x.starts_with('/') => remove_dot_segments(x) + query,
+        x => {
+            let directory = &base_path[..base_path.rfind('/').map_or(0, |i| i + 1)];
+            remove_dot_segments(&format!("{}{}", directory, x)) + query
+        }
+    };
+    format!("http://{}{}", host, merged)
+}
+
+/// `path` with its `.` and `..` segments applied.
+fn remove_dot_segments(path: &str) -> String {
+    let mut segments: Vec<&str> = vec![];
+    let mut pieces = path.split('/').skip(1).peekable();
+    while let Some(piece) = pieces.next() {
+        let last = pieces.peek().is_none();
+        match piece {
+            "." | ".." => {
+                if piece == ".." {
+                    segments.pop();
+                }
+                // A path ending in a dot segment still names a directory.
+                if last {
+                    segments.push("");
+                }
+            }
+            x => segments.push(x),
+        }
+    }
+    format!("/{}", segments.join("/"))
+}
+
+/// Sends a GET for `url` and reads the response head. HTTP/1.0 is asked for so the
+/// body is never chunked; SHOUTcast answers `ICY 200 OK`, which is taken for 200.
+async fn get(url: &str) -> io::Result<(TcpStream, u16, Vec<(String, String)>, Vec<u8>)> {
+    let invalid = |x: &str| io::Error::new(io::ErrorKind::InvalidInput, x.to_string());
+    let timed_out = |x: &str| io::Error::new(io::ErrorKind::TimedOut, x.to_string());
+    let (address, host, path) = split_url(url).ok_or_else(|| invalid("not an HTTP URL"))?;
+    let mut connection = timeout(CONNECT_TIMEOUT, TcpStream::connect(address.as_str()))
+        .await
+        .map_err(|_| timed_out("connecting timed out"))??;
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nUser-Agent: agni\r\nIcy-MetaData: 1\r\nAccept: */*\r\n\r\n",
+        path, host
+    );
+    connection.write_all(request.as_bytes()).await?;
+
+    let mut received = vec![];
+    let read_head = async {
+        loop {
+            if let Some(i) = received.windows(4).position(|x| x == b"\r\n\r\n") {
+                return Ok(i + 4);
+            }
+            if received.len() > MAX_HEAD_SIZE {
+                return Err(invalid("response head too long"));
+            }
+            let mut buf = [0; 4096];
+            match connection.read(&mut buf).await? {
+                0 => return Err(invalid("connection closed before the response head")),
+                n => received.extend_from_slice(&buf[..n]),
+            }
+        }
+    };
+    let head_end = timeout(HEAD_TIMEOUT, read_head)
+        .await
+        .map_err(|_| timed_out("no response head in time"))??;
+    let head = String::from_utf8_lossy(&received[..head_end]).into_owned();
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|x| x.split_whitespace().nth(1))
+        .and_then(|x| x.parse().ok())
+        .ok_or_else(|| invalid("malformed status line"))?;
+    let headers = lines
+        .filter_map(|x| x.split_once(':'))
+        .map(|(name, value)| (name.trim().to_lowercase(), value.trim().to_string()))
+        .collect();
+    Ok((connection, status, headers, received.split_off(head_end)))
+}
+
+/// Connects to the stream at `url`, following redirects.
+pub async fn open(url: &str) -> io::Result<Opened> {
+    let mut url = url.to_string();
+    for _ in 0..=MAX_REDIRECTS {
+        if !url.to_lowercase().starts_with("http://") {
+            return Ok(Opened::Redirect(url));
+        }
+        let (connection, status, headers, buffered) = get(&url).await?;
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(x, _)| x == name)
+                .map(|(_, value)| value.clone())
+        };
+        match status {
+            200..=299 => {
+                return Ok(Opened::Stream(RemoteStream {
+                    connection,
+                    buffered,
+                    content_type: header("content-type"),
+                    metadata_interval: header("icy-metaint")
+                        .and_then(|x| x.parse().ok())
+                        .filter(|&x| x > 0),
+                }))
+            }
+            301 | 302 | 303 | 307 | 308 => {
+                let location = header("location").ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "redirect without Location")
+                })?;
+                url = resolve_location(&url, &location);
+            }
+            x => {
+                return Err(io::Error::other(format!("stream server answered {}", x)));
+            }
+        }
+    }
+    Err(io::Error::other("too many redirects"))
+}
+
+impl RemoteStream {
+    /// The audio of the stream, with `on_title` called for each title it announces.
+    pub fn into_body<F: Fn(String) + 'static>(
+        self,
+        on_title: F,
+    ) -> impl Stream<Item = io::Result<Bytes>> + Unpin {
+        let parser = self.metadata_interval.map(IcyParser::new);
+        let state = (self.connection, parser, Some(self.buffered), on_title);
+        Box::pin(stream::unfold(Some(state), |state| async move {
+            let (mut connection, mut parser, buffered, on_title) = state?;
+            let data = match buffered.filter(|x| !x.is_empty()) {
+                Some(x) => x,
+                None => {
+                    let mut buf = vec![0; CHUNK_SIZE];
+                    match connection.read(&mut buf).await {
+                        Ok(0) => return None,
+                        Ok(n) => {
+                            buf.truncate(n);
+                            buf
+                        }
+                        Err(e) => return Some((Err(e), None)),
+                    }
+                }
+            };
+            let audio = match &mut parser {
+                Some(parser) => {
+                    let mut audio = vec![];
+                    for title in parser.feed(&data, &mut audio) {
+                        on_title(title);
+                    }
+                    audio
+                }
+                None => data,
+            };
+            Some((
+                Ok(Bytes::from(audio)),
+                Some((connection, parser, None, on_title)),
+            ))
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::sync::Arc;
+    use tokio::net::TcpListener;
+    use tokio::time::delay_for;
+
+    const METAINT: usize = 10;
+
+    /// A metadata block: its length byte, then `text` padded to 16-byte units.
+    fn metadata_block(text: &str) -> Vec<u8> {
+        let units = text.len().div_ceil(16);
+        let mut block = vec![units as u8];
+        block.extend_from_slice(text.as_bytes());
+        block.resize(1 + units * 16, 0);
+        block
+    }
+
+    /// The body of an ICY stream and the audio in it, with blocks that announce a
+    /// title, announce nothing, and carry more than the title.
+    fn icy_body() -> (Vec<u8>, Vec<u8>) {
+        let blocks = [
+            metadata_block("StreamTitle='A - One';"),
+            vec![0],
+            metadata_block("StreamTitle='B - Two';StreamUrl='http://x/';"),
+        ];
+        let (mut body, mut audio) = (vec![], vec![]);
+        for (i, block) in blocks.iter().enumerate() {
+            let chunk: Vec<u8> = (0..METAINT).map(|x| (i * METAINT + x) as u8).collect();
+            body.extend_from_slice(&chunk);
+            audio.extend_from_slice(&chunk);
+            body.extend_from_slice(block);
+        }
+        body.extend_from_slice(b"tail");
+        audio.extend_from_slice(b"tail");
+        (body, audio)
+    }
+
+    /// Serves `/start`, which redirects to an absolute path, that redirecting to a
+    /// relative one, which is the stream. The body is written a few bytes at a time so
+    /// that metadata blocks arrive split across reads. Returns the base URL and the
+    /// paths requested.
+    async fn icy_server(body: Vec<u8>) -> (String, Arc<Mutex<Vec<String>>>) {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base = format!("http://{}", listener.local_addr().unwrap());
+        let requested = Arc::new(Mutex::new(vec![]));
+        let paths = requested.clone();
+        tokio::spawn(async move {
+            loop {
+                let (mut connection, _) = listener.accept().await.unwrap();
+                let mut request = vec![];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut buf = [0; 1024];
+                    let n = connection.read(&mut buf).await.unwrap();
+                    request.extend_from_slice(&buf[..n]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let path = request.split_whitespace().nth(1).unwrap().to_string();
+                paths.lock().unwrap().push(path.clone());
+                let redirect = |location: &str| {
+                    format!("HTTP/1.0 302 Found\r\nLocation: {}\r\n\r\n", location).into_bytes()
+                };
+                match path.as_str() {
+                    "/start" => connection.write_all(&redirect("/moved/here")).await,
+                    "/moved/here" => connection.write_all(&redirect("radio/live?x=1")).await,
+                    _ => {
+                        let mut response = format!(
+                            "ICY 200 OK\r\ncontent-type: audio/mpeg\r\nicy-metaint: {}\r\n\r\n",
+                            METAINT
+                        )
+                        .into_bytes();
+                        // Some of the body comes with the head.
+                        response.extend_from_slice(&body[..5]);
+                        connection.write_all(&response).await.unwrap();
+                        for piece in body[5..].chunks(7) {
+                            delay_for(Duration::from_millis(5)).await;
+                            connection.write_all(piece).await.unwrap();
+                        }
+                        Ok(())
+                    }
+                }
+                .unwrap();
+            }
+        });
+        (base, requested)
+    }
+
+    #[tokio::test]
+    async fn follows_redirects_and_strips_icy_metadata() {
+        let (body, expected) = icy_body();
+        let (base, requested) = icy_server(body).await;
+        let stream = match open(&format!("{}/start", base)).await.unwrap() {
+            Opened::Stream(x) => x,
+            Opened::Redirect(x) => panic!("not followed: {}", x),
+        };
+        assert_eq!(stream.content_type.as_deref(), Some("audio/mpeg"));
+        assert_eq!(stream.metadata_interval, Some(METAINT));
+
+        let titles = Arc::new(Mutex::new(vec![]));
+        let announced = titles.clone();
+        let mut body = stream.into_body(move |x| announced.lock().unwrap().push(x));
+        let mut audio = vec![];
+        let mut reads = 0;
+        while let Some(chunk) = body.next().await {
+            audio.extend_from_slice(&chunk.unwrap());
+            reads += 1;
+        }
+        assert!(reads > 3, "the body arrived in {} reads", reads);
+        assert_eq!(audio, expected);
+        assert_eq!(*titles.lock().unwrap(), ["A - One", "B - Two"]);
+        assert_eq!(
+            *requested.lock().unwrap(),
+            ["/start", "/moved/here", "/moved/radio/live?x=1"]
+        );
+    }
+
+    #[test]
+    fn icy_blocks_split_anywhere() {
+        let (body, expected) = icy_body();
+        for split in 1..body.len() {
+            let mut parser = IcyParser::new(METAINT);
+            let mut audio = vec![];
+            let mut titles = parser.feed(&body[..split], &mut audio);
+            titles.extend(parser.feed(&body[split..], &mut audio));
+            assert_eq!(audio, expected, "split at {}", split);
+            assert_eq!(titles, ["A - One", "B - Two"], "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn resolves_locations() {
+        let base = "http://radio.example:8000/streams/live.mp3?sid=1";
+        let resolve = |x| resolve_location(base, x);
+        assert_eq!(resolve("http://other/x"), "http://other/x");
+        assert_eq!(resolve("HTTPS://other/x"), "HTTPS://other/x");
+        assert_eq!(resolve("//cdn.example/x"), "http://cdn.example/x");
+        assert_eq!(resolve("/root.mp3"), "http://radio.example:8000/root.mp3");
+        assert_eq!(
+            resolve("backup.mp3?sid=2"),
+            "http://radio.example:8000/streams/backup.mp3?sid=2"
+        );
+        assert_eq!(
+            resolve("../other/./a.mp3"),
+            "http://radio.example:8000/other/a.mp3"
+        );
+        assert_eq!(resolve("../../.."), "http://radio.example:8000/");
+        assert_eq!(
+            resolve("?sid=3"),
+            "http://radio.example:8000/streams/live.mp3?sid=3"
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_server_fails() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        drop(listener);
+        assert!(open(&format!("http://{}/", address)).await.is_err());
+    }
+}
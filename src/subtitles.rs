@@ -0,0 +1,136 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::util::path_bytes;
+
+/// Sidecar subtitle formats, as `(extension, mime)`.
+pub const SUBTITLE_TYPES: &[(&str, &str)] = &[
+    ("srt", "text/srt"),
+    ("ass", "text/x-ass"),
+    ("ssa", "text/x-ssa"),
+    ("vtt", "text/vtt"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Subtitle {
+    pub path: PathBuf,
+    pub extension: &'static str,
+    pub mime: &'static str,
+}
+
+pub fn subtitle_type_for(path: &Path) -> Option<(&'static str, &'static str)> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    SUBTITLE_TYPES
+        .iter()
+        .find(|(x, _)| *x == extension)
+        .copied()
+}
+
+/// The file stem of `path` as raw bytes, as names need not be UTF-8.
+fn stem(path: &Path) -> Option<&[u8]> {
+    path.file_stem().map(|x| path_bytes(Path::new(x)))
+}
+
+/// Subtitles among `siblings` that belong to `video`: `Movie.srt`, and also
+/// tagged variants such as `Movie.en.srt` or `Movie.forced.en.srt`.
+pub fn find_subtitles(video: &Path, siblings: &[PathBuf]) -> Vec<Subtitle> {
+    let video_stem = match stem(video) {
+        Some(x) => x,
+        None => return vec![],
+    };
+
+    let mut subtitles: Vec<Subtitle> = siblings
+        .iter()
+        .filter_map(|sibling| {
+            let (extension, mime) = subtitle_type_for(sibling)?;
+            let sibling_stem = stem(sibling)?;
+            let tagged = sibling_stem.get(video_stem.len()) == Some(&b'.');
+            if sibling_stem.starts_with(video_stem)
+                && (sibling_stem.len() == video_stem.len() || tagged)
+            {
+                Some(Subtitle {
+                    path: sibling.clone(),
+                    extension,
+                    mime,
+                })
+            } else {
+                None
+            }
+        })
+        .collect();
+    // The untagged file, if any, is the default track.
+    subtitles.sort_by_key(|x| (stem(&x.path) != Some(video_stem), x.path.clone()));
+    subtitles
+}
+
+/// Like [`find_subtitles`], listing the video's directory itself.
+pub fn find_subtitles_on_disk(video: &Path) -> Vec<Subtitle> {
+    let siblings: Vec<PathBuf> = video
+        .parent()
+        .and_then(|x| fs::read_dir(x).ok())
+        .map(|entries| entries.filter_map(|x| x.ok()).map(|x| x.path()).collect())
+        .unwrap_or_default();
+    find_subtitles(video, &siblings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(subtitles: &[Subtitle]) -> Vec<&str> {
+        subtitles
+            .iter()
+            .map(|x| x.path.file_name().unwrap().to_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn tagged_and_untagged_sidecars() {
+        let siblings: Vec<PathBuf> = [
+            "Movie.mkv",
+            "Movie.en.srt",
+            "Movie.srt",
+            "Movie.forced.en.SRT",
+            "Movie.nfo",
+            "Movie 2.srt",
+            "Movies.srt",
+            "Other.srt",
+        ]
+        .iter()
+        .map(|x| Path::new("/media").join(x))
+        .collect();
+        let subtitles = find_subtitles(Path::new("/media/Movie.mkv"), &siblings);
+        // The untagged file comes first as the default track.
+        assert_eq!(
+            names(&subtitles),
+            ["Movie.srt", "Movie.en.srt", "Movie.forced.en.SRT"]
+        );
+        assert_eq!(subtitles[2].extension, "srt");
+        assert_eq!(subtitles[2].mime, "text/srt");
+    }
+
+    #[test]
+    fn stems_with_dots() {
+        let siblings = vec![
+            PathBuf::from("/media/Mr. Robot.S01E01.srt"),
+            PathBuf::from("/media/Mr. Robot.S01E01.de.vtt"),
+            PathBuf::from("/media/Mr. Robot.S01E02.srt"),
+        ];
+        let subtitles = find_subtitles(Path::new("/media/Mr. Robot.S01E01.mkv"), &siblings);
+        assert_eq!(
+            names(&subtitles),
+            ["Mr. Robot.S01E01.srt", "Mr. Robot.S01E01.de.vtt"]
+        );
+        assert_eq!(subtitles[1].mime, "text/vtt");
+    }
+
+    #[test]
+    fn subtitle_types() {
+        assert_eq!(
+            subtitle_type_for(Path::new("a.ASS")),
+            Some(("ass", "text/x-ass"))
+        );
+        assert_eq!(subtitle_type_for(Path::new("a.sub")), None);
+        assert_eq!(subtitle_type_for(Path::new("srt")), None);
+    }
+}
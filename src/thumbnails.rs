@@ -0,0 +1,153 @@
+use image::imageops::FilterType;
+use image::ImageOutputFormat;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use uuid::Uuid;
+
+use crate::archives::{self, cover, is_archive, split_member};
+use crate::media_types::{media_type_for, MUSIC_TRACK, PHOTO, VIDEO_ITEM};
+use crate::util::{fnv1a, path_bytes};
+
+/// Largest edge of a DLNA `JPEG_TN` thumbnail.
+pub const JPEG_TN_SIZE: u32 = 160;
+pub const JPEG_TN: &str = "JPEG_TN";
+pub const JPEG_TN_PROTOCOL_INFO: &str = "http-get:*:image/jpeg:DLNA.ORG_PN=JPEG_TN";
+pub const THUMBNAIL_DIR: &str = "thumbnails";
+
+/// Folder art file names, in order of preference.
+pub const FOLDER_ART: &[&str] = &[
+    "folder.jpg",
+    "folder.png",
+    "cover.jpg",
+    "cover.png",
+    "front.jpg",
+    "front.png",
+    "poster.jpg",
+    "poster.png",
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ArtSource {
+    /// An image file, decoded as is.
+    Image(PathBuf),
+    /// Cover art embedded in the tags of a media file.
+    Embedded(PathBuf),
+}
+
+impl ArtSource {
+    fn path(&self) -> &Path {
+        match self {
+            ArtSource::Image(x) | ArtSource::Embedded(x) => x,
+        }
+    }
+}
+
+pub fn find_folder_art(dir: &Path) -> Option<PathBuf> {
+    FOLDER_ART.iter().map(|x| dir.join(x)).find(|x| x.is_file())
+}
+
+/// A Kodi poster made for one video: `Movie-poster.jpg` next to `Movie.mkv`.
+pub fn find_poster(video: &Path) -> Option<PathBuf> {
+    let stem = video.file_stem()?.to_string_lossy();
+    ["jpg", "png"]
+        .iter()
+        .map(|x| video.with_file_name(format!("{}-poster.{}", stem, x)))
+        .find(|x| x.is_file())
+}
+
+fn read_embedded_art(path: &Path) -> Option<Vec<u8>> {
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    match extension.as_str() {
+        "mp3" => {
+            let tag = id3::Tag::read_from_path(path).ok()?;
+            let picture = tag.pictures().next()?;
+            Some(picture.data.clone())
+        }
+        "m4a" | "mp4" | "m4v" => {
+            let tag = mp4ameta::Tag::read_from_path(path).ok()?;
+            let artwork = tag.artwork()?;
+            Some(artwork.data.to_vec())
+        }
+        _ => None,
+    }
+}
+
+/// Where the art for `path` comes from, trying the most specific source first.
+pub fn find_art_source(path: &Path) -> Option<ArtSource> {
+    if path.is_dir() {
+        return find_folder_art(path).map(ArtSource::Image);
+    }
+    if is_archive(path) {
+        return cover(path).map(ArtSource::Image);
+    }
+    let class = media_type_for(path)?.class;
+    if class == PHOTO {
+        return Some(ArtSource::Image(path.to_path_buf()));
+    }
+    if class == VIDEO_ITEM {
+        if let Some(poster) = find_poster(path) {
+            return Some(ArtSource::Image(poster));
+        }
+    }
+    if (class == MUSIC_TRACK || class == VIDEO_ITEM) && read_embedded_art(path).is_some() {
+        return Some(ArtSource::Embedded(path.to_path_buf()));
+    }
+    find_folder_art(path.parent()?).map(ArtSource::Image)
+}
+
+fn cache_path(data_dir: &Path, source: &ArtSource) -> PathBuf {
+    let path = source.path();
+    // An archive member changes with its archive.
+    let file = split_member(path).map_or(path, |(archive, _)| archive);
+    let modified = fs::metadata(file)
+        .and_then(|x| x.modified())
+        .ok()
+        .and_then(|x| x.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |x| x.as_secs());
+    let mut key = path_bytes(path).to_vec();
+    key.extend(format!(":{}", modified).as_bytes());
+    data_dir
+        .join(THUMBNAIL_DIR)
+        .join(format!("{:016x}.jpg", fnv1a(&key)))
+}
+
+fn to_io_error<E: std::fmt::Display>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Returns the cached `JPEG_TN` thumbnail for `path`, creating it if needed.
+pub fn get_thumbnail(data_dir: &Path, path: &Path) -> io::Result<PathBuf> {
+    let source = find_art_source(path)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no art found"))?;
+    let cached = cache_path(data_dir, &source);
+    if cached.is_file() {
+        return Ok(cached);
+    }
+
+    let data = match &source {
+        ArtSource::Image(x) => archives::read(x)?,
+        ArtSource::Embedded(x) => read_embedded_art(x)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no embedded art"))?,
+    };
+    let thumbnail = image::load_from_memory(&data)
+        .map_err(to_io_error)?
+        .resize(JPEG_TN_SIZE, JPEG_TN_SIZE, FilterType::Triangle)
+        .to_rgb8();
+
+    let mut encoded = vec![];
+    image::DynamicImage::ImageRgb8(thumbnail)
+        .write_to(
+            &mut io::Cursor::new(&mut encoded),
+            ImageOutputFormat::Jpeg(85),
+        )
+        .map_err(to_io_error)?;
+    fs::create_dir_all(cached.parent().unwrap())?;
+    // Another request may be making the same thumbnail; each writes its own file and
+    // renames it into place, so none is ever served half written.
+    let temporary = cached.with_extension(format!("{}.tmp", Uuid::new_v4()));
+    fs::write(&temporary, encoded)?;
+    fs::rename(&temporary, &cached)?;
+    Ok(cached)
+}
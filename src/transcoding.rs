@@ -0,0 +1,285 @@
+use actix_web::web::Bytes;
+use futures::stream::{self, Stream};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, NON_ALPHANUMERIC};
+use serde::Deserialize;
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::Path;
+use std::process::Stdio;
+use tokio::io::AsyncReadExt;
+use tokio::process::{Child, ChildStdout, Command};
+
+pub const TRANSCODE_QUERY: &str = "transcode";
+/// Placeholder in a profile's command line that is replaced by the source file.
+pub const INPUT_PLACEHOLDER: &str = "{input}";
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// A way of serving a file in a different format than it is stored in.
+pub trait Transcoder: Send + Sync {
+    /// Unique name, used in the `?transcode=` query of the resource URL.
+    fn name(&self) -> &str;
+    fn mime(&self) -> &str;
+    fn protocol_info(&self) -> String;
+    fn applies_to(&self, path: &Path) -> bool;
+    /// Starts transcoding `input`, returning the running process.
+    fn spawn(&self, input: &Path) -> io::Result<Child>;
+}
+
+/// A transcoding profile from the config, run as an external command that writes the
+/// transcoded stream to stdout.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct TranscodeProfile {
+    pub name: String,
+    pub mime: String,
+    /// DLNA profile, e.g. `MPEG_TS_HD_NA_ISO`, advertised as `DLNA.ORG_PN`.
+    #[serde(default)]
+    pub dlna_profile: Option<String>,
+    /// Source file extensions this profile is offered for.
+    pub extensions: Vec<String>,
+    /// Program and arguments; `{input}` is replaced by the source path.
+    pub command: Vec<String>,
+}
+
+impl Transcoder for TranscodeProfile {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mime(&self) -> &str {
+        &self.mime
+    }
+
+    fn protocol_info(&self) -> String {
+        // Transcoded streams cannot seek by byte range and are converted content.
+        let flags = "DLNA.ORG_OP=00;DLNA.ORG_CI=1";
+        match &self.dlna_profile {
+            Some(x) => format!("http-get:*:{}:DLNA.ORG_PN={};{}", self.mime, x, flags),
+            None => format!("http-get:*:{}:{}", self.mime, flags),
+        }
+    }
+
+    fn applies_to(&self, path: &Path) -> bool {
+        let extension = match path.extension().and_then(|x| x.to_str()) {
+            Some(x) => x.to_lowercase(),
+            None => return false,
+        };
+        self.extensions
+            .iter()
+            .any(|x| x.to_lowercase() == extension)
+    }
+
+    fn spawn(&self, input: &Path) -> io::Result<Child> {
+        let (program, args) = self
+            .command
+            .split_first()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty command"))?;
+        Command::new(program)
+            .args(args.iter().map(|x| substitute_input(x, input.as_os_str())))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+    }
+}
+
+/// `argument` with `input` in place of each [`INPUT_PLACEHOLDER`], keeping the bytes
+/// of a path that is not UTF-8 as they are.
+fn substitute_input(argument: &str, input: &OsStr) -> OsString {
+    let mut pieces = argument.split(INPUT_PLACEHOLDER);
+    let mut substituted = OsString::from(pieces.next().unwrap_or_default());
+    for piece in pieces {
+        substituted.push(input);
+        substituted.push(piece);
+    }
+    substituted
+}
+
+/// The query that requests `name`, percent-encoded as profile names may hold spaces,
+/// `&` or `#`.
+pub fn transcode_query(name: &str) -> String {
+    format!(
+        "{}={}",
+        TRANSCODE_QUERY,
+        utf8_percent_encode(name, NON_ALPHANUMERIC)
+    )
+}
+
+/// The transcoding profile requested by a resource URL, if any.
+pub fn transcode_name(url: &str) -> Option<Cow<'_, str>> {
+    let (_, query) = url.split_once('?')?;
+    query.split('&').find_map(|x| match x.split_once('=') {
+        Some((TRANSCODE_QUERY, name)) => Some(percent_decode_str(name).decode_utf8_lossy()),
+        _ => None,
+    })
+}
+
+/// Streams the stdout of `child`. The process is killed once the stream is dropped,
+/// e.g. when the renderer disconnects.
+pub fn stdout_stream(
+    mut child: Child,
+) -> io::Result<impl Stream<Item = io::Result<Bytes>> + Unpin> {
+    let stdout = child
+        .stdout
+        .take()
+        .ok_or_else(|| io::Error::other("stdout not captured"))?;
+    let state: (Child, ChildStdout) = (child, stdout);
+    Ok(Box::pin(stream::unfold(Some(state), |state| async move {
+        let (child, mut stdout) = state?;
+        let mut buf = vec![0; CHUNK_SIZE];
+        match stdout.read(&mut buf).await {
+            Ok(0) => None,
+            Ok(n) => {
+                buf.truncate(n);
+                Some((Ok(Bytes::from(buf)), Some((child, stdout))))
+            }
+            Err(e) => Some((Err(e), None)),
+        }
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+    use std::fs;
+    use std::time::Duration;
+
+    fn profile(command: &[&str]) -> TranscodeProfile {
+        TranscodeProfile {
+            name: "stub".to_string(),
+            mime: "video/mp2t".to_string(),
+            dlna_profile: None,
+            extensions: vec!["mkv".to_string()],
+            command: command.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+
+    async fn collect(child: Child) -> Vec<u8> {
+        let mut stream = stdout_stream(child).unwrap();
+        let mut output = vec![];
+        while let Some(chunk) = stream.next().await {
+            output.extend_from_slice(&chunk.unwrap());
+        }
+        output
+    }
+
+    /// Whether process `pid` has stopped running: reaped, or killed and not yet reaped.
+    fn has_exited(pid: u32) -> bool {
+        match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat
+                .rsplit(") ")
+                .next()
+                .is_some_and(|x| x.starts_with('Z') || x.starts_with('X')),
+            Err(_) => true,
+        }
+    }
+
+    #[tokio::test]
+    async fn substitutes_input() {
+        let profile = profile(&[
+            "sh",
+            "-c",
+            "printf '%s|%s' \"$0\" \"$1\"",
+            "{input}",
+            "-i={input}",
+        ]);
+        let child = profile
+            .spawn(Path::new("/media/A Movie (2020).mkv"))
+            .unwrap();
+        assert_eq!(
+            collect(child).await,
+            b"/media/A Movie (2020).mkv|-i=/media/A Movie (2020).mkv"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn passes_paths_that_are_not_utf8() {
+        use std::os::unix::ffi::OsStrExt;
+        let input = Path::new(OsStr::from_bytes(b"/media/caf\xe9 {input} %20.mkv"));
+        let profile = profile(&[
+            "sh",
+            "-c",
+            "printf '%s|%s' \"$0\" \"$1\"",
+            "{input}",
+            "x{input}y",
+        ]);
+        let child = profile.spawn(input).unwrap();
+        assert_eq!(
+            collect(child).await,
+            b"/media/caf\xe9 {input} %20.mkv|x/media/caf\xe9 {input} %20.mkvy"
+        );
+    }
+
+    #[tokio::test]
+    async fn streams_stdout() {
+        // More than one chunk, so the stream has to carry on reading.
+        let profile = profile(&["sh", "-c", "head -c 200000 \"$0\"", "{input}"]);
+        let child = profile.spawn(Path::new("/dev/zero")).unwrap();
+        assert_eq!(collect(child).await, vec![0; 200_000]);
+    }
+
+    #[tokio::test]
+    async fn empty_command() {
+        assert!(profile(&[]).spawn(Path::new("a.mkv")).is_err());
+    }
+
+    #[tokio::test]
+    async fn kills_child_when_dropped() {
+        let profile = profile(&["sh", "-c", "printf started; exec sleep 30"]);
+        let child = profile.spawn(Path::new("a.mkv")).unwrap();
+        let pid = child.id();
+        let mut stream = stdout_stream(child).unwrap();
+        assert_eq!(&stream.next().await.unwrap().unwrap()[..], b"started");
+        assert!(!has_exited(pid));
+        drop(stream);
+        for _ in 0..50 {
+            if has_exited(pid) {
+                return;
+            }
+            tokio::time::delay_for(Duration::from_millis(20)).await;
+        }
+        panic!(
+            "transcoder {} still running after the stream was dropped",
+            pid
+        );
+    }
+
+    #[test]
+    fn protocol_info_flags() {
+        let mut profile = profile(&["cat"]);
+        assert_eq!(
+            profile.protocol_info(),
+            "http-get:*:video/mp2t:DLNA.ORG_OP=00;DLNA.ORG_CI=1"
+        );
+        profile.dlna_profile = Some("MPEG_TS_HD_NA_ISO".to_string());
+        assert_eq!(
+            profile.protocol_info(),
+            "http-get:*:video/mp2t:DLNA.ORG_PN=MPEG_TS_HD_NA_ISO;DLNA.ORG_OP=00;DLNA.ORG_CI=1"
+        );
+    }
+
+    #[test]
+    fn transcode_queries() {
+        assert_eq!(
+            transcode_name("http://h/agni-files/a.mkv?transcode=ts"),
+            Some("ts".into())
+        );
+        assert_eq!(
+            transcode_name("http://h/agni-files/a.mkv?x=1&transcode=ts"),
+            Some("ts".into())
+        );
+        assert_eq!(transcode_name("http://h/agni-files/a.mkv"), None);
+        let url = format!(
+            "http://h/agni-files/a.mkv?{}",
+            transcode_query("H.264 & AAC #2")
+        );
+        assert_eq!(
+            url,
+            "http://h/agni-files/a.mkv?transcode=H%2E264%20%26%20AAC%20%232"
+        );
+        assert_eq!(transcode_name(&url).as_deref(), Some("H.264 & AAC #2"));
+    }
+}
@@ -0,0 +1,409 @@
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use crate::access::Access;
+use crate::collation::{natural_order_strings, Collation};
+use crate::library::{Library, Metadata, Object};
+use crate::media_types::VIDEO_ITEM;
+use crate::util::fnv1a;
+
+/// Show and season containers are numbered from here, out of reach of the index.
+/// A show's ID carries a hash of its name in bits 16 to 47; a season's adds one more
+/// than its number to that.
+const FIRST_TV_ID: u64 = 1 << 48;
+/// The static "TV Shows" container, numbered after the playback views.
+pub const TV_SHOWS_ID: u64 = 5;
+pub const TV_SHOWS_TITLE: &str = "TV Shows";
+const TV_CONTAINER: &str = "object.container";
+
+/// Where an episode belongs, as read from its file name.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Episode {
+    pub series: String,
+    /// For shows named by air date, the year.
+    pub season: u32,
+    pub episode: Option<u32>,
+    /// Air date as `YYYY-MM-DD`, for shows named by date.
+    pub date: Option<String>,
+}
+
+/// A show or season in the "TV Shows" hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TvNode {
+    Show(u64),
+    Season(u64, u32),
+}
+
+fn patterns() -> &'static [Regex; 3] {
+    static PATTERNS: OnceLock<[Regex; 3]> = OnceLock::new();
+    PATTERNS.get_or_init(|| {
+        let compile = |x: &str| RegexBuilder::new(x).case_insensitive(true).build().unwrap();
+        [
+            // Show.Name.S01E02, Show Name - s1e2
+            compile(r"(?:^|[^a-z0-9])s(\d{1,3})[ ._-]?e(\d{1,4})"),
+            // Show Name 1x02
+            compile(r"(?:^|[^a-z0-9])(\d{1,2})x(\d{2,3})(?:[^a-z0-9]|$)"),
+            // Show.Name.2021.03.04
+            compile(r"(?:^|[^0-9])((?:19|20)\d{2})[ ._-](\d{2})[ ._-](\d{2})(?:[^0-9]|$)"),
+        ]
+    })
+}
+
+/// Folder names that say which season, not which show, such as `Season 2`.
+fn is_season_folder(name: &str) -> bool {
+    let name = name.to_lowercase();
+    name == "specials"
+        || name
+            .strip_prefix("season")
+            .is_some_and(|x| x.trim().chars().all(|x| x.is_ascii_digit()))
+}
+
+/// Turns the part of a file name before the episode number into a show name.
+fn clean_series(name: &str) -> String {
+    let name = name.replace(['.', '_'], " ");
+    name.trim_matches(|x: char| x.is_whitespace() || "-[(".contains(x))
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Where the episode at `path` belongs, if its name follows a known scheme. The show
+/// is named by what precedes the episode number, or else by its folder.
+pub fn parse_episode(path: &Path) -> Option<Episode> {
+    let stem = path.file_stem()?.to_string_lossy();
+    let [numbered, crossed, dated] = patterns();
+    let (prefix, season, episode, date) =
+        if let Some(x) = numbered.captures(&stem).or_else(|| crossed.captures(&stem)) {
+            let start = x.get(0)?.start();
+            (
+                &stem[..start],
+                x[1].parse().ok()?,
+                Some(x[2].parse().ok()?),
+                None,
+            )
+        } else {
+            let x = dated.captures(&stem)?;
+            let start = x.get(0)?.start();
+            let date = format!("{}-{}-{}", &x[1], &x[2], &x[3]);
+            (&stem[..start], x[1].parse().ok()?, None, Some(date))
+        };
+    let mut series = clean_series(prefix);
+    if series.is_empty() {
+        let mut folder = path.parent()?;
+        if folder
+            .file_name()
+            .is_some_and(|x| is_season_folder(&x.to_string_lossy()))
+        {
+            folder = folder.parent()?;
+        }
+        series = clean_series(&folder.file_name()?.to_string_lossy());
+    }
+    if series.is_empty() {
+        return None;
+    }
+    Some(Episode {
+        series,
+        season,
+        episode,
+        date,
+    })
+}
+
+fn series_hash(series: &str) -> u64 {
+    fnv1a(series.to_lowercase().as_bytes()) & 0xFFFF_FFFF
+}
+
+pub fn show_id(series: &str) -> u64 {
+    FIRST_TV_ID | series_hash(series) << 16
+}
+
+pub fn season_id(series: &str, season: u32) -> u64 {
+    show_id(series) + (season as u64 & 0xFFFF) + 1
+}
+
+impl TvNode {
+    pub fn from_id(id: u64) -> Option<Self> {
+        if !(FIRST_TV_ID..FIRST_TV_ID << 1).contains(&id) {
+            return None;
+        }
+        let hash = (id >> 16) & 0xFFFF_FFFF;
+        match id & 0xFFFF {
+            0 => Some(TvNode::Show(hash)),
+            x => Some(TvNode::Season(hash, x as u32 - 1)),
+        }
+    }
+}
+
+fn season_title(season: u32, dated: bool) -> String {
+    match season {
+        0 => "Specials".to_string(),
+        x if dated => x.to_string(),
+        x => format!("Season {}", x),
+    }
+}
+
+fn container(id: u64, parent_id: u64, title: String) -> Object {
+    Object {
+        id,
+        parent_id,
+        path: PathBuf::new(),
+        title,
+        class: TV_CONTAINER.to_string(),
+        size: 0,
+        modified: 0,
+        metadata: Metadata::default(),
+        update_id: 0,
+    }
+}
+
+/// Every indexed video with episode information that `access` allows.
+fn episodes(library: &Library, access: &Access) -> rusqlite::Result<Vec<(Object, Episode)>> {
+    Ok(library
+        .items_of_class(VIDEO_ITEM)?
+        .into_iter()
+        .filter(|x| access.allows(&x.path))
+        .filter_map(|x| {
+            let episode = x.metadata.episode.clone()?;
+            Some((x, episode))
+        })
+        .collect())
+}
+
+/// One container per show.
+pub fn shows(library: &Library, access: &Access) -> rusqlite::Result<Vec<Object>> {
+    let mut shows = BTreeMap::new();
+    for (_, episode) in episodes(library, access)? {
+        shows
+            .entry(show_id(&episode.series))
+            .or_insert(episode.series);
+    }
+    let mut shows: Vec<Object> = shows
+        .into_iter()
+        .map(|(id, series)| container(id, TV_SHOWS_ID, series))
+        .collect();
+    shows.sort_by(|a, b| natural_order_strings(&a.title, &b.title, Collation::default()));
+    Ok(shows)
+}
+
+/// The seasons of a show, or the episodes of a season in order. `None` if no
+/// indexed episode belongs there.
+pub fn children(
+    library: &Library,
+    node: TvNode,
+    access: &Access,
+) -> rusqlite::Result<Option<Vec<Object>>> {
+    let episodes = episodes(library, access)?;
+    let children = match node {
+        TvNode::Show(hash) => {
+            let mut seasons = BTreeMap::new();
+            for (_, episode) in episodes
+                .iter()
+                .filter(|(_, x)| series_hash(&x.series) == hash)
+            {
+                seasons.entry(episode.season).or_insert((
+                    season_id(&episode.series, episode.season),
+                    show_id(&episode.series),
+                    episode.date.is_some(),
+                ));
+            }
+            seasons
+                .into_iter()
+                .map(|(season, (id, parent_id, dated))| {
+                    container(id, parent_id, season_title(season, dated))
+                })
+                .collect::<Vec<_>>()
+        }
+        TvNode::Season(hash, season) => {
+            let mut episodes: Vec<(Object, Episode)> = episodes
+                .into_iter()
+                .filter(|(_, x)| series_hash(&x.series) == hash && x.season == season)
+                .collect();
+            episodes.sort_by(|(a, x), (b, y)| {
+                (x.episode, &x.date, &a.title).cmp(&(y.episode, &y.date, &b.title))
+            });
+            episodes.into_iter().map(|(x, _)| x).collect()
+        }
+    };
+    Ok(if children.is_empty() {
+        None
+    } else {
+        Some(children)
+    })
+}
+
+/// The show or season container itself, for BrowseMetadata.
+pub fn object(
+    library: &Library,
+    node: TvNode,
+    access: &Access,
+) -> rusqlite::Result<Option<Object>> {
+    let show =
+        |hash: u64| move |episode: &&(Object, Episode)| series_hash(&episode.1.series) == hash;
+    let episodes = episodes(library, access)?;
+    Ok(match node {
+        TvNode::Show(hash) => episodes
+            .iter()
+            .find(show(hash))
+            .map(|(_, x)| container(show_id(&x.series), TV_SHOWS_ID, x.series.clone())),
+        TvNode::Season(hash, season) => episodes
+            .iter()
+            .filter(show(hash))
+            .find(|(_, x)| x.season == season)
+            .map(|(_, x)| {
+                container(
+                    season_id(&x.series, season),
+                    show_id(&x.series),
+                    season_title(season, x.date.is_some()),
+                )
+            }),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::access::AccessRules;
+    use crate::config::RootConfig;
+    use crate::library::ScannedObject;
+
+    fn episode(path: &str) -> Option<(String, u32, Option<u32>, Option<String>)> {
+        parse_episode(Path::new(path)).map(|x| (x.series, x.season, x.episode, x.date))
+    }
+
+    #[test]
+    fn episode_schemes() {
+        let expected =
+            |series: &str, season, episode| Some((series.to_string(), season, Some(episode), None));
+        assert_eq!(
+            episode("/tv/The.Office.US.S01E02.720p.mkv"),
+            expected("The Office US", 1, 2)
+        );
+        assert_eq!(
+            episode("/tv/Show Name - s3e12 - Title.mkv"),
+            expected("Show Name", 3, 12)
+        );
+        assert_eq!(
+            episode("/tv/Show_Name_2x05.avi"),
+            expected("Show Name", 2, 5)
+        );
+        assert_eq!(
+            episode("/tv/The Daily Show 2021.03.04.mkv"),
+            Some((
+                "The Daily Show".to_string(),
+                2021,
+                None,
+                Some("2021-03-04".to_string())
+            ))
+        );
+    }
+
+    #[test]
+    fn series_from_folders() {
+        let expected =
+            |series: &str, season, episode| Some((series.to_string(), season, Some(episode), None));
+        assert_eq!(
+            episode("/tv/Firefly/Season 1/S01E03.mkv"),
+            expected("Firefly", 1, 3)
+        );
+        assert_eq!(
+            episode("/tv/Firefly/Specials/s00e01.mkv"),
+            expected("Firefly", 0, 1)
+        );
+        assert_eq!(episode("/tv/Firefly/1x04.mkv"), expected("Firefly", 1, 4));
+    }
+
+    #[test]
+    fn not_episodes() {
+        assert_eq!(episode("/films/Blade Runner 2049.mkv"), None);
+        assert_eq!(episode("/films/1920x1080 sample.mkv"), None);
+        assert_eq!(episode("/films/Mission.Impossible.1996.mkv"), None);
+        assert_eq!(episode("/films/Cases 2021-03.mkv"), None);
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        let show = show_id("Firefly");
+        let hash = series_hash("Firefly");
+        assert_eq!(show_id("FIREFLY"), show);
+        assert_eq!(TvNode::from_id(show), Some(TvNode::Show(hash)));
+        assert_eq!(
+            TvNode::from_id(season_id("Firefly", 0)),
+            Some(TvNode::Season(hash, 0))
+        );
+        assert_eq!(
+            TvNode::from_id(season_id("Firefly", 2021)),
+            Some(TvNode::Season(hash, 2021))
+        );
+        assert_eq!(TvNode::from_id(TV_SHOWS_ID), None);
+        assert_eq!(TvNode::from_id(FIRST_TV_ID << 1), None);
+    }
+
+    #[test]
+    fn shows_seasons_and_episodes() {
+        let library = Library::open_in_memory();
+        let root = library.add_root(Path::new("/tv"), "TV").unwrap();
+        let scanned: Vec<ScannedObject> = [
+            "Firefly.S01E02.mkv",
+            "Firefly.S01E01.mkv",
+            "Firefly.S02E01.mkv",
+            "firefly 1x10.mkv",
+            "Alias.S01E01.mkv",
+            "Serenity.mkv",
+        ]
+        .iter()
+        .map(|name| {
+            let path = Path::new("/tv").join(name);
+            ScannedObject {
+                title: name.to_string(),
+                class: VIDEO_ITEM.to_string(),
+                size: 0,
+                modified: 0,
+                metadata: Metadata {
+                    episode: parse_episode(&path),
+                    ..Metadata::default()
+                },
+                inode: None,
+                path,
+            }
+        })
+        .collect();
+        library.sync_directory(root, &scanned).unwrap();
+        let roots = [RootConfig {
+            title: "TV".to_string(),
+            path: PathBuf::from("/tv"),
+            collation: Collation::default(),
+            flatten: false,
+        }];
+        let access = AccessRules::new(&[], &roots).unwrap().find("", None);
+        let titles = |x: Vec<Object>| x.into_iter().map(|x| x.title).collect::<Vec<_>>();
+
+        assert_eq!(
+            titles(shows(&library, &access).unwrap()),
+            ["Alias", "Firefly"]
+        );
+        let firefly = TvNode::from_id(show_id("Firefly")).unwrap();
+        assert_eq!(
+            titles(children(&library, firefly, &access).unwrap().unwrap()),
+            ["Season 1", "Season 2"]
+        );
+        let season = TvNode::from_id(season_id("Firefly", 1)).unwrap();
+        assert_eq!(
+            titles(children(&library, season, &access).unwrap().unwrap()),
+            [
+                "Firefly.S01E01.mkv",
+                "Firefly.S01E02.mkv",
+                "firefly 1x10.mkv"
+            ]
+        );
+        let object = object(&library, season, &access).unwrap().unwrap();
+        assert_eq!(
+            (object.id, object.parent_id),
+            (season_id("Firefly", 1), show_id("Firefly"))
+        );
+        let missing = TvNode::from_id(season_id("Firefly", 3)).unwrap();
+        assert_eq!(children(&library, missing, &access).unwrap(), None);
+    }
+}
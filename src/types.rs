@@ -1,51 +1,33 @@
 use lru_cache::LruCache;
 use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::{FromStr, ParseBoolError};
+use std::sync::Arc;
 use strong_xml::{XmlRead, XmlWrite};
-use uuid::Uuid;
 
-pub const ENVELOPE_XMLNS: &str = "urn:schemas-upnp-org:service-1-0";
+use crate::access::AccessRules;
+use crate::bookmarks::BookmarkStore;
+use crate::clients::ClientProfiles;
+use crate::config::Config;
+use crate::connections::ConnectionTracker;
+use crate::library::Library;
+use crate::scanner::Scanner;
+use crate::ssdp::Device;
+use crate::streams::StreamTitles;
+use crate::transcoding::Transcoder;
+use crate::views::View;
+
 pub const ENVELOPE_XMLNS_S: &str = "http://schemas.xmlsoap.org/soap/envelope/";
 pub const ENVELOPE_ENCODING_STYLE: &str = "http://schemas.xmlsoap.org/soap/encoding/";
 pub const CONTENT_DIRECTORY_XMLNS: &str = "urn:schemas-upnp-org:service:ContentDirectory:1";
-
-#[derive(XmlWrite, XmlRead, PartialEq, Debug, Clone)]
-#[xml(tag = "s:Envelope")]
-pub struct Envelope {
-    #[xml(attr = "xmlns")]
-    pub xmlns: String,
-    #[xml(attr = "xmlns:s")]
-    pub xmlns_s: String,
-    #[xml(attr = "s:encodingStyle")]
-    pub encoding_style: String,
-    #[xml(child = "s:Body")]
-    pub body: Body,
-}
-
-#[derive(XmlWrite, XmlRead, PartialEq, Debug, Clone)]
-#[xml(tag = "s:Body")]
-pub struct Body {
-    #[xml(child = "u:BrowseResponse")]
-    pub browse_response: BrowseResponse,
-}
-
-#[derive(XmlWrite, XmlRead, PartialEq, Debug, Clone)]
-#[xml(tag = "u:BrowseResponse")]
-pub struct BrowseResponse {
-    #[xml(attr = "xmlns:u")]
-    pub xmlns_u: String,
-    #[xml(flatten_text = "Result")]
-    pub result: String,
-    #[xml(flatten_text = "NumberReturned")]
-    pub number_returned: u64,
-    #[xml(flatten_text = "TotalMatches")]
-    pub total_matches: u64,
-    #[xml(flatten_text = "UpdateID")]
-    pub update_id: u64,
-}
+pub const CONNECTION_MANAGER_XMLNS: &str = "urn:schemas-upnp-org:service:ConnectionManager:1";
 
 pub const XMLNS_DC: &str = "http://purl.org/dc/elements/1.1/";
 pub const XMLNS_UPNP: &str = "urn:schemas-upnp-org:metadata-1-0/upnp/";
 pub const XMLNS_DIDL: &str = "urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/";
+pub const XMLNS_SEC: &str = "http://www.sec.co.kr/";
+pub const XMLNS_DLNA: &str = "urn:schemas-dlna-org:metadata-1-0/";
 
 #[derive(XmlWrite, XmlRead, PartialEq, Debug, Clone)]
 #[xml(tag = "DIDL-Lite")]
@@ -54,6 +36,10 @@ pub struct DidlLite {
     pub xmlns_dc: String,
     #[xml(attr = "xmlns:upnp")]
     pub xmlns_upnp: String,
+    #[xml(attr = "xmlns:sec")]
+    pub xmlns_sec: String,
+    #[xml(attr = "xmlns:dlna")]
+    pub xmlns_dlna: String,
     #[xml(attr = "xmlns")]
     pub xmlns: String,
     #[xml(child = "container", child = "item")]
@@ -72,7 +58,33 @@ pub enum ListItem {
 pub struct ListItemWrapper {
     pub list_item: ListItem,
     pub id: u64,
-    pub dir: Option<String>,
+    pub dir: Option<PathBuf>,
+    /// The media file behind an item.
+    pub file: Option<PathBuf>,
+    /// Set for the virtual containers that list files by playback state.
+    pub view: Option<View>,
+}
+
+/// A DIDL-Lite boolean attribute, written `1` or `0`.
+#[derive(PartialEq, Debug, Clone, Copy)]
+pub struct Flag(pub bool);
+
+impl fmt::Display for Flag {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.0 { "1" } else { "0" })
+    }
+}
+
+impl FromStr for Flag {
+    type Err = ParseBoolError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "1" => Ok(Flag(true)),
+            "0" => Ok(Flag(false)),
+            x => x.parse().map(Flag),
+        }
+    }
 }
 
 #[derive(XmlWrite, XmlRead, PartialEq, Debug, Clone)]
@@ -82,10 +94,28 @@ pub struct Container {
     pub id: u64,
     #[xml(attr = "parentId")]
     pub parent_id: u64,
+    /// Clients cannot change anything agni serves.
+    #[xml(attr = "restricted")]
+    pub restricted: Flag,
+    /// Whether Search works in this container.
+    #[xml(attr = "searchable")]
+    pub searchable: Flag,
+    /// Media, and containers leading to some, that it holds. Left out where counting
+    /// would mean listing.
+    #[xml(attr = "childCount")]
+    pub child_count: Option<u64>,
     #[xml(flatten_text = "dc:title")]
     pub title: String,
     #[xml(flatten_text = "upnp:class")]
     pub class: String,
+    #[xml(child = "upnp:albumArtURI")]
+    pub album_art: Option<AlbumArtUri>,
+    #[xml(flatten_text = "dc:description")]
+    pub description: Option<String>,
+    #[xml(flatten_text = "dc:date")]
+    pub date: Option<String>,
+    #[xml(flatten_text = "upnp:genre")]
+    pub genres: Vec<String>,
 }
 
 #[derive(XmlWrite, XmlRead, PartialEq, Debug, Clone)]
@@ -100,7 +130,29 @@ pub struct Item {
     #[xml(flatten_text = "upnp:class")]
     pub class: String,
     #[xml(child = "res")]
-    pub res: Res,
+    pub res: Vec<Res>,
+    #[xml(child = "sec:CaptionInfoEx")]
+    pub caption_info: Vec<CaptionInfo>,
+    #[xml(child = "upnp:albumArtURI")]
+    pub album_art: Option<AlbumArtUri>,
+    #[xml(flatten_text = "upnp:lastPlaybackPosition")]
+    pub last_playback_position: Option<String>,
+    #[xml(flatten_text = "upnp:playbackCount")]
+    pub playback_count: Option<u32>,
+    #[xml(flatten_text = "sec:dcmInfo")]
+    pub dcm_info: Option<String>,
+    #[xml(flatten_text = "upnp:seriesTitle")]
+    pub series_title: Option<String>,
+    #[xml(flatten_text = "upnp:episodeSeason")]
+    pub episode_season: Option<u32>,
+    #[xml(flatten_text = "upnp:episodeNumber")]
+    pub episode_number: Option<u32>,
+    #[xml(flatten_text = "dc:description")]
+    pub description: Option<String>,
+    #[xml(flatten_text = "dc:date")]
+    pub date: Option<String>,
+    #[xml(flatten_text = "upnp:genre")]
+    pub genres: Vec<String>,
 }
 
 #[derive(XmlWrite, XmlRead, PartialEq, Debug, Clone)]
@@ -108,6 +160,28 @@ pub struct Item {
 pub struct Res {
     #[xml(attr = "protocolInfo")]
     pub protocol_info: String,
+    /// `H:MM:SS`, where known.
+    #[xml(attr = "duration")]
+    pub duration: Option<String>,
+    #[xml(text)]
+    pub content: String,
+}
+
+/// Samsung's subtitle extension; `caption_type` is the file extension, e.g. `srt`.
+#[derive(XmlWrite, XmlRead, PartialEq, Debug, Clone)]
+#[xml(tag = "sec:CaptionInfoEx")]
+pub struct CaptionInfo {
+    #[xml(attr = "sec:type")]
+    pub caption_type: String,
+    #[xml(text)]
+    pub content: String,
+}
+
+#[derive(XmlWrite, XmlRead, PartialEq, Debug, Clone)]
+#[xml(tag = "upnp:albumArtURI")]
+pub struct AlbumArtUri {
+    #[xml(attr = "dlna:profileID")]
+    pub profile_id: String,
     #[xml(text)]
     pub content: String,
 }
@@ -115,6 +189,17 @@ pub struct Res {
 pub struct AppState {
     pub cache: LruCache<u64, Vec<ListItemWrapper>>,
     pub item_map: HashMap<u64, ListItemWrapper>,
-    pub id_counter: u64,
-    pub uuid: Uuid,
+    pub device: Device,
+    pub connections: ConnectionTracker,
+    pub config: Config,
+    pub transcoders: Vec<Arc<dyn Transcoder>>,
+    /// Which shared folders each client sees.
+    pub access: Arc<AccessRules>,
+    pub client_profiles: Arc<ClientProfiles>,
+    pub bookmarks: BookmarkStore,
+    pub library: Arc<Library>,
+    /// Index ID and path of each shared folder.
+    pub roots: Vec<(u64, PathBuf)>,
+    pub scanner: Arc<Scanner>,
+    pub stream_titles: Arc<StreamTitles>,
 }
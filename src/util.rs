@@ -1,182 +1,673 @@
 use lru_cache::LruCache;
-use percent_encoding::{utf8_percent_encode, AsciiSet, CONTROLS};
+use percent_encoding::{percent_decode_str, percent_encode, AsciiSet, CONTROLS};
 use pnet::datalink;
-use regex::Regex;
+use pnet::ipnetwork::IpNetwork;
+use socket2::{Domain, Protocol, Socket, Type};
 use std::cmp::Ordering;
-use std::net::IpAddr;
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use crate::types::{Container, Item, ListItem, ListItemWrapper, Res};
+use crate::bookmarks::format_duration;
+use crate::broadcast::is_ipv6_link_local;
+use crate::clients::ClientProfile;
+use crate::collation::{natural_order_strings, Collation};
+use crate::config::{InterfaceConfig, LibraryConfig, Station};
+use crate::library::{Library, Object, STORAGE_FOLDER};
+use crate::media_types::{media_type_for, protocol_info, MEDIA_TYPES};
+use crate::merged::{MERGED_ID, MERGED_TITLE};
+use crate::streams::{url_mime, RADIO_ID, RADIO_TITLE};
+use crate::subtitles::{subtitle_type_for, SUBTITLE_TYPES};
+use crate::thumbnails::{JPEG_TN, JPEG_TN_PROTOCOL_INFO};
+use crate::transcoding::{transcode_query, Transcoder};
+use crate::tv::TV_SHOWS_ID;
+use crate::types::{
+    AlbumArtUri, CaptionInfo, Container, Flag, Item, ListItem, ListItemWrapper, Res,
+};
+use crate::views::{View, VIEWS, VIEWS_TITLE};
 
-const FRAGMENT: &AsciiSet = &CONTROLS
+pub const FILES_PATH: &str = "/agni-files";
+pub const THUMBNAILS_PATH: &str = "/agni-thumbnails";
+/// Remote streams, proxied by object ID.
+pub const STREAMS_PATH: &str = "/agni-streams";
+
+/// Bytes escaped in the path of a resource URL. Anything outside ASCII is always
+/// escaped, and `%`, `#` and `?` must be so the path survives the round trip.
+const PATH: &AsciiSet = &CONTROLS
     .add(b' ')
     .add(b'"')
+    .add(b'#')
+    .add(b'%')
     .add(b'<')
     .add(b'>')
+    .add(b'?')
     .add(b'`')
     .add(b'[')
-    .add(b']');
+    .add(b'\\')
+    .add(b']')
+    .add(b'^')
+    .add(b'{')
+    .add(b'|')
+    .add(b'}');
 
-pub fn natural_order_strings(first_string: String, second_string: String) -> Ordering {
-    let regex = Regex::new("[^0-9]+|[0-9]+").unwrap();
-    let tokens1: Vec<String> = regex
-        .captures_iter(&first_string)
-        .map(|c| c.get(0).unwrap().as_str().to_lowercase().to_owned())
-        .collect();
-    println!("{:?}", tokens1);
+#[derive(Debug, Clone, Copy)]
+pub struct LocalAddress {
+    pub interface_index: u32,
+    pub network: IpNetwork,
+}
 
-    let tokens2: Vec<String> = regex
-        .captures_iter(&second_string)
-        .map(|c| c.get(0).unwrap().as_str().to_lowercase().to_owned())
-        .collect();
-    println!("{:?}", tokens2);
+pub fn get_local_ip(interface_config: &InterfaceConfig) -> Vec<LocalAddress> {
+    let interfaces = datalink::interfaces();
+    interfaces
+        .iter()
+        .filter(|&x| {
+            x.is_multicast() && x.is_broadcast() && interface_config.is_interface_allowed(&x.name)
+        })
+        .flat_map(|location| {
+            location.ips.iter().map(move |&network| LocalAddress {
+                interface_index: location.index,
+                network,
+            })
+        })
+        .filter(|x| interface_config.is_address_allowed(&x.network))
+        .collect()
+}
 
-    let (longer, shorter, is_first_longer) = if tokens1.len() > tokens2.len() {
-        (tokens1, tokens2, true)
-    } else {
-        (tokens1, tokens2, true)
+/// Picks the local address whose subnet contains `remote`, i.e. the interface a
+/// request from `remote` arrived on.
+pub fn get_local_ip_for(
+    interface_config: &InterfaceConfig,
+    remote: SocketAddr,
+) -> Option<LocalAddress> {
+    find_local_ip_for(get_local_ip(interface_config), remote)
+}
+
+/// Every interface has the same `fe80::/64`, so a link-local address is only picked
+/// for a request that arrived on its interface, going by the sender's scope ID.
+fn find_local_ip_for(addresses: Vec<LocalAddress>, remote: SocketAddr) -> Option<LocalAddress> {
+    let scope_id = match remote {
+        SocketAddr::V6(x) => x.scope_id(),
+        SocketAddr::V4(_) => 0,
     };
+    addresses.into_iter().find(|x| {
+        x.network.contains(remote.ip())
+            && (!matches!(x.network.ip(), IpAddr::V6(ip) if is_ipv6_link_local(&ip))
+                || x.interface_index == scope_id)
+    })
+}
 
-    for (a, b) in longer.iter().zip(shorter.iter()) {
-        let cmp = if let (Ok(x), Ok(y)) = (a.parse::<u128>(), b.parse::<u128>()) {
-            x.cmp(&y)
-        } else {
-            a.cmp(b)
-        };
-        if cmp != Ordering::Equal {
-            if is_first_longer {
-                return cmp;
-            } else {
-                return match cmp {
-                    Ordering::Greater => Ordering::Less,
-                    Ordering::Less => Ordering::Greater,
-                    Ordering::Equal => Ordering::Equal,
-                };
-            }
-        }
-    }
-    if is_first_longer {
-        Ordering::Greater
-    } else {
-        Ordering::Less
-    }
+/// Binds an IPv6-only listener so it can sit next to an IPv4 listener on the same port.
+pub fn tcp_listener_v6(port: u16) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::ipv6(), Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_only_v6(true)?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, port)).into())?;
+    socket.listen(1024)?;
+    Ok(socket.into_tcp_listener())
 }
 
-pub fn get_local_ip() -> Vec<IpAddr> {
-    let interfaces = datalink::interfaces();
-    let locations = interfaces
-        .iter()
+/// The raw bytes of `path`, which need not be UTF-8.
+#[cfg(unix)]
+pub fn path_bytes(path: &Path) -> &[u8] {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes()
+}
+
+#[cfg(not(unix))]
+pub fn path_bytes(path: &Path) -> &[u8] {
+    path.as_os_str().as_encoded_bytes()
+}
+
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    use std::os::unix::ffi::OsStringExt;
+    PathBuf::from(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+/// Percent-encodes every byte of `path` that cannot appear as is in a URL path.
+pub fn encode_path(path: &Path) -> String {
+    percent_encode(path_bytes(path), PATH).to_string()
+}
+
+/// Reverses [`encode_path`]. Resource URLs put a `/` before the absolute path, so the
+/// doubled separator is dropped to give back the path recorded in the index.
+pub fn decode_path(encoded: &str) -> PathBuf {
+    let path = path_from_bytes(percent_decode_str(encoded).collect());
+    path.components().collect()
+}
+
+/// A title for the file or folder `name`: not valid UTF-8 is replaced, and characters
+/// XML 1.0 does not allow even escaped are dropped.
+pub fn title_for(name: &OsStr) -> String {
+    name.to_string_lossy()
+        .chars()
         .filter(|&x| {
-            // let s = format!("{}", x);
-            // s.contains("192.168")
-            x.is_multicast() && x.is_broadcast()
+            matches!(x, '\t' | '\n' | '\r')
+                || ('\u{20}'..='\u{d7ff}').contains(&x)
+                || ('\u{e000}'..='\u{fffd}').contains(&x)
+                || x >= '\u{10000}'
         })
-        .map(|location| location.ips.iter().map(|x| x.ip()).filter(|x| x.is_ipv4()))
-        .flatten()
-        .collect();
+        .collect()
+}
 
-    locations
-    // location.ips.iter().map(|x| x.ip()).collect()
+pub fn stream_url(hostname: &str, id: u64) -> String {
+    format!("http://{}{}/{}", hostname, STREAMS_PATH, id)
 }
 
-pub struct ReadDirectoryReturnType {
-    pub list_items: Vec<ListItemWrapper>,
-    pub id_counter: u64,
+pub fn file_url(hostname: &str, path: &Path) -> String {
+    format!("http://{}{}/{}", hostname, FILES_PATH, encode_path(path))
+}
+
+pub fn thumbnail_url(hostname: &str, path: &Path) -> String {
+    format!(
+        "http://{}{}/{}",
+        hostname,
+        THUMBNAILS_PATH,
+        encode_path(path)
+    )
+}
+
+/// Length of the file at `path`, or 0 if it cannot be read.
+pub fn file_size(path: &Path) -> u64 {
+    fs::metadata(path).map_or(0, |x| x.len())
+}
+
+/// 64-bit FNV-1a, for cache keys that must stay stable across builds.
+pub fn fnv1a(data: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
 }
 
-pub async fn read_directory(
-    hostname: String,
-    path: String,
+fn album_art(hostname: &str, path: &Path) -> AlbumArtUri {
+    AlbumArtUri {
+        profile_id: JPEG_TN.to_string(),
+        content: thumbnail_url(hostname, path),
+    }
+}
+
+/// The `SourceProtocolInfo` of the ConnectionManager: everything `list_item` may put in
+/// a `res` for `profile`, once each.
+pub fn source_protocol_info(
+    transcoders: &[Arc<dyn Transcoder>],
+    profile: &ClientProfile,
+) -> String {
+    let media = MEDIA_TYPES.iter().map(|x| protocol_info(x.mime));
+    // Streams whose type their URL does not tell.
+    let streams = std::iter::once(protocol_info("*"));
+    let subtitles = SUBTITLE_TYPES.iter().map(|(_, mime)| protocol_info(mime));
+    let transcoded = transcoders
+        .iter()
+        .filter(|x| profile.allows_transcoder(x.name()))
+        .map(|x| x.protocol_info());
+    let mut infos: Vec<String> = vec![];
+    for info in media
+        .chain(streams)
+        .chain(subtitles)
+        .chain(transcoded)
+        .chain(std::iter::once(JPEG_TN_PROTOCOL_INFO.to_string()))
+    {
+        let info = profile.protocol_info(&info);
+        if !infos.contains(&info) {
+            infos.push(info);
+        }
+    }
+    infos.join(",")
+}
+
+/// The DIDL-Lite object for an indexed file or folder, listed under `parent_id`.
+pub fn list_item(
+    hostname: &str,
+    object: &Object,
     parent_id: u64,
-    mut id_counter: u64,
-) -> ReadDirectoryReturnType {
-    let mut entries = tokio::fs::read_dir(&path).await.unwrap();
-
-    let mut list_items = vec![];
-
-    while let Ok(entry) = entries.next_entry().await {
-        if let Some(entry) = entry {
-            if let Ok(entry_type) = entry.file_type().await {
-                if entry_type.is_dir() {
-                    list_items.push(ListItemWrapper {
-                        list_item: ListItem::Container(Container {
-                            id: id_counter,
-                            parent_id: parent_id,
-                            title: entry.file_name().into_string().unwrap(),
-                            class: "object.container.storageFolder".to_string(),
-                        }),
-                        id: id_counter,
-                        dir: Some(entry.path().to_str().unwrap().to_string()),
-                    })
-                } else {
-                    let file_name = entry.file_name().into_string().unwrap();
-                    let file_path = entry.path().to_str().unwrap().to_string();
-                    let file_path = utf8_percent_encode(&file_path, FRAGMENT).to_string();
-
-                    if file_name.ends_with(".mp4") || file_name.ends_with(".mkv") {
-                        list_items.push(ListItemWrapper {
-                            list_item: ListItem::Item(Item {
-                                id: id_counter,
-                                parent_id: parent_id,
-                                title: entry.file_name().into_string().unwrap(),
-                                class: "object.item.videoItem".to_string(),
-                                res: Res {
-                                    protocol_info: "http-get:*:video/x-matroska:*".to_string(),
-                                    content: format!(
-                                        "http://{}/agni-files/{}",
-                                        hostname, file_path
-                                    ),
-                                },
-                            }),
-                            id: id_counter,
-                            dir: None,
-                        })
-                    }
-                }
-            }
-        } else {
-            break;
+    transcoders: &[Arc<dyn Transcoder>],
+) -> ListItemWrapper {
+    let art = if object.metadata.has_art {
+        Some(album_art(hostname, &object.path))
+    } else {
+        None
+    };
+    if object.is_container() {
+        return ListItemWrapper {
+            list_item: ListItem::Container(Container {
+                id: object.id,
+                parent_id,
+                restricted: Flag(true),
+                searchable: Flag(object.class == STORAGE_FOLDER),
+                child_count: None,
+                title: object.title.clone(),
+                class: object.class.clone(),
+                album_art: art,
+                description: object.metadata.description.clone(),
+                date: object.metadata.date.clone(),
+                genres: object.metadata.genres.clone(),
+            }),
+            id: object.id,
+            dir: Some(object.path.clone()),
+            file: None,
+            view: None,
+        };
+    }
+
+    let path = object.path.as_path();
+    let url = file_url(hostname, path);
+    // A stack lists each part in playing order; a disc folder is served as one stream
+    // of its main title.
+    let sources: Vec<(&Path, String)> = match object.metadata.parts.first() {
+        Some(first) if first == path => object
+            .metadata
+            .parts
+            .iter()
+            .map(|x| (x.as_path(), file_url(hostname, x)))
+            .collect(),
+        Some(first) => vec![(first.as_path(), url.clone())],
+        None => vec![(path, url.clone())],
+    };
+    let duration = object.metadata.duration.map(format_duration);
+    let mut res = vec![];
+    for (source, url) in sources {
+        if let Some(media_type) = media_type_for(source) {
+            res.push(Res {
+                protocol_info: protocol_info(media_type.mime),
+                duration: duration.clone(),
+                content: url,
+            });
         }
-        id_counter += 1;
+    }
+    if let Some(url) = &object.metadata.url {
+        // Plain HTTP goes through agni, for renderers that cannot follow redirects or
+        // strip ICY metadata; HTTPS cannot, so it is handed out as is.
+        let mime = url_mime(url).unwrap_or("*");
+        let content = match url.to_lowercase().starts_with("http://") {
+            true => stream_url(hostname, object.id),
+            false => url.clone(),
+        };
+        res.push(Res {
+            protocol_info: protocol_info(mime),
+            duration: duration.clone(),
+            content,
+        });
+    }
+    for transcoder in transcoders.iter().filter(|x| x.applies_to(path)) {
+        res.push(Res {
+            protocol_info: transcoder.protocol_info(),
+            duration: duration.clone(),
+            content: format!("{}?{}", url, transcode_query(transcoder.name())),
+        });
+    }
+    if art.is_some() {
+        res.push(Res {
+            protocol_info: JPEG_TN_PROTOCOL_INFO.to_string(),
+            duration: None,
+            content: thumbnail_url(hostname, &object.path),
+        });
+    }
+    let episode = object.metadata.episode.as_ref();
+    let mut caption_info = vec![];
+    for subtitle in &object.metadata.subtitles {
+        let (extension, mime) = match subtitle_type_for(subtitle) {
+            Some(x) => x,
+            None => continue,
+        };
+        let url = file_url(hostname, subtitle);
+        res.push(Res {
+            protocol_info: protocol_info(mime),
+            duration: None,
+            content: url.clone(),
+        });
+        caption_info.push(CaptionInfo {
+            caption_type: extension.to_string(),
+            content: url,
+        });
     }
 
-    list_items.sort_by(|a, b| {
-        let compute_string =
-            |list_item_wrapper: &ListItemWrapper| match list_item_wrapper.list_item.clone() {
-                ListItem::Container(x) => format!("D{}", x.title),
-                ListItem::Item(x) => format!("F{}", x.title),
+    ListItemWrapper {
+        list_item: ListItem::Item(Item {
+            id: object.id,
+            parent_id,
+            title: object.title.clone(),
+            class: object.class.clone(),
+            res,
+            caption_info,
+            album_art: art,
+            last_playback_position: None,
+            playback_count: None,
+            dcm_info: None,
+            series_title: episode.map(|x| x.series.clone()),
+            episode_season: episode.map(|x| x.season),
+            episode_number: episode.and_then(|x| x.episode),
+            description: object.metadata.description.clone(),
+            date: object.metadata.date.clone(),
+            genres: object.metadata.genres.clone(),
+        }),
+        id: object.id,
+        dir: None,
+        file: Some(object.path.clone()).filter(|_| object.metadata.url.is_none()),
+        view: None,
+    }
+}
+
+/// Orders objects by a CDS `SortCriteria` such as `+upnp:class,-dc:date`. Properties
+/// agni cannot sort by are skipped; folders before files in natural title order
+/// breaks any remaining ties.
+pub fn sort_objects(objects: &mut [Object], sort_criteria: &str, collation: Collation) {
+    let criteria: Vec<(bool, &str)> = sort_criteria
+        .split(',')
+        .map(|x| x.trim())
+        .filter(|x| !x.is_empty())
+        .map(|x| match x.strip_prefix('-') {
+            Some(property) => (false, property),
+            None => (true, x.trim_start_matches('+')),
+        })
+        .collect();
+    objects.sort_by(|a, b| {
+        for (ascending, property) in &criteria {
+            let ordering = match *property {
+                "dc:title" => natural_order_strings(&a.title, &b.title, collation),
+                "dc:date" => a.modified.cmp(&b.modified),
+                "upnp:class" => a.class.cmp(&b.class),
+                _ => Ordering::Equal,
             };
-        natural_order_strings(compute_string(a), compute_string(b))
+            let ordering = if *ascending {
+                ordering
+            } else {
+                ordering.reverse()
+            };
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+        }
+        b.is_container()
+            .cmp(&a.is_container())
+            .then_with(|| natural_order_strings(&a.title, &b.title, collation))
     });
-    ReadDirectoryReturnType {
-        list_items,
-        id_counter,
-    }
 }
 
-pub fn get_cache() -> LruCache<u64, Vec<ListItemWrapper>> {
-    let mut initial_list_items = vec![ListItemWrapper {
+pub fn get_cache(
+    library: &Library,
+    config: &LibraryConfig,
+    stations: &[Station],
+) -> LruCache<u64, Vec<ListItemWrapper>> {
+    // Shared folders take their IDs from the library index, so they survive restarts.
+    let mut initial_list_items: Vec<ListItemWrapper> = config
+        .roots
+        .iter()
+        .filter_map(|root| {
+            let dir = root.path.clone();
+            match library.add_root(&dir, &root.title) {
+                Ok(id) => Some(ListItemWrapper {
+                    list_item: ListItem::Container(Container {
+                        id,
+                        parent_id: 0,
+                        restricted: Flag(true),
+                        searchable: Flag(true),
+                        child_count: None,
+                        title: root.title.clone(),
+                        class: "object.container.storageFolder".to_string(),
+                        album_art: None,
+                        description: None,
+                        date: None,
+                        genres: vec![],
+                    }),
+                    id,
+                    dir: Some(dir),
+                    file: None,
+                    view: None,
+                }),
+                Err(e) => {
+                    log::error!("Cannot index {}: {}", dir.display(), e);
+                    None
+                }
+            }
+        })
+        .collect();
+
+    // The merged view comes right after the roots it merges; its children are listed
+    // from them as they are browsed.
+    if config.merge_roots {
+        initial_list_items.push(ListItemWrapper {
+            list_item: ListItem::Container(Container {
+                id: MERGED_ID,
+                parent_id: 0,
+                restricted: Flag(true),
+                searchable: Flag(true),
+                child_count: None,
+                title: MERGED_TITLE.to_string(),
+                class: STORAGE_FOLDER.to_string(),
+                album_art: None,
+                description: None,
+                date: None,
+                genres: vec![],
+            }),
+            id: MERGED_ID,
+            dir: None,
+            file: None,
+            view: None,
+        });
+    }
+
+    // The playback views sit next to the folder roots.
+    let library_id = 1;
+    initial_list_items.push(ListItemWrapper {
         list_item: ListItem::Container(Container {
-            id: 1,
+            id: library_id,
             parent_id: 0,
-            title: "Documents".to_string(),
-            class: "object.container.storageFolder".to_string(),
+            restricted: Flag(true),
+            searchable: Flag(false),
+            child_count: Some(VIEWS.len() as u64),
+            title: VIEWS_TITLE.to_string(),
+            class: "object.container".to_string(),
+            album_art: None,
+            description: None,
+            date: None,
+            genres: vec![],
         }),
-        id: 1,
-        dir: Some("/home/nithin/Server".into()),
-    }];
-
-    for (i, x) in initial_list_items.iter_mut().enumerate() {
-        let id = i as u64 + 1;
-        x.id = id;
-        match &mut x.list_item {
-            ListItem::Container(x) => x.id = id,
-            ListItem::Item(x) => x.id = id,
-        }
+        id: library_id,
+        dir: None,
+        file: None,
+        view: None,
+    });
+    let views = VIEWS
+        .iter()
+        .zip(library_id + 1..)
+        .map(|(view, id)| ListItemWrapper {
+            list_item: ListItem::Container(Container {
+                id,
+                parent_id: library_id,
+                restricted: Flag(true),
+                searchable: Flag(false),
+                child_count: None,
+                title: view.title().to_string(),
+                class: "object.container".to_string(),
+                album_art: None,
+                description: None,
+                date: None,
+                genres: vec![],
+            }),
+            id,
+            dir: None,
+            file: None,
+            view: Some(*view),
+        })
+        .collect();
+    initial_list_items.push(ListItemWrapper {
+        list_item: ListItem::Container(Container {
+            id: TV_SHOWS_ID,
+            parent_id: 0,
+            restricted: Flag(true),
+            searchable: Flag(false),
+            child_count: None,
+            title: View::TvShows.title().to_string(),
+            class: "object.container".to_string(),
+            album_art: None,
+            description: None,
+            date: None,
+            genres: vec![],
+        }),
+        id: TV_SHOWS_ID,
+        dir: None,
+        file: None,
+        view: Some(View::TvShows),
+    });
+    // Stations are listed with resource URLs, which depend on the request.
+    if !stations.is_empty() {
+        initial_list_items.push(ListItemWrapper {
+            list_item: ListItem::Container(Container {
+                id: RADIO_ID,
+                parent_id: 0,
+                restricted: Flag(true),
+                searchable: Flag(false),
+                child_count: Some(stations.len() as u64),
+                title: RADIO_TITLE.to_string(),
+                class: "object.container".to_string(),
+                album_art: None,
+                description: None,
+                date: None,
+                genres: vec![],
+            }),
+            id: RADIO_ID,
+            dir: None,
+            file: None,
+            view: None,
+        });
     }
 
     let mut cache: LruCache<u64, Vec<ListItemWrapper>> = LruCache::new(100);
 
     cache.insert(0, initial_list_items);
+    cache.insert(library_id, views);
 
     cache
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::Uri;
+
+    /// File names that have broken URL handling before: URL delimiters, escapes,
+    /// spaces, bidirectional and combining text, dot files and bytes that are not UTF-8.
+    const NASTY_NAMES: &[&[u8]] = &[
+        b"Track #1.mp3",
+        b"100% Hits.mp3",
+        b"Who?.mkv",
+        b"Rock + Roll.flac",
+        b"a+b=c&d;e,f.mp3",
+        b"  two  spaces .mp3",
+        b"%20 already escaped %2F.mp3",
+        b"%zz not an escape.mp3",
+        b"[brackets] {braces} <angles> `ticks` ^caret \\back \"quotes\".mkv",
+        "\u{5e9}\u{5dc}\u{5d5}\u{5dd} \u{202e}gpj.exe.mp4".as_bytes(),
+        "Cafe\u{301} e\u{301}\u{302}\u{323}.mp3".as_bytes(),
+        "\u{1f3b5} emoji.mp3".as_bytes(),
+        b".hidden",
+        b"..double",
+        b"...",
+        b".mkv",
+        b"caf\xe9.mp3",
+        b"\xff\xfe invalid \x80.mp3",
+        b"tab\there\nnewline.mp3",
+    ];
+
+    fn nasty_paths() -> Vec<PathBuf> {
+        NASTY_NAMES
+            .iter()
+            .flat_map(|name| {
+                let name = path_from_bytes(name.to_vec());
+                [
+                    Path::new("/media").join(&name),
+                    Path::new("/media").join(&name).join("inside.mp3"),
+                ]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn encoded_paths_are_url_safe() {
+        for path in nasty_paths() {
+            let encoded = encode_path(&path);
+            assert!(
+                encoded.bytes().all(|x| x.is_ascii_graphic()
+                    && !matches!(x, b'#' | b'?' | b'"' | b'<' | b'>' | b'\\')),
+                "{:?} encoded as {}",
+                path,
+                encoded
+            );
+            assert!(
+                !encoded.contains('%')
+                    || encoded.split('%').skip(1).all(|x| {
+                        x.len() >= 2 && x.as_bytes()[..2].iter().all(u8::is_ascii_hexdigit)
+                    })
+            );
+        }
+    }
+
+    #[test]
+    fn paths_round_trip() {
+        for path in nasty_paths() {
+            assert_eq!(decode_path(&encode_path(&path)), path);
+        }
+    }
+
+    #[test]
+    fn paths_round_trip_through_urls() {
+        for path in nasty_paths() {
+            let url = file_url("192.168.1.2:3030", &path);
+            let uri: Uri = url.parse().unwrap_or_else(|e| panic!("{}: {}", url, e));
+            assert_eq!(uri.query(), None, "{}", url);
+            let requested = uri.path().trim_start_matches(FILES_PATH);
+            assert_eq!(decode_path(requested), path, "{}", url);
+        }
+    }
+
+    #[test]
+    fn titles() {
+        let title = |name: &[u8]| title_for(path_from_bytes(name.to_vec()).as_os_str());
+        assert_eq!(title(b"Track #1.mp3"), "Track #1.mp3");
+        assert_eq!(title(b"100% Hits?.mp3"), "100% Hits?.mp3");
+        assert_eq!(title(b".hidden"), ".hidden");
+        assert_eq!(title(b"caf\xe9.mp3"), "caf\u{fffd}.mp3");
+        assert_eq!(title(b"\xff\xfe.mp3"), "\u{fffd}\u{fffd}.mp3");
+        assert_eq!(
+            title("\u{5e9}\u{5dc}\u{5d5}\u{5dd} \u{202e}gpj.exe.mp4".as_bytes()),
+            "\u{5e9}\u{5dc}\u{5d5}\u{5dd} \u{202e}gpj.exe.mp4"
+        );
+        assert_eq!(title("e\u{301}\u{302}".as_bytes()), "e\u{301}\u{302}");
+        // Characters XML cannot carry are dropped; whitespace controls are kept.
+        assert_eq!(title(b"a\x01b\x1fc\td\ne.mp3"), "abc\td\ne.mp3");
+        assert_eq!(title("\u{fffe}\u{ffff}x".as_bytes()), "x");
+    }
+
+    #[test]
+    fn link_local_requests_get_the_address_of_their_interface() {
+        let address = |interface_index, network: &str| LocalAddress {
+            interface_index,
+            network: network.parse().unwrap(),
+        };
+        let addresses = vec![
+            address(2, "192.168.1.5/24"),
+            address(2, "fe80::2/64"),
+            address(3, "fe80::3/64"),
+        ];
+        let find = |remote: &str| {
+            find_local_ip_for(addresses.clone(), remote.parse().unwrap())
+                .map(|x| x.network.ip().to_string())
+        };
+        assert_eq!(find("192.168.1.9:1900").as_deref(), Some("192.168.1.5"));
+        assert_eq!(find("[fe80::9%3]:1900").as_deref(), Some("fe80::3"));
+        assert_eq!(find("[fe80::9%2]:1900").as_deref(), Some("fe80::2"));
+        assert_eq!(find("[fe80::9]:1900"), None);
+        assert_eq!(find("10.0.0.9:1900"), None);
+    }
+}
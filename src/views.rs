@@ -0,0 +1,93 @@
+use std::collections::HashSet;
+use std::path::PathBuf;
+
+use crate::access::Access;
+use crate::bookmarks::BookmarkStore;
+use crate::library::{Library, Object};
+use crate::media_types::VIDEO_ITEM;
+use crate::tv::{self, TV_SHOWS_TITLE};
+
+pub const VIEWS_TITLE: &str = "Library";
+/// How many files "Recently added" lists.
+pub const RECENTLY_ADDED_LIMIT: usize = 50;
+
+/// A virtual container listing files from all roots by playback state, or the
+/// shows of the "TV Shows" hierarchy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum View {
+    ContinueWatching,
+    RecentlyAdded,
+    Unwatched,
+    TvShows,
+}
+
+pub const VIEWS: &[View] = &[View::ContinueWatching, View::RecentlyAdded, View::Unwatched];
+
+impl View {
+    pub fn title(&self) -> &'static str {
+        match self {
+            View::ContinueWatching => "Continue watching",
+            View::RecentlyAdded => "Recently added",
+            View::Unwatched => "Unwatched",
+            View::TvShows => TV_SHOWS_TITLE,
+        }
+    }
+}
+
+/// What the views need of the bookmarks, copied out so that the index can be queried
+/// without holding them.
+#[derive(Debug, Default)]
+pub struct Playback {
+    in_progress: Vec<PathBuf>,
+    watched: HashSet<PathBuf>,
+}
+
+impl Playback {
+    pub fn for_view(view: View, bookmarks: &BookmarkStore) -> Self {
+        match view {
+            View::ContinueWatching => Playback {
+                in_progress: bookmarks.in_progress(),
+                ..Playback::default()
+            },
+            View::Unwatched => Playback {
+                watched: bookmarks.watched(),
+                ..Playback::default()
+            },
+            View::RecentlyAdded | View::TvShows => Playback::default(),
+        }
+    }
+}
+
+/// The indexed files `view` lists, in order, of those `access` allows.
+pub fn view_objects(
+    view: View,
+    library: &Library,
+    playback: &Playback,
+    access: &Access,
+) -> rusqlite::Result<Vec<Object>> {
+    match view {
+        View::ContinueWatching => {
+            let mut objects = vec![];
+            for file in &playback.in_progress {
+                objects.extend(library.get_by_path(file)?);
+            }
+            objects.retain(|x| access.allows(&x.path));
+            Ok(objects)
+        }
+        // Restricted clients get the newest of the files they see, so the limit
+        // applies after filtering.
+        View::RecentlyAdded if access.is_restricted() => Ok(library
+            .recently_added(usize::MAX)?
+            .into_iter()
+            .filter(|x| access.allows(&x.path))
+            .take(RECENTLY_ADDED_LIMIT)
+            .collect()),
+        View::RecentlyAdded => library.recently_added(RECENTLY_ADDED_LIMIT),
+        View::Unwatched => Ok(library
+            .items_of_class(VIDEO_ITEM)?
+            .into_iter()
+            .filter(|x| !playback.watched.contains(&x.path) && access.allows(&x.path))
+            .collect()),
+        View::TvShows => tv::shows(library, access),
+    }
+}